@@ -1,7 +1,17 @@
 use core::arch::asm;
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, Ordering};
 
 struct Ax {}
 
+/// Interrupt enable flag in EFLAGS
+const EFLAGS_INTERRUPT: u32 = 1 << 9;
+
+/// Stands in for IF on the host. Nothing interrupts a test, so it only
+/// records cli and sti for [`interrupts_enabled`]
+#[cfg(test)]
+static INTERRUPT_FLAG: AtomicBool = AtomicBool::new(false);
+
 #[derive(Default)]
 #[repr(C)]
 pub struct Registers {
@@ -20,6 +30,13 @@ extern "C" {
     pub fn invoke_realmode(int: u16, registers: *mut Registers);
 }
 
+/// Tests run as a normal process, which has no I/O privilege, so reaching
+/// hardware from one is a bug in the test
+#[cfg(test)]
+fn no_port_io(port: u16, _value: impl Sized) -> ! {
+    panic!("Port {:#x} used on the host", port)
+}
+
 /// If we send IO port instructions too quickly we have timing issues
 /// https://wiki.osdev.org/Inline_Assembly/Examples#I/O_access
 #[inline(always)]
@@ -35,7 +52,28 @@ pub fn out8(port: u16, value: u8) {
 
 #[inline(always)]
 fn out8_fast(port: u16, value: u8) {
-    unsafe { asm!("out dx, al", in("al") value, in("dx") port) }
+    #[cfg(test)]
+    no_port_io(port, value);
+    #[cfg(not(test))]
+    unsafe {
+        asm!("out dx, al", in("al") value, in("dx") port)
+    }
+}
+
+#[inline(always)]
+pub fn out16(port: u16, value: u16) {
+    out16_fast(port, value);
+    iowait();
+}
+
+#[inline(always)]
+fn out16_fast(port: u16, value: u16) {
+    #[cfg(test)]
+    no_port_io(port, value);
+    #[cfg(not(test))]
+    unsafe {
+        asm!("out dx, ax", in("ax") value, in("dx") port)
+    }
 }
 
 #[inline(always)]
@@ -46,11 +84,19 @@ pub fn out32(port: u16, value: u32) {
 
 #[inline(always)]
 fn out32_fast(port: u16, value: u32) {
-    unsafe { asm!("out dx, eax", in("eax") value, in("dx") port) }
+    #[cfg(test)]
+    no_port_io(port, value);
+    #[cfg(not(test))]
+    unsafe {
+        asm!("out dx, eax", in("eax") value, in("dx") port)
+    }
 }
 
 #[inline(always)]
 pub fn in8(port: u16) -> u8 {
+    #[cfg(test)]
+    no_port_io(port, ());
+    #[cfg(not(test))]
     unsafe {
         let value: u8;
         asm!("in al, dx", out("al") value, in("dx") port);
@@ -58,8 +104,23 @@ pub fn in8(port: u16) -> u8 {
     }
 }
 
+#[inline(always)]
+pub fn in16(port: u16) -> u16 {
+    #[cfg(test)]
+    no_port_io(port, ());
+    #[cfg(not(test))]
+    unsafe {
+        let value: u16;
+        asm!("in ax, dx", out("ax") value, in("dx") port);
+        value
+    }
+}
+
 #[inline(always)]
 pub fn in32(port: u16) -> u32 {
+    #[cfg(test)]
+    no_port_io(port, ());
+    #[cfg(not(test))]
     unsafe {
         let value: u32;
         asm!("in eax, dx", out("eax") value, in("dx") port);
@@ -70,13 +131,59 @@ pub fn in32(port: u16) -> u32 {
 /// Clear the interrupt flag [https://www.felixcloutier.com/x86/cli]
 #[inline(always)]
 pub fn cli() {
-    unsafe { asm!("cli") }
+    #[cfg(test)]
+    INTERRUPT_FLAG.store(false, Ordering::Relaxed);
+    #[cfg(not(test))]
+    unsafe {
+        asm!("cli")
+    }
 }
 
 /// Set the interrupt flag [https://www.felixcloutier.com/x86/sti]
 #[inline(always)]
 pub fn sti() {
-    unsafe { asm!("sti") }
+    #[cfg(test)]
+    INTERRUPT_FLAG.store(true, Ordering::Relaxed);
+    #[cfg(not(test))]
+    unsafe {
+        asm!("sti")
+    }
+}
+
+/// Run `f` with interrupts disabled, the interrupt flag is restored to what
+/// it was before so this is safe to call from inside an ISR
+#[inline(always)]
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let enabled = interrupts_enabled();
+    cli();
+
+    let ret = f();
+
+    if enabled {
+        sti();
+    }
+    ret
+}
+
+/// Whether the interrupt flag is set
+#[inline(always)]
+pub fn interrupts_enabled() -> bool {
+    #[cfg(test)]
+    return INTERRUPT_FLAG.load(Ordering::Relaxed);
+    #[cfg(not(test))]
+    {
+        let eflags: u32;
+        unsafe { asm!("pushfd", "pop {:e}", out(reg) eflags) }
+        (eflags & EFLAGS_INTERRUPT) == EFLAGS_INTERRUPT
+    }
+}
+
+/// Read the time stamp counter [https://www.felixcloutier.com/x86/rdtsc]
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") low, out("edx") high) }
+    (high as u64) << 32 | low as u64
 }
 
 #[allow(dead_code)]
@@ -89,6 +196,9 @@ pub fn esp() -> u32 {
 
 #[inline(always)]
 pub fn lidt(descriptor: &crate::interrupts::LidtDesc) {
+    #[cfg(test)]
+    panic!("lidt of {:p} on the host", descriptor);
+    #[cfg(not(test))]
     unsafe {
         core::arch::asm!("lidt [{}]", in(reg) descriptor);
     }
@@ -96,5 +206,11 @@ pub fn lidt(descriptor: &crate::interrupts::LidtDesc) {
 
 #[inline(always)]
 pub fn halt() {
-    unsafe { core::arch::asm!("hlt") }
+    // There is nothing to wake a test up, and it may not halt anyway
+    #[cfg(test)]
+    core::hint::spin_loop();
+    #[cfg(not(test))]
+    unsafe {
+        core::arch::asm!("hlt")
+    }
 }
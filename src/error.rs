@@ -1,6 +1,6 @@
 pub type Result<T> = core::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Error {
     CannotFindRsdp,
     RsdpCheckSumNotZero,
@@ -9,4 +9,109 @@ pub enum Error {
     /// Either not implemented or failing to parse packet from
     /// network buffer
     CouldNotParsePacket,
+
+    /// Frame is bigger than the NIC buffers
+    FrameTooLarge,
+    /// Every transmit descriptor is still owned by the NIC
+    TransmitQueueFull,
+    /// The NIC link did not come up
+    LinkDown,
+
+    /// Nobody answered our ARP requests for an address
+    ArpTimeout,
+
+    /// Received data failed its checksum
+    InvalidChecksum,
+    /// Destination is off-link and there is no default gateway
+    NoRoute,
+
+    /// Another socket is already bound to the port
+    PortInUse,
+    /// Every socket slot is in use
+    NoFreeSockets,
+    /// Nothing arrived on a socket before the timeout
+    ReceiveTimeout,
+
+    /// No DHCP server answered
+    DhcpTimeout,
+    /// The DHCP server kept refusing the address it offered us
+    DhcpNak,
+
+    /// Downloads must go above 1 MiB
+    InvalidLoadAddress,
+    /// The TFTP server stopped answering
+    TftpTimeout,
+    /// The file is bigger than the region we were given to put it in
+    TftpFileTooLarge,
+    /// The file name is too long to fit in a read request
+    TftpFilenameTooLong,
+    /// TFTP server error 0, the message is printed to the console
+    TftpNotDefined,
+    /// TFTP server error 1
+    TftpFileNotFound,
+    /// TFTP server error 2
+    TftpAccessViolation,
+    /// TFTP server error 3
+    TftpDiskFull,
+    /// TFTP server error 4, or the server sent us something unexpected
+    TftpIllegalOperation,
+    /// TFTP server error 5
+    TftpUnknownTransferId,
+    /// TFTP server error 6
+    TftpFileExists,
+    /// TFTP server error 7
+    TftpNoSuchUser,
+    /// TFTP server error 8, or the server sent back options we cannot use
+    TftpOptionNegotiation,
+
+    /// DHCP did not give us any DNS servers
+    DnsNoServers,
+    /// No DNS server answered
+    DnsTimeout,
+    /// The DNS server says the name does not exist
+    DnsNameNotFound,
+    /// The DNS server could not answer the query
+    DnsServerFailure,
+    /// The name exists but has no IPv4 address
+    DnsNoAddress,
+    /// The name cannot be put in a DNS query
+    InvalidHostName,
+
+    /// The TCP peer stopped acknowledging what we sent
+    TcpTimeout,
+    /// Nothing is listening on the port we connected to
+    TcpConnectionRefused,
+    /// The TCP peer reset the connection
+    TcpConnectionReset,
+    /// Writing to a TCP connection that is closing
+    TcpConnectionClosed,
+
+    /// Nobody answered our neighbor solicitations
+    NdpTimeout,
+    /// Another host on the link is using the IPv6 address we wanted
+    Ipv6DuplicateAddress,
+    /// No router advertised a prefix we could build an address from
+    Ipv6NoRouter,
+
+    /// No router advertisement set the managed or other configuration flag
+    Dhcpv6NotAdvertised,
+    /// No DHCPv6 server offered us an address
+    Dhcpv6Timeout,
+    /// The DHCPv6 server would not give us the address it offered
+    Dhcpv6NoAddress,
+
+    /// Not an http:// URL we can parse
+    InvalidUrl,
+    /// The HTTP server sent something we could not parse
+    HttpBadResponse,
+    /// HTTP status 404
+    HttpNotFound,
+    /// Any other HTTP status we cannot use, it is printed to the console
+    HttpStatus,
+    /// The HTTP server kept redirecting us
+    HttpTooManyRedirects,
+    /// The file is bigger than the region we were given to put it in
+    HttpFileTooLarge,
+    /// The connection closed before the whole body arrived
+    HttpTruncated,
 }
@@ -19,12 +19,20 @@ static mut IDT: *mut [IdtEntry; IDT_ENTRIES as usize] =
 
 /// ARG1: IRQ name
 /// ARG2: module path
+#[cfg(not(test))]
 macro_rules! isr {
     ($irq:ident, $module:ident$(::$rest:ident)*) => {
         #[naked]
         unsafe extern "C" fn $irq() -> ! {
             core::arch::asm!(
+                // Whatever we interrupted still needs the caller saved
+                // registers the handler is free to clobber
+                "pushad",
+                // The ABI expects the direction flag clear, iretd puts back
+                // whatever the interrupted code had
+                "cld",
                 "call {}",
+                "popad",
                 "iretd",
                 sym $crate::$module$(::$rest)*::isr,
                 options(noreturn)
@@ -33,6 +41,7 @@ macro_rules! isr {
     };
 }
 
+#[cfg(not(test))]
 macro_rules! trap_isr {
     ($irq: ident, $module: ident$(::$rest: ident)*) => {
         #[naked]
@@ -48,6 +57,28 @@ macro_rules! trap_isr {
     };
 }
 
+/// The stubs above are 32 bit and nothing raises interrupts on the host, so
+/// tests get entries that only name the handler, as the stub would
+#[cfg(test)]
+macro_rules! isr {
+    ($irq:ident, $module:ident$(::$rest:ident)*) => {
+        unsafe extern "C" fn $irq() -> ! {
+            let _ = $crate::$module$(::$rest)*::isr;
+            unreachable!("Interrupt on the host")
+        }
+    };
+}
+
+#[cfg(test)]
+macro_rules! trap_isr {
+    ($irq:ident, $module:ident$(::$rest:ident)*) => {
+        unsafe extern "C" fn $irq() -> ! {
+            let _ = $crate::$module$(::$rest)*::trap;
+            unreachable!("Exception on the host")
+        }
+    };
+}
+
 trap_isr!(trap_default, interrupts);
 isr!(isr_default, interrupts);
 
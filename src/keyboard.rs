@@ -1,4 +1,4 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use crate::{cpu::in8, interrupts::Idt, pic};
 
@@ -18,6 +18,8 @@ const _LEFT_CTRL: u8 = 0x1D;
 const RELEASE_OFFSET: u8 = 0x80;
 
 static LEFT_SHIFT_DOWN: AtomicBool = AtomicBool::new(false);
+/// The last key pressed that has not been taken, 0 if there is none
+static LAST_KEY: AtomicU32 = AtomicU32::new(0);
 
 const KEY_MAP: [char; 59] = [
     '\0', '\0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', '-', '=',
@@ -50,6 +52,7 @@ fn isr() {
             };
 
             if key != &'\0' {
+                LAST_KEY.store(*key as u32, Ordering::Relaxed);
                 crate::print!("{}", key);
             }
         }
@@ -58,6 +61,12 @@ fn isr() {
     pic::end_of_interrupt();
 }
 
+/// Take the last key pressed, only the most recent is kept
+pub fn take_key() -> Option<char> {
+    char::from_u32(LAST_KEY.swap(0, Ordering::Relaxed))
+        .filter(|&key| key != '\0')
+}
+
 pub fn init() {
     Idt::insert(irq, IRQ_PIN);
     crate::pic::unmask(IRQ_PIN);
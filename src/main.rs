@@ -1,5 +1,8 @@
-#![no_std]
-#![no_main]
+// The host build is only for cargo test, which needs std and its own main
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Tests only reach the network stack, not the hardware
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 #![feature(naked_functions)]
 
 extern crate alloc;
@@ -12,6 +15,7 @@ mod interrupts;
 mod acpi;
 mod cpu;
 mod error;
+#[cfg(not(test))]
 mod instrinsics;
 mod keyboard;
 mod mm;
@@ -19,7 +23,39 @@ mod net;
 mod pci;
 mod pic;
 mod pit;
+mod serial;
 
+use core::net::IpAddr;
+
+use alloc::vec::Vec;
+
+use error::{Error, Result};
+
+/// Where the downloaded kernel is placed in physical memory
+const KERNEL_LOAD_ADDR: usize = 0x200_000;
+/// Largest kernel we will download
+const KERNEL_MAX_LEN: usize = 0x1_000_000;
+/// 802.1Q VLAN the boot network is on, [`None`] if it is untagged
+const VLAN_ID: Option<u16> = None;
+/// Write every frame to COM1 as a pcap stream
+const PCAP_CAPTURE: bool = false;
+/// Leave the NIC interrupts off and only take frames off the cards while
+/// waiting on the network, for cards whose interrupt routing is broken
+const POLLED_NIC: bool = false;
+/// Interface to boot from, e.g.
+/// `Some(net::Selector::Mac([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]))` or
+/// `Some(net::Selector::Pci(pci::Location { bus: 0, slot: 3, function: 0 }))`.
+/// [`None`] offers a choice on the keyboard, then tries each in turn
+const BOOT_INTERFACE: Option<net::Selector> = None;
+/// How long the keyboard choice of interface is offered for
+const INTERFACE_PROMPT_MS: u64 = 3_000;
+/// How long an interface has to get a link before we move on
+const LINK_TIMEOUT_MS: u64 = 5_000;
+/// Echo requests sent to the DHCPv4 gateway before downloading, a quick
+/// check of the route off the link. 0 skips it
+const GATEWAY_PINGS: u16 = 1;
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo<'_>) -> ! {
     print!("{}", info);
@@ -47,10 +83,185 @@ fn entry(entry_addr: u32, memory_map_base_addr: u32, gdt_cs_offset: u16) {
     mm::init(memory_map_base_addr)
         .expect("Failed to find suitable memory region for allocator");
 
-    //let devices = pci::init();
-    //net::init(&devices);
+    let devices = pci::init();
+    net::init(&devices);
+    net::configure_vlan(VLAN_ID);
+    net::set_polled(POLLED_NIC);
+    if PCAP_CAPTURE {
+        net::start_capture();
+    }
+
+    let interfaces = net::interfaces();
+    let pinned = match BOOT_INTERFACE {
+        Some(selector) => Some(
+            interfaces
+                .iter()
+                .position(|interface| interface.matches(&selector))
+                .expect("Boot interface not found"),
+        ),
+        None => choose_interface(interfaces),
+    };
+    let candidates: Vec<usize> = match pinned {
+        Some(index) => Vec::from([index]),
+        None => (0..interfaces.len()).collect(),
+    };
+
+    // Only the provisioning network will answer DHCP
+    let booted = candidates.into_iter().any(|index| {
+        net::use_interface(index);
+        println!("Interface {}: {}", index + 1, interfaces[index]);
+        netboot()
+    });
+    assert!(booted, "Failed to boot from any interface");
 
     loop {
         cpu::halt();
     }
 }
+
+/// List the interfaces and give the user a moment to pick one by number,
+/// [`None`] if they do not or there is nothing to choose between
+fn choose_interface(interfaces: &[net::Interface]) -> Option<usize> {
+    if interfaces.len() < 2 {
+        return None;
+    }
+
+    for (i, interface) in interfaces.iter().enumerate() {
+        println!("{}: {}", i + 1, interface);
+    }
+    println!(
+        "Press 1-{} to pick the boot interface",
+        interfaces.len().min(9)
+    );
+
+    // Only a key pressed from now counts
+    keyboard::take_key();
+    let deadline = pit::ticks() + INTERFACE_PROMPT_MS;
+    while pit::ticks() < deadline {
+        let chosen = keyboard::take_key()
+            .and_then(|key| key.to_digit(10))
+            .and_then(|digit| (digit as usize).checked_sub(1))
+            .filter(|&index| index < interfaces.len());
+        if chosen.is_some() {
+            return chosen;
+        }
+        cpu::halt();
+    }
+
+    None
+}
+
+/// Configure the current interface and boot whatever its DHCP server points
+/// us at, false if no server answered or the download failed
+fn netboot() -> bool {
+    match net::wait_for_link(LINK_TIMEOUT_MS) {
+        Ok(link) => {
+            println!("Link: {}", link);
+        }
+        Err(error) => {
+            println!("Link: {:?}", error);
+            return false;
+        }
+    }
+
+    // Most networks hand out the boot file over DHCPv4, so only pay for
+    // the IPv6 timeouts when it does not
+    let lease = match net::obtain_lease() {
+        Ok(lease) => {
+            println!("DHCP: {:?}", lease);
+            Some(lease)
+        }
+        Err(error) => {
+            println!("DHCP: {:?}", error);
+            None
+        }
+    };
+
+    if let Some(lease) = &lease {
+        if let Some(gateway) = lease.gateway.filter(|_| GATEWAY_PINGS != 0) {
+            if let Err(error) = net::ping(gateway, GATEWAY_PINGS) {
+                println!("Ping: {:?}", error);
+            }
+        }
+
+        if let Some(boot_file) = &lease.boot_file {
+            // The TFTP server option may be a name or an IP. Without it or
+            // siaddr the DHCP server is the best guess
+            return boot(boot_file, || match &lease.tftp_server {
+                Some(server) => net::resolve_host(server).map(IpAddr::V4),
+                None if lease.next_server.is_unspecified() => {
+                    Ok(lease.server.into())
+                }
+                None => Ok(lease.next_server.into()),
+            });
+        }
+    }
+
+    match net::autoconfigure_ipv6() {
+        Ok(address) => {
+            println!("IPv6: {}", address);
+        }
+        Err(error) => {
+            println!("IPv6: {:?}", error);
+        }
+    }
+
+    // On IPv6 only networks the boot file comes from DHCPv6
+    let boot_file_url = match net::obtain_ipv6_lease() {
+        Ok(lease) => {
+            println!("DHCPv6: {:?}", lease);
+            lease.boot_file_url
+        }
+        Err(error) => {
+            println!("DHCPv6: {:?}", error);
+            None
+        }
+    };
+
+    if let Some(boot_file_url) = &boot_file_url {
+        // Always a URL so there is no TFTP server to fall back to
+        return boot(boot_file_url, || Err(Error::InvalidUrl));
+    }
+
+    lease.is_some()
+}
+
+/// Download the kernel named by `boot_file`. HTTP boot servers and DHCPv6
+/// hand out a URL rather than a file name, anything else is fetched from
+/// `tftp_server`. False if the download failed
+fn boot(boot_file: &str, tftp_server: impl FnOnce() -> Result<IpAddr>) -> bool {
+    let len = if has_scheme(boot_file, "http://") {
+        net::http::download(boot_file, KERNEL_LOAD_ADDR, KERNEL_MAX_LEN)
+    } else if has_scheme(boot_file, "tftp://") {
+        net::tftp::download_url(boot_file, KERNEL_LOAD_ADDR, KERNEL_MAX_LEN)
+    } else {
+        tftp_server().and_then(|server| {
+            net::tftp::download(
+                server,
+                boot_file,
+                KERNEL_LOAD_ADDR,
+                KERNEL_MAX_LEN,
+            )
+        })
+    };
+
+    match len {
+        Ok(len) => {
+            println!(
+                "Downloaded {} ({} bytes) to {:#X}",
+                boot_file, len, KERNEL_LOAD_ADDR
+            );
+            true
+        }
+        Err(error) => {
+            println!("Download {}: {:?}", boot_file, error);
+            false
+        }
+    }
+}
+
+/// URL schemes are case insensitive
+fn has_scheme(url: &str, scheme: &str) -> bool {
+    url.get(..scheme.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+}
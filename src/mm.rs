@@ -4,18 +4,36 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering::SeqCst},
 };
 
+/// Entries in the memory map the BIOS left us
+const MEMORY_MAP_LEN: usize = 20;
+/// Usable RAM in the memory map
+const TYPE_USABLE: u32 = 1;
+/// Kept free under the heap when memory is handed out with [`reserve`], for
+/// whatever is allocated after
+const HEAP_HEADROOM: usize = 0x10_0000;
+
 #[derive(Debug)]
 struct Allocator {
     arena: UnsafeCell<*mut u8>,
+    /// Bytes between the start of the arena and the bottom of the heap,
+    /// which grows down from the top
     remaining: AtomicUsize,
+    /// The heap may not grow below this offset into the arena, it has been
+    /// handed out by [`reserve`]
+    floor: AtomicUsize,
 }
 
-#[global_allocator]
+// Tests on the host use the system allocator
+#[cfg_attr(not(test), global_allocator)]
 static mut GLOBAL_ALLOCATOR: Allocator = Allocator {
     arena: UnsafeCell::new(core::ptr::null_mut()),
     remaining: AtomicUsize::new(0),
+    floor: AtomicUsize::new(0),
 };
 
+/// Copied out of low memory by [`init`]
+static mut MEMORY_MAP: [Entry; MEMORY_MAP_LEN] = [Entry::EMPTY; MEMORY_MAP_LEN];
+
 unsafe impl Sync for Allocator {}
 
 unsafe impl GlobalAlloc for Allocator {
@@ -24,13 +42,14 @@ unsafe impl GlobalAlloc for Allocator {
             return core::ptr::null_mut();
         }
 
+        let floor = self.floor.load(SeqCst);
         let mut alloc_base = 0;
         if self
             .remaining
-            .fetch_update(SeqCst, SeqCst, |mut remaining| {
-                remaining -= layout.size();
-                alloc_base = remaining & !layout.align() + 1;
-                Some(alloc_base)
+            .fetch_update(SeqCst, SeqCst, |remaining| {
+                alloc_base = remaining.checked_sub(layout.size())?
+                    & !(layout.align() - 1);
+                (alloc_base >= floor).then_some(alloc_base)
             })
             .is_err()
         {
@@ -44,7 +63,7 @@ unsafe impl GlobalAlloc for Allocator {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Entry {
     base_addr: u64,
     length: u64,
@@ -52,13 +71,34 @@ struct Entry {
     acpi_attributes: u32,
 }
 
+impl Entry {
+    const EMPTY: Self = Self {
+        base_addr: 0,
+        length: 0,
+        r#type: 0,
+        acpi_attributes: 0,
+    };
+
+    fn contains(&self, addr: usize) -> bool {
+        let addr = addr as u64;
+        self.r#type == TYPE_USABLE
+            && self.base_addr <= addr
+            && addr < self.base_addr + self.length
+    }
+
+    fn end(&self) -> usize {
+        (self.base_addr + self.length) as usize
+    }
+}
+
 pub fn init(memory_map: u32) -> Result<(), ()> {
     unsafe {
-        let entries = &*(memory_map as *const [Entry; 20]);
+        MEMORY_MAP = *(memory_map as *const [Entry; MEMORY_MAP_LEN]);
+        let entries = &*core::ptr::addr_of!(MEMORY_MAP);
 
         let mut largest_entry: Option<&Entry> = None;
         for entry in entries.iter() {
-            if entry.r#type != 1 {
+            if entry.r#type != TYPE_USABLE {
                 continue;
             }
             if let Some(current_largest) = largest_entry {
@@ -82,3 +122,31 @@ pub fn init(memory_map: u32) -> Result<(), ()> {
         }
     }
 }
+
+/// Claim `[addr, addr + len)` to write to directly, cut short where it stops
+/// being usable RAM or would run into the heap. Returns how much of it is
+/// ours, the heap never grows into that afterwards
+pub fn reserve(addr: usize, len: usize) -> usize {
+    let Some(entry) = (unsafe { &*core::ptr::addr_of!(MEMORY_MAP) })
+        .iter()
+        .find(|entry| entry.contains(addr))
+    else {
+        return 0;
+    };
+    let mut end = addr.saturating_add(len).min(entry.end());
+
+    let allocator = unsafe { &*core::ptr::addr_of!(GLOBAL_ALLOCATOR) };
+    let arena = unsafe { *allocator.arena.get() } as usize;
+    if entry.base_addr as usize == arena {
+        // Stay clear of the heap, leaving it some room to grow
+        let heap = (arena + allocator.remaining.load(SeqCst))
+            .saturating_sub(HEAP_HEADROOM);
+        end = end.min(heap);
+        if end <= addr {
+            return 0;
+        }
+        allocator.floor.fetch_max(end - arena, SeqCst);
+    }
+
+    end - addr
+}
@@ -1,10 +1,142 @@
 use core::net::Ipv4Addr;
 
-use crate::error::Error;
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pit,
+};
 
-use super::{nic::MacAddress, Endianness, Serialise};
+use super::{
+    nic::MacAddress,
+    packet::{EtherType, Packet, Protocol},
+    Endianness, Serialise,
+};
+
+/// How many addresses we remember at once
+const CACHE_SIZE: usize = 16;
+/// How long an entry is trusted for before we ask again
+const CACHE_LIFETIME_MS: u64 = 60_000;
+/// How long to wait for a reply to each request in [`resolve`]
+const RESOLVE_TIMEOUT_MS: u64 = 1_000;
+/// How many requests [`resolve`] sends before giving up
+const RESOLVE_RETRIES: usize = 3;
+
+static mut CACHE: [Option<CacheEntry>; CACHE_SIZE] = [None; CACHE_SIZE];
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    ip: Ipv4Addr,
+    mac: MacAddress,
+    /// Tick at which this entry is no longer valid
+    expires: u64,
+}
+
+/// Look up a non expired entry in the cache
+fn lookup(ip: Ipv4Addr) -> Option<MacAddress> {
+    let now = pit::ticks();
+    cpu::without_interrupts(|| unsafe {
+        CACHE
+            .iter()
+            .flatten()
+            .find(|entry| entry.ip == ip && entry.expires > now)
+            .map(|entry| entry.mac)
+    })
+}
+
+/// Forget every entry, they belong to the network we were on
+pub(super) fn flush() {
+    cpu::without_interrupts(|| unsafe { CACHE = [None; CACHE_SIZE] })
+}
+
+/// Insert or refresh an entry, if the cache is full the entry closest to
+/// expiring is replaced
+fn insert(ip: Ipv4Addr, mac: MacAddress) {
+    let now = pit::ticks();
+    let new_entry = CacheEntry {
+        ip,
+        mac,
+        expires: now + CACHE_LIFETIME_MS,
+    };
+
+    cpu::without_interrupts(|| unsafe {
+        let mut victim = 0;
+        for (i, slot) in CACHE.iter().enumerate() {
+            match slot {
+                Some(entry) if entry.ip == ip => {
+                    victim = i;
+                    break;
+                }
+                None => victim = i,
+                Some(entry) => {
+                    if let Some(current) = CACHE[victim] {
+                        if entry.expires < current.expires {
+                            victim = i;
+                        }
+                    }
+                }
+            }
+        }
+        CACHE[victim] = Some(new_entry);
+    })
+}
+
+/// Get the MAC address for an on-link IPv4 address, asking the network if we
+/// do not already know it. Blocks until answered or timed out
+pub fn resolve(ip: Ipv4Addr) -> Result<MacAddress> {
+    if ip == Ipv4Addr::BROADCAST {
+        return Ok(MacAddress::BROADCAST);
+    }
+
+    for _ in 0..RESOLVE_RETRIES {
+        if let Some(mac) = lookup(ip) {
+            return Ok(mac);
+        }
+
+        request(ip)?;
+
+        // Replies are put in the cache by [`super::poll`]
+        let deadline = pit::ticks() + RESOLVE_TIMEOUT_MS;
+        while pit::ticks() < deadline {
+            if let Some(mac) = lookup(ip) {
+                return Ok(mac);
+            }
+            super::wait();
+        }
+    }
+
+    Err(Error::ArpTimeout)
+}
+
+/// Broadcast a who-has for `ip`
+fn request(ip: Ipv4Addr) -> Result<()> {
+    let arp = Arp::new(Arp::REQUEST, MacAddress::ZERO, ip);
+
+    Packet::new(MacAddress::BROADCAST, EtherType::Arp, Protocol::Arp(arp))
+        .send()
+}
+
+/// Called for every ARP packet we receive, learns the sender and answers
+/// requests for our address
+pub(super) fn handle(arp: &Arp) {
+    let our_ip = super::ipv4_address();
+    if our_ip.is_unspecified() {
+        return;
+    }
+
+    if (arp.operand == Arp::REPLY || arp.dst_ip == our_ip)
+        && !arp.src_ip.is_unspecified()
+    {
+        insert(arp.src_ip, arp.src_mac);
+    }
+
+    if arp.operand == Arp::REQUEST && arp.dst_ip == our_ip {
+        let reply = Arp::new(Arp::REPLY, arp.src_mac, arp.src_ip);
+
+        _ = Packet::new(arp.src_mac, EtherType::Arp, Protocol::Arp(reply))
+            .send();
+    }
+}
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct Arp {
     hardware_ty: [u8; 2],
@@ -20,10 +152,31 @@ pub struct Arp {
 
 impl Arp {
     pub const LEN: usize = core::mem::size_of::<Self>();
+
+    const HARDWARE_ETHERNET: [u8; 2] = [0x00, 0x01];
+    const PROTOCOL_IPV4: [u8; 2] = [0x08, 0x00];
+
+    const REQUEST: u16 = 1;
+    const REPLY: u16 = 2;
+
+    /// Ethernet/IPv4 ARP packet sent from our address
+    fn new(operand: u16, dst_mac: MacAddress, dst_ip: Ipv4Addr) -> Self {
+        Self {
+            hardware_ty: Self::HARDWARE_ETHERNET,
+            protocol_ty: Self::PROTOCOL_IPV4,
+            hardware_len: 6,
+            protocol_len: 4,
+            operand,
+            src_mac: super::mac(),
+            src_ip: super::ipv4_address(),
+            dst_mac,
+            dst_ip,
+        }
+    }
 }
 
-impl Serialise for Arp {
-    fn deserialise(buffer: &[u8]) -> Result<Self, Error> {
+impl Serialise<'_> for Arp {
+    fn deserialise(buffer: &[u8]) -> Result<Self> {
         let mut ptr = 0;
 
         Ok(Self {
@@ -39,7 +192,49 @@ impl Serialise for Arp {
         })
     }
 
-    fn serialise(&self, _: &mut [u8]) {
-        todo!()
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = 0;
+
+        emit!(ptr, buffer, [u8; 2], self.hardware_ty);
+        emit!(ptr, buffer, [u8; 2], self.protocol_ty);
+        emit!(ptr, buffer, u8, self.hardware_len);
+        emit!(ptr, buffer, u8, self.protocol_len);
+        emit!(ptr, buffer, Endianness::Big, u16, self.operand);
+        emit!(ptr, buffer, [u8; 6], self.src_mac.into());
+        emit!(ptr, buffer, [u8; 4], self.src_ip.octets());
+        emit!(ptr, buffer, [u8; 6], self.dst_mac.into());
+        emit!(ptr, buffer, [u8; 4], self.dst_ip.octets());
+
+        ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{cell::Cell, net::Ipv4Addr};
+
+    use alloc::{rc::Rc, vec::Vec};
+
+    use crate::net::{self, testing};
+
+    const OUR_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const PEER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+    #[test]
+    fn resolve_learns_the_reply() {
+        let requests = Rc::new(Cell::new(0));
+        let counted = requests.clone();
+        let _stack = testing::take_stack(move |frame| {
+            let reply = testing::answer_arp(frame, PEER_IP);
+            counted.set(counted.get() + reply.is_some() as usize);
+            reply.into_iter().collect::<Vec<_>>()
+        });
+        net::configure_ipv4(OUR_IP, NETMASK, Ipv4Addr::UNSPECIFIED);
+
+        assert_eq!(super::resolve(PEER_IP).unwrap(), testing::PEER_MAC.into());
+        // The second time comes from the cache
+        assert_eq!(super::resolve(PEER_IP).unwrap(), testing::PEER_MAC.into());
+        assert_eq!(requests.get(), 1);
     }
 }
@@ -0,0 +1,621 @@
+//! DHCPv4 client [https://www.rfc-editor.org/rfc/rfc2131]
+
+use core::net::Ipv4Addr;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    error::{Error, Result},
+    pit,
+};
+
+use super::{udp::UdpSocket, Endianness, Serialise};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+/// Marks the start of the options field
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// How many times each message is sent before we give up on it
+const RETRIES: u32 = 4;
+/// Timeout for the first attempt, doubled for every retransmit
+const INITIAL_TIMEOUT_MS: u64 = 2_000;
+/// How many times we start over after being NAKed
+const MAX_NAKS: u32 = 3;
+/// Back off before starting over after a NAK
+const NAK_DELAY_MS: u64 = 2_000;
+
+/// Used when the server does not send a subnet mask
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+/// Option codes [https://www.rfc-editor.org/rfc/rfc2132]
+mod option {
+    pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const ROUTER: u8 = 3;
+    pub const DNS_SERVERS: u8 = 6;
+    pub const HOST_NAME: u8 = 12;
+    pub const REQUESTED_IP: u8 = 50;
+    pub const LEASE_TIME: u8 = 51;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_ID: u8 = 54;
+    pub const PARAMETER_REQUEST_LIST: u8 = 55;
+    pub const TFTP_SERVER_NAME: u8 = 66;
+    pub const BOOT_FILE_NAME: u8 = 67;
+    pub const END: u8 = 255;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Ack,
+    Nak,
+    Unknown(u8),
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<MessageType> for u8 {
+    fn from(value: MessageType) -> Self {
+        match value {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Unknown(value) => value,
+        }
+    }
+}
+
+/// Everything the rest of the boot needs to know from the server
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub host_name: Option<String>,
+    /// Seconds the address is ours for
+    pub lease_time: Option<u32>,
+    /// The DHCP server that gave us the lease
+    pub server: Ipv4Addr,
+    /// `siaddr`, the server to use in the next step of the boot
+    pub next_server: Ipv4Addr,
+    /// Option 66 or the `sname` field, may be a name or a dotted IP
+    pub tftp_server: Option<String>,
+    /// Option 67 or the `file` field
+    pub boot_file: Option<String>,
+}
+
+impl Lease {
+    fn new(message: &Dhcp, server: Ipv4Addr) -> Self {
+        let mut lease = Self {
+            address: message.your_ip,
+            netmask: DEFAULT_NETMASK,
+            gateway: None,
+            dns_servers: Vec::new(),
+            host_name: None,
+            lease_time: None,
+            server,
+            next_server: message.server_ip,
+            tftp_server: text(&message.server_name),
+            boot_file: text(&message.boot_file),
+        };
+
+        for (code, data) in message.options() {
+            match code {
+                option::SUBNET_MASK => {
+                    lease.netmask = ip(data).unwrap_or(lease.netmask)
+                }
+                option::ROUTER => lease.gateway = ip(data),
+                option::DNS_SERVERS => {
+                    lease.dns_servers =
+                        data.chunks_exact(4).filter_map(ip).collect()
+                }
+                option::HOST_NAME => lease.host_name = text(data),
+                option::LEASE_TIME => {
+                    lease.lease_time =
+                        data.try_into().ok().map(u32::from_be_bytes)
+                }
+                // The options take priority over the fixed fields
+                option::TFTP_SERVER_NAME => {
+                    lease.tftp_server = text(data).or(lease.tftp_server)
+                }
+                option::BOOT_FILE_NAME => {
+                    lease.boot_file = text(data).or(lease.boot_file)
+                }
+                _ => {}
+            }
+        }
+
+        lease
+    }
+}
+
+/// First four bytes of an option as an address
+fn ip(data: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    Some(octets.into())
+}
+
+/// NUL terminated or padded text, [`None`] if empty
+fn text(data: &[u8]) -> Option<String> {
+    let len = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    (len != 0).then(|| String::from_utf8_lossy(&data[..len]).into_owned())
+}
+
+/// Iterator over the (code, data) pairs in an options field
+struct Options<'a> {
+    buffer: &'a [u8],
+    ptr: usize,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let code = *self.buffer.get(self.ptr)?;
+            self.ptr += 1;
+
+            match code {
+                option::PAD => continue,
+                option::END => return None,
+                code => {
+                    let len = *self.buffer.get(self.ptr)? as usize;
+                    self.ptr += 1;
+
+                    let data = self.buffer.get(self.ptr..self.ptr + len)?;
+                    self.ptr += len;
+
+                    return Some((code, data));
+                }
+            }
+        }
+    }
+}
+
+/// BOOTP message with DHCP options
+#[derive(Debug)]
+struct Dhcp<'a> {
+    op: u8,
+    hardware_ty: u8,
+    hardware_len: u8,
+    hops: u8,
+    xid: u32,
+    secs: u16,
+    flags: u16,
+    client_ip: Ipv4Addr,
+    your_ip: Ipv4Addr,
+    server_ip: Ipv4Addr,
+    relay_ip: Ipv4Addr,
+    client_hardware_addr: [u8; 16],
+    server_name: [u8; 64],
+    boot_file: [u8; 128],
+    /// Everything after the magic cookie
+    options: &'a [u8],
+}
+
+impl<'a> Dhcp<'a> {
+    /// Fixed fields and magic cookie
+    const HEADER_LEN: usize = 240;
+
+    const OP_REQUEST: u8 = 1;
+    const OP_REPLY: u8 = 2;
+
+    /// Ask the server to broadcast replies as we cannot take unicast yet
+    const FLAG_BROADCAST: u16 = 1 << 15;
+
+    fn request(xid: u32, secs: u16, options: &'a [u8]) -> Self {
+        let mut client_hardware_addr = [0; 16];
+        client_hardware_addr[..6]
+            .copy_from_slice(&<[u8; 6]>::from(super::mac()));
+
+        Self {
+            op: Self::OP_REQUEST,
+            hardware_ty: 1,
+            hardware_len: 6,
+            hops: 0,
+            xid,
+            secs,
+            flags: Self::FLAG_BROADCAST,
+            client_ip: Ipv4Addr::UNSPECIFIED,
+            your_ip: Ipv4Addr::UNSPECIFIED,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            relay_ip: Ipv4Addr::UNSPECIFIED,
+            client_hardware_addr,
+            server_name: [0; 64],
+            boot_file: [0; 128],
+            options,
+        }
+    }
+
+    fn options(&self) -> Options<'a> {
+        Options {
+            buffer: self.options,
+            ptr: 0,
+        }
+    }
+
+    fn message_type(&self) -> Option<MessageType> {
+        self.options()
+            .find(|(code, _)| *code == option::MESSAGE_TYPE)
+            .and_then(|(_, data)| data.first())
+            .map(|&ty| ty.into())
+    }
+
+    fn server_id(&self) -> Option<Ipv4Addr> {
+        self.options()
+            .find(|(code, _)| *code == option::SERVER_ID)
+            .and_then(|(_, data)| ip(data))
+    }
+}
+
+impl<'a> Serialise<'a> for Dhcp<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::HEADER_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        let dhcp = Self {
+            op: consume!(ptr, buffer, u8),
+            hardware_ty: consume!(ptr, buffer, u8),
+            hardware_len: consume!(ptr, buffer, u8),
+            hops: consume!(ptr, buffer, u8),
+            xid: consume!(ptr, buffer, Endianness::Big, u32),
+            secs: consume!(ptr, buffer, Endianness::Big, u16),
+            flags: consume!(ptr, buffer, Endianness::Big, u16),
+            client_ip: consume!(ptr, buffer, [u8; 4]).into(),
+            your_ip: consume!(ptr, buffer, [u8; 4]).into(),
+            server_ip: consume!(ptr, buffer, [u8; 4]).into(),
+            relay_ip: consume!(ptr, buffer, [u8; 4]).into(),
+            client_hardware_addr: consume!(ptr, buffer, [u8; 16]),
+            server_name: consume!(ptr, buffer, [u8; 64]),
+            boot_file: consume!(ptr, buffer, [u8; 128]),
+            options: &[],
+        };
+
+        if consume!(ptr, buffer, [u8; 4]) != MAGIC_COOKIE {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        Ok(Self {
+            options: consume!(ptr, buffer, [u8], buffer.len() - ptr),
+            ..dhcp
+        })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = 0;
+
+        emit!(ptr, buffer, u8, self.op);
+        emit!(ptr, buffer, u8, self.hardware_ty);
+        emit!(ptr, buffer, u8, self.hardware_len);
+        emit!(ptr, buffer, u8, self.hops);
+        emit!(ptr, buffer, Endianness::Big, u32, self.xid);
+        emit!(ptr, buffer, Endianness::Big, u16, self.secs);
+        emit!(ptr, buffer, Endianness::Big, u16, self.flags);
+        emit!(ptr, buffer, [u8; 4], self.client_ip.octets());
+        emit!(ptr, buffer, [u8; 4], self.your_ip.octets());
+        emit!(ptr, buffer, [u8; 4], self.server_ip.octets());
+        emit!(ptr, buffer, [u8; 4], self.relay_ip.octets());
+        emit!(ptr, buffer, [u8; 16], self.client_hardware_addr);
+        emit!(ptr, buffer, [u8; 64], self.server_name);
+        emit!(ptr, buffer, [u8; 128], self.boot_file);
+        emit!(ptr, buffer, [u8; 4], MAGIC_COOKIE);
+        emit!(ptr, buffer, [u8], self.options);
+
+        ptr
+    }
+}
+
+/// Append a single option to an options buffer
+fn put_option(buffer: &mut [u8], ptr: &mut usize, code: u8, data: &[u8]) {
+    emit!(*ptr, buffer, u8, code);
+    emit!(*ptr, buffer, u8, data.len() as u8);
+    emit!(*ptr, buffer, [u8], data);
+}
+
+struct Client {
+    socket: UdpSocket,
+    xid: u32,
+    /// Tick we started at, for the `secs` field
+    start: u64,
+}
+
+impl Client {
+    fn new() -> Result<Self> {
+        let mac: [u8; 6] = super::mac().into();
+        let start = pit::ticks();
+
+        Ok(Self {
+            socket: UdpSocket::bind(CLIENT_PORT)?,
+            // Only needs to be unlikely to clash with other clients
+            xid: u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]])
+                ^ start as u32,
+            start,
+        })
+    }
+
+    /// Broadcast a DISCOVER or REQUEST, `offer` is the (address, server) we
+    /// are requesting
+    fn send(
+        &self,
+        message_type: MessageType,
+        offer: Option<(Ipv4Addr, Ipv4Addr)>,
+    ) -> Result<()> {
+        let mut options = [0u8; 32];
+        let mut ptr = 0;
+
+        put_option(
+            &mut options,
+            &mut ptr,
+            option::MESSAGE_TYPE,
+            &[message_type.into()],
+        );
+        if let Some((address, server)) = offer {
+            put_option(
+                &mut options,
+                &mut ptr,
+                option::REQUESTED_IP,
+                &address.octets(),
+            );
+            put_option(
+                &mut options,
+                &mut ptr,
+                option::SERVER_ID,
+                &server.octets(),
+            );
+        }
+        put_option(
+            &mut options,
+            &mut ptr,
+            option::PARAMETER_REQUEST_LIST,
+            &[
+                option::SUBNET_MASK,
+                option::ROUTER,
+                option::DNS_SERVERS,
+                option::HOST_NAME,
+                option::LEASE_TIME,
+                option::SERVER_ID,
+                option::TFTP_SERVER_NAME,
+                option::BOOT_FILE_NAME,
+            ],
+        );
+        emit!(ptr, options, u8, option::END);
+
+        let secs = ((pit::ticks() - self.start) / 1000) as u16;
+        let dhcp = Dhcp::request(self.xid, secs, &options[..ptr]);
+
+        let mut buffer = [0u8; Dhcp::HEADER_LEN + 32];
+        let len = dhcp.serialise(&mut buffer);
+
+        self.socket.send_to(
+            &buffer[..len],
+            Ipv4Addr::BROADCAST.into(),
+            SERVER_PORT,
+        )
+    }
+
+    /// Send `message_type` and wait for a reply of one of the `expected`
+    /// types that `on_reply` accepts, retransmitting with exponential backoff
+    fn transact<T>(
+        &self,
+        message_type: MessageType,
+        offer: Option<(Ipv4Addr, Ipv4Addr)>,
+        expected: &[MessageType],
+        mut on_reply: impl FnMut(MessageType, &Dhcp) -> Option<T>,
+    ) -> Result<T> {
+        let mut buffer = [0u8; super::udp::MAX_PAYLOAD_LEN];
+        let mut timeout = INITIAL_TIMEOUT_MS;
+
+        for _ in 0..RETRIES {
+            self.send(message_type, offer)?;
+
+            let deadline = pit::ticks() + timeout;
+            while let Some(remaining) = deadline.checked_sub(pit::ticks()) {
+                let Ok((len, _, _)) =
+                    self.socket.recv_from(&mut buffer, remaining)
+                else {
+                    break;
+                };
+
+                let Ok(reply) = Dhcp::deserialise(&buffer[..len]) else {
+                    continue;
+                };
+                if reply.op != Dhcp::OP_REPLY || reply.xid != self.xid {
+                    continue;
+                }
+
+                let accepted = reply
+                    .message_type()
+                    .filter(|ty| expected.contains(ty))
+                    .and_then(|ty| on_reply(ty, &reply));
+                if let Some(accepted) = accepted {
+                    return Ok(accepted);
+                }
+            }
+
+            timeout *= 2;
+        }
+
+        Err(Error::DhcpTimeout)
+    }
+}
+
+/// Run DISCOVER, OFFER, REQUEST, ACK and configure the interface with the
+/// address we are given
+pub fn obtain_lease() -> Result<Lease> {
+    let client = Client::new()?;
+
+    for _ in 0..MAX_NAKS {
+        // Selecting, we take the first offer we get. One without a server
+        // identifier cannot be requested so we wait for another
+        let (address, server) = client.transact(
+            MessageType::Discover,
+            None,
+            &[MessageType::Offer],
+            |_, offer| offer.server_id().map(|server| (offer.your_ip, server)),
+        )?;
+
+        // Requesting
+        let lease = client.transact(
+            MessageType::Request,
+            Some((address, server)),
+            &[MessageType::Ack, MessageType::Nak],
+            |ty, reply| {
+                Some(
+                    (ty == MessageType::Ack).then(|| Lease::new(reply, server)),
+                )
+            },
+        )?;
+
+        match lease {
+            Some(lease) => {
+                super::configure_ipv4(
+                    lease.address,
+                    lease.netmask,
+                    lease.gateway.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                );
+                super::dns::set_servers(&lease.dns_servers);
+                return Ok(lease);
+            }
+            None => pit::sleep_ms(NAK_DELAY_MS),
+        }
+    }
+
+    Err(Error::DhcpNak)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::RefCell,
+        net::{Ipv4Addr, SocketAddrV4},
+    };
+
+    use alloc::{rc::Rc, vec, vec::Vec};
+
+    use super::*;
+    use crate::net::{self, testing};
+
+    const SERVER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const OFFERED_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 50);
+
+    /// The server's `message_type` answer to `request`, always for
+    /// [`OFFERED_IP`]
+    fn reply(request: &Dhcp, message_type: MessageType) -> Vec<u8> {
+        let mut options = [0u8; 64];
+        let mut ptr = 0;
+        put_option(
+            &mut options,
+            &mut ptr,
+            option::MESSAGE_TYPE,
+            &[message_type.into()],
+        );
+        put_option(
+            &mut options,
+            &mut ptr,
+            option::SERVER_ID,
+            &SERVER_IP.octets(),
+        );
+        put_option(
+            &mut options,
+            &mut ptr,
+            option::SUBNET_MASK,
+            &[255, 255, 255, 0],
+        );
+        put_option(&mut options, &mut ptr, option::ROUTER, &SERVER_IP.octets());
+        put_option(&mut options, &mut ptr, option::BOOT_FILE_NAME, b"kernel");
+        emit!(ptr, options, u8, option::END);
+
+        let dhcp = Dhcp {
+            op: Dhcp::OP_REPLY,
+            your_ip: OFFERED_IP,
+            server_ip: SERVER_IP,
+            ..Dhcp::request(request.xid, 0, &options[..ptr])
+        };
+        let mut buffer = [0u8; Dhcp::HEADER_LEN + 64];
+        let len = dhcp.serialise(&mut buffer);
+
+        testing::udp(
+            SocketAddrV4::new(SERVER_IP, SERVER_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, CLIENT_PORT),
+            &buffer[..len],
+        )
+    }
+
+    #[test]
+    fn lease_from_discover_offer_request_ack() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let log = received.clone();
+        let _stack = testing::take_stack(move |frame| {
+            let Some((_, dst, payload)) = testing::parse_udp(frame) else {
+                return Vec::new();
+            };
+            let Ok(request) = Dhcp::deserialise(payload) else {
+                return Vec::new();
+            };
+            assert_eq!(
+                dst,
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT)
+            );
+
+            let message_type = request.message_type().unwrap();
+            log.borrow_mut().push(message_type);
+            match message_type {
+                MessageType::Discover => {
+                    vec![reply(&request, MessageType::Offer)]
+                }
+                MessageType::Request => {
+                    let requested = request
+                        .options()
+                        .find(|(code, _)| *code == option::REQUESTED_IP)
+                        .and_then(|(_, data)| ip(data));
+                    let answer = if requested == Some(OFFERED_IP)
+                        && request.server_id() == Some(SERVER_IP)
+                    {
+                        MessageType::Ack
+                    } else {
+                        MessageType::Nak
+                    };
+                    vec![reply(&request, answer)]
+                }
+                _ => Vec::new(),
+            }
+        });
+
+        let lease = obtain_lease().unwrap();
+
+        assert_eq!(
+            *received.borrow(),
+            [MessageType::Discover, MessageType::Request]
+        );
+        assert_eq!(lease.address, OFFERED_IP);
+        assert_eq!(lease.netmask, Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(lease.gateway, Some(SERVER_IP));
+        assert_eq!(lease.server, SERVER_IP);
+        assert_eq!(lease.next_server, SERVER_IP);
+        assert_eq!(lease.boot_file.as_deref(), Some("kernel"));
+        assert_eq!(net::ipv4_address(), OFFERED_IP);
+    }
+}
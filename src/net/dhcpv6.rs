@@ -0,0 +1,481 @@
+//! DHCPv6 client [https://www.rfc-editor.org/rfc/rfc8415] for a single
+//! address and the boot file URL [https://www.rfc-editor.org/rfc/rfc5970]
+
+use core::net::Ipv6Addr;
+
+use alloc::string::String;
+
+use crate::{
+    error::{Error, Result},
+    pit,
+};
+
+use super::{ndp, udp::UdpSocket, Endianness, Serialise};
+
+const CLIENT_PORT: u16 = 546;
+const SERVER_PORT: u16 = 547;
+
+/// ff02::1:2, every DHCPv6 server and relay agent on the link
+const ALL_DHCP_AGENTS: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 1, 2);
+
+/// How many times each message is sent before we give up on it
+const RETRIES: u32 = 3;
+/// Timeout for the first attempt, doubled for every retransmit
+const INITIAL_TIMEOUT_MS: u64 = 1_000;
+
+/// DHCPv6 does not tell us the on-link prefix, that is left to router
+/// advertisements. Used when they have not told us either
+const DEFAULT_PREFIX_LEN: u8 = 64;
+
+/// Type 3 DUID, link-layer address [RFC 8415 11.4], for ethernet. Followed by
+/// our MAC
+const DUID_LL: [u8; 4] = [0, 3, 0, 1];
+/// Longest DUID a server may have [RFC 8415 11.1]
+const MAX_DUID_LEN: usize = 130;
+
+/// Room for every option we send
+const MAX_OPTIONS_LEN: usize = 256;
+
+/// Option codes
+mod option {
+    pub const CLIENT_ID: u16 = 1;
+    pub const SERVER_ID: u16 = 2;
+    pub const IA_NA: u16 = 3;
+    pub const IA_ADDR: u16 = 5;
+    pub const OPTION_REQUEST: u16 = 6;
+    pub const ELAPSED_TIME: u16 = 8;
+    pub const STATUS_CODE: u16 = 13;
+    pub const BOOT_FILE_URL: u16 = 59;
+}
+
+/// Status code meaning everything in the enclosing option is usable
+const STATUS_SUCCESS: u16 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageType {
+    Solicit,
+    Advertise,
+    Request,
+    Reply,
+    Unknown(u8),
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Solicit,
+            2 => Self::Advertise,
+            3 => Self::Request,
+            7 => Self::Reply,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<MessageType> for u8 {
+    fn from(value: MessageType) -> Self {
+        match value {
+            MessageType::Solicit => 1,
+            MessageType::Advertise => 2,
+            MessageType::Request => 3,
+            MessageType::Reply => 7,
+            MessageType::Unknown(value) => value,
+        }
+    }
+}
+
+/// Everything the rest of the boot needs to know from the server
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub address: Ipv6Addr,
+    /// Option 59, where to download the kernel from
+    pub boot_file_url: Option<String>,
+}
+
+impl Lease {
+    fn new(message: &Dhcpv6, iaid: [u8; 4]) -> Option<Self> {
+        if !is_success(message.options) {
+            return None;
+        }
+
+        let address = ia_address(message, iaid)?;
+        let boot_file_url = find(message.options, option::BOOT_FILE_URL)
+            .filter(|url| !url.is_empty())
+            .map(|url| String::from_utf8_lossy(url).into_owned());
+
+        Some(Self {
+            address,
+            boot_file_url,
+        })
+    }
+}
+
+/// Iterator over the (code, data) pairs in an options field, options nest so
+/// this is also used on the data of some options
+struct Options<'a> {
+    buffer: &'a [u8],
+    ptr: usize,
+}
+
+impl<'a> Options<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, ptr: 0 }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.buffer.get(self.ptr..self.ptr + 4)?;
+        let code = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+        let data = self.buffer.get(self.ptr + 4..self.ptr + 4 + len)?;
+        self.ptr += 4 + len;
+
+        Some((code, data))
+    }
+}
+
+/// Data of the first option with `code`
+fn find(options: &[u8], code: u16) -> Option<&[u8]> {
+    Options::new(options)
+        .find(|(option, _)| *option == code)
+        .map(|(_, data)| data)
+}
+
+/// A missing status code means success
+fn is_success(options: &[u8]) -> bool {
+    find(options, option::STATUS_CODE)
+        .and_then(|data| data.get(..2))
+        .is_none_or(|code| {
+            u16::from_be_bytes([code[0], code[1]]) == STATUS_SUCCESS
+        })
+}
+
+/// The first address in our identity association that is still valid
+fn ia_address(message: &Dhcpv6, iaid: [u8; 4]) -> Option<Ipv6Addr> {
+    // IAID, T1 and T2 then the options for the association
+    let ia = find(message.options, option::IA_NA)?;
+    if ia.len() < 12 || ia[..4] != iaid || !is_success(&ia[12..]) {
+        return None;
+    }
+
+    Options::new(&ia[12..])
+        .filter(|&(code, data)| code == option::IA_ADDR && data.len() >= 24)
+        .find_map(|(_, data)| {
+            // Address, preferred lifetime, valid lifetime then options
+            let mut address = [0; 16];
+            address.copy_from_slice(&data[..16]);
+            let valid_lifetime =
+                u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
+
+            (valid_lifetime != 0 && is_success(&data[24..]))
+                .then_some(address.into())
+        })
+}
+
+/// Append a single option to an options buffer
+fn put_option(buffer: &mut [u8], ptr: &mut usize, code: u16, data: &[u8]) {
+    emit!(*ptr, buffer, Endianness::Big, u16, code);
+    emit!(*ptr, buffer, Endianness::Big, u16, data.len() as u16);
+    emit!(*ptr, buffer, [u8], data);
+}
+
+/// Client and server message, relay messages have a different layout and are
+/// never sent to clients
+#[derive(Debug)]
+struct Dhcpv6<'a> {
+    ty: MessageType,
+    /// Only the low 24 bits are sent
+    xid: u32,
+    options: &'a [u8],
+}
+
+impl Dhcpv6<'_> {
+    const HEADER_LEN: usize = 4;
+    const XID_MASK: u32 = 0x00FF_FFFF;
+}
+
+impl<'a> Serialise<'a> for Dhcpv6<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::HEADER_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        let header = consume!(ptr, buffer, Endianness::Big, u32);
+
+        Ok(Self {
+            ty: ((header >> 24) as u8).into(),
+            xid: header & Self::XID_MASK,
+            options: consume!(ptr, buffer, [u8], buffer.len() - ptr),
+        })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let header =
+            (u8::from(self.ty) as u32) << 24 | self.xid & Self::XID_MASK;
+
+        let mut ptr = 0;
+        emit!(ptr, buffer, Endianness::Big, u32, header);
+        emit!(ptr, buffer, [u8], self.options);
+
+        ptr
+    }
+}
+
+struct Client {
+    socket: UdpSocket,
+    /// Our DUID
+    client_id: [u8; 10],
+    /// Identifies our one identity association for non-temporary addresses
+    iaid: [u8; 4],
+}
+
+impl Client {
+    fn new() -> Result<Self> {
+        let mac: [u8; 6] = super::mac().into();
+
+        let mut client_id = [0; 10];
+        client_id[..4].copy_from_slice(&DUID_LL);
+        client_id[4..].copy_from_slice(&mac);
+
+        Ok(Self {
+            socket: UdpSocket::bind(CLIENT_PORT)?,
+            client_id,
+            iaid: [mac[2], mac[3], mac[4], mac[5]],
+        })
+    }
+
+    /// Multicast a SOLICIT or REQUEST, `server_id` and `address` are what we
+    /// are requesting
+    fn send(
+        &self,
+        message_type: MessageType,
+        xid: u32,
+        elapsed: u16,
+        server_id: Option<&[u8]>,
+        address: Option<Ipv6Addr>,
+    ) -> Result<()> {
+        let mut options = [0u8; MAX_OPTIONS_LEN];
+        let mut ptr = 0;
+
+        put_option(&mut options, &mut ptr, option::CLIENT_ID, &self.client_id);
+        if let Some(server_id) = server_id {
+            put_option(&mut options, &mut ptr, option::SERVER_ID, server_id);
+        }
+
+        // Leave T1 and T2 up to the server, we never renew
+        let mut ia = [0u8; 12 + 4 + 24];
+        let mut ia_len = 0;
+        emit!(ia_len, ia, [u8; 4], self.iaid);
+        emit!(ia_len, ia, Endianness::Big, u32, 0);
+        emit!(ia_len, ia, Endianness::Big, u32, 0);
+        if let Some(address) = address {
+            let mut ia_address = [0u8; 24];
+            ia_address[..16].copy_from_slice(&address.octets());
+            put_option(&mut ia, &mut ia_len, option::IA_ADDR, &ia_address);
+        }
+        put_option(&mut options, &mut ptr, option::IA_NA, &ia[..ia_len]);
+
+        put_option(
+            &mut options,
+            &mut ptr,
+            option::OPTION_REQUEST,
+            &option::BOOT_FILE_URL.to_be_bytes(),
+        );
+        put_option(
+            &mut options,
+            &mut ptr,
+            option::ELAPSED_TIME,
+            &elapsed.to_be_bytes(),
+        );
+
+        let dhcpv6 = Dhcpv6 {
+            ty: message_type,
+            xid,
+            options: &options[..ptr],
+        };
+
+        let mut buffer = [0u8; Dhcpv6::HEADER_LEN + MAX_OPTIONS_LEN];
+        let len = dhcpv6.serialise(&mut buffer);
+
+        self.socket
+            .send_to(&buffer[..len], ALL_DHCP_AGENTS.into(), SERVER_PORT)
+    }
+
+    /// Send `message_type` and wait for an `expected` reply that `on_reply`
+    /// accepts, retransmitting with exponential backoff
+    fn transact<T>(
+        &self,
+        message_type: MessageType,
+        server_id: Option<&[u8]>,
+        address: Option<Ipv6Addr>,
+        expected: MessageType,
+        mut on_reply: impl FnMut(&Dhcpv6) -> Option<T>,
+    ) -> Result<T> {
+        let mut buffer = [0u8; super::udp::MAX_PAYLOAD_LEN];
+        let mut timeout = INITIAL_TIMEOUT_MS;
+
+        // Every exchange gets its own transaction ID and elapsed time
+        let start = pit::ticks();
+        let xid =
+            (u32::from_be_bytes(self.iaid) ^ start as u32) & Dhcpv6::XID_MASK;
+
+        for _ in 0..RETRIES {
+            // In hundredths of a second
+            let elapsed = ((pit::ticks() - start) / 10).min(u16::MAX as u64);
+            self.send(message_type, xid, elapsed as u16, server_id, address)?;
+
+            let deadline = pit::ticks() + timeout;
+            while let Some(remaining) = deadline.checked_sub(pit::ticks()) {
+                let Ok((len, _, _)) =
+                    self.socket.recv_from(&mut buffer, remaining)
+                else {
+                    break;
+                };
+
+                let Ok(reply) = Dhcpv6::deserialise(&buffer[..len]) else {
+                    continue;
+                };
+                if reply.ty != expected
+                    || reply.xid != xid
+                    || find(reply.options, option::CLIENT_ID)
+                        != Some(&self.client_id[..])
+                {
+                    continue;
+                }
+
+                if let Some(result) = on_reply(&reply) {
+                    return Ok(result);
+                }
+            }
+
+            timeout *= 2;
+        }
+
+        Err(Error::Dhcpv6Timeout)
+    }
+}
+
+/// Run SOLICIT, ADVERTISE, REQUEST, REPLY and configure the interface with the
+/// address we are given. Run [`super::autoconfigure_ipv6`] first so we know
+/// our router, our on-link prefix and whether there is a server to ask
+pub fn obtain_ipv6_lease() -> Result<Lease> {
+    if !ndp::dhcpv6_advertised() {
+        return Err(Error::Dhcpv6NotAdvertised);
+    }
+
+    let client = Client::new()?;
+
+    // Soliciting, we take the first advertisement with an address in it
+    let (address, server_id) = client.transact(
+        MessageType::Solicit,
+        None,
+        None,
+        MessageType::Advertise,
+        |advertise| {
+            let address = ia_address(advertise, client.iaid)?;
+            let server_id = find(advertise.options, option::SERVER_ID)?;
+
+            (server_id.len() <= MAX_DUID_LEN)
+                .then(|| (address, server_id.to_vec()))
+        },
+    )?;
+
+    // Requesting
+    let lease = client
+        .transact(
+            MessageType::Request,
+            Some(&server_id),
+            Some(address),
+            MessageType::Reply,
+            |reply| {
+                (find(reply.options, option::SERVER_ID) == Some(&server_id[..]))
+                    .then(|| Lease::new(reply, client.iaid))
+            },
+        )?
+        .ok_or(Error::Dhcpv6NoAddress)?;
+
+    // We should DECLINE a duplicate, but we would not be using the server
+    // again either way
+    ndp::check_duplicate(lease.address)?;
+
+    let prefix_len = match super::ipv6_prefix_len() {
+        0 => DEFAULT_PREFIX_LEN,
+        prefix_len => prefix_len,
+    };
+    let router = super::ipv6_router()
+        .or_else(ndp::advertised_router)
+        .unwrap_or(Ipv6Addr::UNSPECIFIED);
+    super::configure_ipv6(lease.address, prefix_len, router);
+
+    Ok(lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IAID: [u8; 4] = [0, 0, 0, 1];
+    const NO_ADDRS_AVAIL: [u8; 2] = [0, 2];
+
+    fn option(code: u16, data: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; 4 + data.len()];
+        put_option(&mut buffer, &mut 0, code, data);
+        buffer
+    }
+
+    /// IA address with the same preferred and valid lifetime
+    fn ia_addr(address: u16, lifetime: u32, options: &[u8]) -> Vec<u8> {
+        let address = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, address);
+        let mut data = Vec::from(address.octets());
+        data.extend_from_slice(&lifetime.to_be_bytes());
+        data.extend_from_slice(&lifetime.to_be_bytes());
+        data.extend_from_slice(options);
+        option(option::IA_ADDR, &data)
+    }
+
+    /// Reply with a single IA_NA, T1 and T2 are left at 0
+    fn ia_address_in(ia_options: &[Vec<u8>], iaid: [u8; 4]) -> Option<u16> {
+        let mut ia = Vec::from(IAID);
+        ia.extend_from_slice(&[0; 8]);
+        ia.extend(ia_options.concat());
+        let options = option(option::IA_NA, &ia);
+
+        let message = Dhcpv6 {
+            ty: MessageType::Reply,
+            xid: 0,
+            options: &options,
+        };
+        ia_address(&message, iaid).map(|address| address.segments()[7])
+    }
+
+    #[test]
+    fn missing_status_is_success() {
+        assert!(is_success(&[]));
+        assert!(is_success(&option(option::STATUS_CODE, b"\0\0ok")));
+        assert!(!is_success(&option(option::STATUS_CODE, &NO_ADDRS_AVAIL)));
+    }
+
+    #[test]
+    fn ia_address_skips_unusable_addresses() {
+        let refused = option(option::STATUS_CODE, &NO_ADDRS_AVAIL);
+        let addresses = [
+            ia_addr(1, 0, &[]),
+            ia_addr(2, 3600, &refused),
+            ia_addr(3, 3600, &[]),
+        ];
+
+        assert_eq!(ia_address_in(&addresses, IAID), Some(3));
+        // Someone else's association
+        assert_eq!(ia_address_in(&addresses, [0, 0, 0, 2]), None);
+        // The whole association was refused
+        assert_eq!(
+            ia_address_in(&[refused, ia_addr(3, 3600, &[])], IAID),
+            None
+        );
+    }
+}
@@ -0,0 +1,515 @@
+//! DNS stub resolver for A records [https://www.rfc-editor.org/rfc/rfc1035]
+
+use core::{
+    net::{IpAddr, Ipv4Addr},
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+};
+
+use alloc::{boxed::Box, string::String};
+
+use crate::{
+    error::{Error, Result},
+    pit,
+};
+
+use super::{
+    udp::{self, UdpSocket},
+    Endianness, Serialise,
+};
+
+const SERVER_PORT: u16 = 53;
+
+/// Servers we remember from DHCP
+const MAX_SERVERS: usize = 3;
+/// How long to wait for each answer
+const TIMEOUT_MS: u64 = 2_000;
+/// How many times we ask each server
+const RETRIES: usize = 2;
+/// Longest CNAME chain we will follow
+const MAX_CNAME_DEPTH: usize = 8;
+/// Most compression pointers we follow in one name, stops loops
+const MAX_POINTERS: usize = 16;
+
+/// How many answers we remember
+const CACHE_SIZE: usize = 8;
+/// Cap on how long we trust an answer for, in seconds
+const MAX_TTL: u32 = 86_400;
+
+/// Longest name in its dotted text form
+const MAX_NAME_LEN: usize = 253;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const CLASS_IN: u16 = 1;
+
+/// Servers learnt from DHCP option 6, unspecified slots are unused
+static SERVERS: [AtomicU32; MAX_SERVERS] =
+    [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+/// ID of the next query we send
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Only used from [`resolve_host`] so never touched while handling a packet
+static mut CACHE: [Option<CacheEntry>; CACHE_SIZE] = {
+    const EMPTY: Option<CacheEntry> = None;
+    [EMPTY; CACHE_SIZE]
+};
+
+struct CacheEntry {
+    name: String,
+    address: Ipv4Addr,
+    /// Tick at which this entry is no longer valid
+    expires: u64,
+}
+
+/// Set the servers [`resolve_host`] asks, extras past the first three are
+/// ignored
+pub(super) fn set_servers(servers: &[Ipv4Addr]) {
+    for (i, slot) in SERVERS.iter().enumerate() {
+        let server = servers.get(i).copied().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        slot.store(server.into(), Ordering::Relaxed);
+    }
+}
+
+fn lookup(name: &str) -> Option<Ipv4Addr> {
+    let now = pit::ticks();
+    unsafe {
+        CACHE
+            .iter()
+            .flatten()
+            .find(|entry| {
+                entry.expires > now && entry.name.eq_ignore_ascii_case(name)
+            })
+            .map(|entry| entry.address)
+    }
+}
+
+/// Insert an answer, if the cache is full the entry closest to expiring is
+/// replaced
+fn insert(name: &str, address: Ipv4Addr, ttl: u32) {
+    let entry = CacheEntry {
+        name: String::from(name),
+        address,
+        expires: pit::ticks() + ttl.min(MAX_TTL) as u64 * 1000,
+    };
+
+    unsafe {
+        let victim = CACHE
+            .iter()
+            .position(|slot| slot.is_none())
+            .or_else(|| {
+                CACHE
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, slot)| slot.as_ref().map(|e| e.expires))
+                    .map(|(i, _)| i)
+            })
+            .unwrap_or(0);
+        CACHE[victim] = Some(entry);
+    }
+}
+
+/// A name in its dotted text form
+struct Name {
+    bytes: [u8; MAX_NAME_LEN],
+    len: usize,
+}
+
+impl Name {
+    /// Copy a dotted name we were given into a [`Name`]
+    fn read_str(name: &str) -> Result<Self> {
+        let name = name.trim_end_matches('.');
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::InvalidHostName);
+        }
+
+        let mut bytes = [0; MAX_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+        Ok(Self {
+            bytes,
+            len: name.len(),
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    /// Read a possibly compressed name from a message, returning it and the
+    /// offset just past it
+    fn read(message: &[u8], mut ptr: usize) -> Result<(Self, usize)> {
+        let mut name = Self {
+            bytes: [0; MAX_NAME_LEN],
+            len: 0,
+        };
+        // Where parsing continues once the name is done, set by the first
+        // compression pointer we follow
+        let mut end = None;
+        let mut pointers = 0;
+
+        loop {
+            let len = *message.get(ptr).ok_or(Error::CouldNotParsePacket)?;
+            match len {
+                0 => {
+                    ptr += 1;
+                    break;
+                }
+                // Top two bits set is a pointer to the rest of the name
+                len if len & 0xC0 == 0xC0 => {
+                    let low = *message
+                        .get(ptr + 1)
+                        .ok_or(Error::CouldNotParsePacket)?;
+                    end.get_or_insert(ptr + 2);
+
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(Error::CouldNotParsePacket);
+                    }
+                    ptr = ((len as usize & 0x3F) << 8) | low as usize;
+                }
+                len => {
+                    let len = len as usize;
+                    let label = message
+                        .get(ptr + 1..ptr + 1 + len)
+                        .ok_or(Error::CouldNotParsePacket)?;
+                    let dot = (name.len != 0) as usize;
+                    if name.len + dot + len > MAX_NAME_LEN {
+                        return Err(Error::CouldNotParsePacket);
+                    }
+
+                    if dot == 1 {
+                        name.bytes[name.len] = b'.';
+                    }
+                    name.bytes[name.len + dot..name.len + dot + len]
+                        .copy_from_slice(label);
+                    name.len += dot + len;
+                    ptr += 1 + len;
+                }
+            }
+        }
+
+        Ok((name, end.unwrap_or(ptr)))
+    }
+}
+
+/// Emit a dotted name as uncompressed labels
+fn emit_name(ptr: &mut usize, buffer: &mut [u8], name: &str) -> Result<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::InvalidHostName);
+        }
+        emit!(*ptr, buffer, u8, label.len() as u8);
+        emit!(*ptr, buffer, [u8], label.as_bytes());
+    }
+    emit!(*ptr, buffer, u8, 0);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Header {
+    id: u16,
+    flags: u16,
+    questions: u16,
+    answers: u16,
+    authorities: u16,
+    additionals: u16,
+}
+
+impl Header {
+    const LEN: usize = 12;
+
+    /// This is a response
+    const FLAG_RESPONSE: u16 = 1 << 15;
+    /// Ask the server to do the recursion for us
+    const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+    const RCODE_MASK: u16 = 0x000F;
+    const RCODE_NAME_ERROR: u16 = 3;
+}
+
+impl Serialise<'_> for Header {
+    fn deserialise(buffer: &[u8]) -> Result<Self> {
+        if buffer.len() < Self::LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        Ok(Self {
+            id: consume!(ptr, buffer, Endianness::Big, u16),
+            flags: consume!(ptr, buffer, Endianness::Big, u16),
+            questions: consume!(ptr, buffer, Endianness::Big, u16),
+            answers: consume!(ptr, buffer, Endianness::Big, u16),
+            authorities: consume!(ptr, buffer, Endianness::Big, u16),
+            additionals: consume!(ptr, buffer, Endianness::Big, u16),
+        })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = 0;
+
+        emit!(ptr, buffer, Endianness::Big, u16, self.id);
+        emit!(ptr, buffer, Endianness::Big, u16, self.flags);
+        emit!(ptr, buffer, Endianness::Big, u16, self.questions);
+        emit!(ptr, buffer, Endianness::Big, u16, self.answers);
+        emit!(ptr, buffer, Endianness::Big, u16, self.authorities);
+        emit!(ptr, buffer, Endianness::Big, u16, self.additionals);
+
+        ptr
+    }
+}
+
+/// What a server told us about a name
+enum Answer {
+    Address(Ipv4Addr, u32),
+    /// Boxed as a name is far bigger than an address
+    Alias(Box<Name>, u32),
+}
+
+/// Parse a response, following any CNAMEs for `name` within it. The TTL is
+/// the shortest along the chain
+fn parse_response(message: &[u8], id: u16, name: &str) -> Result<Answer> {
+    let header = Header::deserialise(message)?;
+    if header.id != id || (header.flags & Header::FLAG_RESPONSE) == 0 {
+        return Err(Error::CouldNotParsePacket);
+    }
+    match header.flags & Header::RCODE_MASK {
+        0 => {}
+        Header::RCODE_NAME_ERROR => return Err(Error::DnsNameNotFound),
+        _ => return Err(Error::DnsServerFailure),
+    }
+
+    // Skip the question, type and class
+    let mut ptr = Header::LEN;
+    for _ in 0..header.questions {
+        ptr = Name::read(message, ptr)?.1 + 4;
+    }
+
+    let mut target = Name::read_str(name)?;
+    let mut aliased = false;
+    let mut chain_ttl = u32::MAX;
+    for _ in 0..header.answers {
+        let (owner, next) = Name::read(message, ptr)?;
+        ptr = next;
+
+        if message.len() < ptr + 10 {
+            return Err(Error::CouldNotParsePacket);
+        }
+        let ty = consume!(ptr, message, Endianness::Big, u16);
+        let class = consume!(ptr, message, Endianness::Big, u16);
+        let ttl = consume!(ptr, message, Endianness::Big, u32);
+        let len = consume!(ptr, message, Endianness::Big, u16) as usize;
+        let data_start = ptr;
+        ptr += len;
+        if message.len() < ptr {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        if class != CLASS_IN
+            || !owner.as_str().eq_ignore_ascii_case(target.as_str())
+        {
+            continue;
+        }
+
+        match ty {
+            TYPE_A if len == 4 => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(&message[data_start..ptr]);
+                return Ok(Answer::Address(octets.into(), ttl.min(chain_ttl)));
+            }
+            // Servers put the chain in order, so keep looking for the alias
+            TYPE_CNAME => {
+                target = Name::read(message, data_start)?.0;
+                aliased = true;
+                chain_ttl = chain_ttl.min(ttl);
+            }
+            _ => {}
+        }
+    }
+
+    if aliased {
+        Ok(Answer::Alias(Box::new(target), chain_ttl))
+    } else {
+        Err(Error::DnsNoAddress)
+    }
+}
+
+/// Ask each server in turn about `name`
+fn query(socket: &UdpSocket, name: &str) -> Result<Answer> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut request = [0u8; Header::LEN + MAX_NAME_LEN + 6];
+    let mut len = Header {
+        id,
+        flags: Header::FLAG_RECURSION_DESIRED,
+        questions: 1,
+        answers: 0,
+        authorities: 0,
+        additionals: 0,
+    }
+    .serialise(&mut request);
+    emit_name(&mut len, &mut request, name)?;
+    emit!(len, request, Endianness::Big, u16, TYPE_A);
+    emit!(len, request, Endianness::Big, u16, CLASS_IN);
+
+    let mut response = [0u8; udp::MAX_PAYLOAD_LEN];
+    let mut asked = false;
+
+    for server in SERVERS.iter() {
+        let server: Ipv4Addr = server.load(Ordering::Relaxed).into();
+        if server.is_unspecified() {
+            continue;
+        }
+        asked = true;
+
+        for _ in 0..RETRIES {
+            socket.send_to(&request[..len], server.into(), SERVER_PORT)?;
+
+            let deadline = pit::ticks() + TIMEOUT_MS;
+            while let Some(remaining) = deadline.checked_sub(pit::ticks()) {
+                let Ok((received, src_ip, src_port)) =
+                    socket.recv_from(&mut response, remaining)
+                else {
+                    break;
+                };
+                if src_ip != IpAddr::V4(server) || src_port != SERVER_PORT {
+                    continue;
+                }
+
+                match parse_response(&response[..received], id, name) {
+                    // Not the answer to this question
+                    Err(Error::CouldNotParsePacket) => continue,
+                    answer => return answer,
+                }
+            }
+        }
+    }
+
+    if asked {
+        Err(Error::DnsTimeout)
+    } else {
+        Err(Error::DnsNoServers)
+    }
+}
+
+/// Look up the IPv4 address of `host`, which may also be a dotted IP
+pub fn resolve_host(host: &str) -> Result<Ipv4Addr> {
+    if let Ok(address) = host.parse() {
+        return Ok(address);
+    }
+    if let Some(address) = lookup(host) {
+        return Ok(address);
+    }
+
+    let socket = UdpSocket::bind(0)?;
+    let mut name = Name::read_str(host)?;
+    // Cached only for as long as every alias on the way holds
+    let mut chain_ttl = u32::MAX;
+
+    for _ in 0..MAX_CNAME_DEPTH {
+        match query(&socket, name.as_str())? {
+            Answer::Address(address, ttl) => {
+                insert(host, address, ttl.min(chain_ttl));
+                return Ok(address);
+            }
+            // The server only gave us an alias, ask about that instead
+            Answer::Alias(alias, ttl) => {
+                name = *alias;
+                chain_ttl = chain_ttl.min(ttl);
+            }
+        }
+    }
+
+    Err(Error::DnsNoAddress)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Pointer to the name that starts right after the header
+    const QUESTION_NAME: [u8; 2] = [0xC0, Header::LEN as u8];
+
+    /// Append an IN class resource record
+    fn record(
+        message: &mut Vec<u8>,
+        owner: &[u8],
+        ty: u16,
+        ttl: u32,
+        data: &[u8],
+    ) {
+        message.extend_from_slice(owner);
+        message.extend_from_slice(&ty.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+    }
+
+    #[test]
+    fn compressed_name_is_followed() {
+        let mut message = Vec::from([0u8; Header::LEN]);
+        message.extend_from_slice(b"\x04boot\x07example\x00");
+        let www = message.len();
+        message.extend_from_slice(b"\x03www");
+        message.extend_from_slice(&QUESTION_NAME);
+        message.push(0xFF);
+
+        let (name, end) = Name::read(&message, www).unwrap();
+        assert_eq!(name.as_str(), "www.boot.example");
+        // Parsing carries on after the pointer, not where it led
+        assert_eq!(end, message.len() - 1);
+    }
+
+    #[test]
+    fn pointer_loop_is_rejected() {
+        let mut message = Vec::from([0u8; Header::LEN]);
+        message.extend_from_slice(&QUESTION_NAME);
+
+        assert!(Name::read(&message, Header::LEN).is_err());
+    }
+
+    #[test]
+    fn alias_chain_keeps_the_shortest_ttl() {
+        let mut message = Vec::from([0u8; Header::LEN]);
+        Header {
+            id: 7,
+            flags: Header::FLAG_RESPONSE,
+            questions: 1,
+            answers: 2,
+            authorities: 0,
+            additionals: 0,
+        }
+        .serialise(&mut message);
+        message.extend_from_slice(b"\x04boot\x07example\x00");
+        message.extend_from_slice(&TYPE_A.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        // cdn.boot.example, just past the owner and fixed fields
+        let alias = message.len() + 12;
+        record(
+            &mut message,
+            &QUESTION_NAME,
+            TYPE_CNAME,
+            60,
+            b"\x03cdn\xC0\x0C",
+        );
+        record(
+            &mut message,
+            &[0xC0, alias as u8],
+            TYPE_A,
+            3600,
+            &[192, 168, 0, 10],
+        );
+
+        match parse_response(&message, 7, "boot.example").unwrap() {
+            Answer::Address(address, ttl) => {
+                assert_eq!(address, Ipv4Addr::new(192, 168, 0, 10));
+                assert_eq!(ttl, 60);
+            }
+            Answer::Alias(..) => panic!("Alias not followed"),
+        }
+    }
+}
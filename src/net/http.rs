@@ -0,0 +1,549 @@
+//! HTTP/1.1 GET client [https://www.rfc-editor.org/rfc/rfc9112] with
+//! redirects and range requests (RFC 9110) to resume interrupted downloads
+
+use core::net::IpAddr;
+
+use alloc::{format, string::String};
+
+use crate::error::{Error, Result};
+
+use super::{dns, progress::Progress, tcp::TcpStream};
+
+const DEFAULT_PORT: u16 = 80;
+
+/// Redirects we follow before deciding the server is going in circles
+const MAX_REDIRECTS: usize = 5;
+/// Times we reconnect to carry on from where a download was cut off
+const MAX_RESUMES: usize = 3;
+/// Longest status or header line we accept
+const MAX_LINE_LEN: usize = 1024;
+/// Bytes read from the connection at a time while parsing headers
+const READ_BUFFER_LEN: usize = 2048;
+
+/// Status codes we do something with
+mod status {
+    pub const OK: u16 = 200;
+    pub const PARTIAL_CONTENT: u16 = 206;
+    pub const MOVED_PERMANENTLY: u16 = 301;
+    pub const FOUND: u16 = 302;
+    pub const SEE_OTHER: u16 = 303;
+    pub const TEMPORARY_REDIRECT: u16 = 307;
+    pub const PERMANENT_REDIRECT: u16 = 308;
+    pub const NOT_FOUND: u16 = 404;
+}
+
+/// The parts of an `http://` URL we need to make a request
+#[derive(Debug)]
+struct Url<'a> {
+    /// Host and optional port, as sent in the `Host` header
+    authority: &'a str,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    const SCHEME: &'static str = "http://";
+
+    fn parse(url: &'a str) -> Result<Self> {
+        let rest = url
+            .get(..Self::SCHEME.len())
+            .filter(|scheme| scheme.eq_ignore_ascii_case(Self::SCHEME))
+            .map(|_| &url[Self::SCHEME.len()..])
+            .ok_or(Error::InvalidUrl)?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        // IPv6 literals are in brackets as they are full of colons
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(literal) => {
+                let (host, port) =
+                    literal.split_once(']').ok_or(Error::InvalidUrl)?;
+                let port = match port {
+                    "" => None,
+                    port => {
+                        Some(port.strip_prefix(':').ok_or(Error::InvalidUrl)?)
+                    }
+                };
+                (host, port)
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| Error::InvalidUrl)?,
+            None => DEFAULT_PORT,
+        };
+        if host.is_empty() {
+            return Err(Error::InvalidUrl);
+        }
+
+        Ok(Self {
+            authority,
+            host,
+            port,
+            path,
+        })
+    }
+
+    /// Work out where a `Location` header points, relative to this URL
+    fn join(&self, location: &str) -> String {
+        // Absolute, other schemes fail when we try to fetch them
+        if location.contains("://") {
+            String::from(location)
+        } else if location.starts_with('/') {
+            format!("{}{}{}", Self::SCHEME, self.authority, location)
+        } else {
+            let directory = match self.path.rfind('/') {
+                Some(index) => &self.path[..=index],
+                None => "/",
+            };
+            format!(
+                "{}{}{}{}",
+                Self::SCHEME,
+                self.authority,
+                directory,
+                location
+            )
+        }
+    }
+}
+
+/// What we took from the status line and headers
+#[derive(Debug, Default)]
+struct Response {
+    status: u16,
+    content_length: Option<usize>,
+    chunked: bool,
+    location: Option<String>,
+    /// First byte of a partial response
+    range_start: Option<usize>,
+    /// Size of the whole file in a partial response, if the server knows it
+    range_total: Option<usize>,
+}
+
+/// Buffers the connection so we can read headers a line at a time, the body
+/// is read straight into memory once the buffer is drained
+struct Reader {
+    stream: TcpStream,
+    buffer: [u8; READ_BUFFER_LEN],
+    start: usize,
+    end: usize,
+}
+
+impl Reader {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: [0; READ_BUFFER_LEN],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Read into `buffer`, returning 0 once the server has closed
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if self.start == self.end {
+            return self.stream.read(buffer);
+        }
+
+        let len = (self.end - self.start).min(buffer.len());
+        buffer[..len]
+            .copy_from_slice(&self.buffer[self.start..self.start + len]);
+        self.start += len;
+        Ok(len)
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = self.stream.read(&mut self.buffer)?;
+            if self.end == 0 {
+                return Err(Error::HttpTruncated);
+            }
+        }
+
+        self.start += 1;
+        Ok(self.buffer[self.start - 1])
+    }
+
+    /// Read a line without its CRLF
+    fn read_line<'b>(&mut self, line: &'b mut [u8]) -> Result<&'b str> {
+        let mut len = 0;
+        loop {
+            match self.read_byte()? {
+                b'\n' => break,
+                byte => {
+                    *line.get_mut(len).ok_or(Error::HttpBadResponse)? = byte;
+                    len += 1;
+                }
+            }
+        }
+
+        let line = core::str::from_utf8(&line[..len])
+            .map_err(|_| Error::HttpBadResponse)?;
+        Ok(line.strip_suffix('\r').unwrap_or(line))
+    }
+
+    /// Read the status line and headers, skipping any informational responses
+    fn response(&mut self) -> Result<Response> {
+        let mut line = [0u8; MAX_LINE_LEN];
+
+        loop {
+            // HTTP/1.1 200 OK
+            let status = self
+                .read_line(&mut line)?
+                .split(' ')
+                .nth(1)
+                .and_then(|status| status.parse().ok())
+                .ok_or(Error::HttpBadResponse)?;
+            let mut response = Response {
+                status,
+                ..Default::default()
+            };
+
+            loop {
+                let header = self.read_line(&mut line)?;
+                if header.is_empty() {
+                    break;
+                }
+                let Some((name, value)) = header.split_once(':') else {
+                    return Err(Error::HttpBadResponse);
+                };
+                response.header(name.trim(), value.trim())?;
+            }
+
+            if !(100..200).contains(&response.status) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+impl Response {
+    fn header(&mut self, name: &str, value: &str) -> Result<()> {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            self.content_length =
+                Some(value.parse().map_err(|_| Error::HttpBadResponse)?);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            // Chunked is always the last coding applied
+            self.chunked = value.rsplit(',').next().is_some_and(|coding| {
+                coding.trim().eq_ignore_ascii_case("chunked")
+            });
+        } else if name.eq_ignore_ascii_case("Location") {
+            self.location = Some(String::from(value));
+        } else if name.eq_ignore_ascii_case("Content-Range") {
+            // bytes 1000-1999/2000, the total may be *
+            let (start, total) = value
+                .strip_prefix("bytes ")
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(start, rest)| {
+                    Some((start, rest.split_once('/')?.1))
+                })
+                .ok_or(Error::HttpBadResponse)?;
+            self.range_start =
+                Some(start.parse().map_err(|_| Error::HttpBadResponse)?);
+            self.range_total = total.parse().ok();
+        }
+
+        Ok(())
+    }
+}
+
+/// How a single request ended
+enum Fetch {
+    Done,
+    Redirect(String),
+}
+
+/// Where the body goes and how much of it we have
+struct Download<'a> {
+    region: &'a mut [u8],
+    written: usize,
+    progress: Progress,
+}
+
+impl Download<'_> {
+    /// Read `len` bytes of body, or everything until the server closes if the
+    /// length is not known
+    fn receive(
+        &mut self,
+        reader: &mut Reader,
+        len: Option<usize>,
+    ) -> Result<()> {
+        // The length is whatever the server said, which may be nonsense
+        let end = match len {
+            Some(len) => self
+                .written
+                .checked_add(len)
+                .ok_or(Error::HttpBadResponse)?,
+            None => usize::MAX,
+        };
+
+        while self.written < end {
+            if self.written == self.region.len() {
+                return Err(Error::HttpFileTooLarge);
+            }
+
+            let limit = end.min(self.region.len());
+            let received =
+                reader.read(&mut self.region[self.written..limit])?;
+            if received == 0 {
+                return match len {
+                    Some(_) => Err(Error::HttpTruncated),
+                    None => Ok(()),
+                };
+            }
+
+            self.written += received;
+            self.progress.update(self.written);
+        }
+
+        Ok(())
+    }
+
+    fn receive_chunked(&mut self, reader: &mut Reader) -> Result<()> {
+        let mut line = [0u8; MAX_LINE_LEN];
+
+        loop {
+            // Size in hex, optionally followed by extensions we ignore
+            let size = reader.read_line(&mut line)?;
+            let size = size.split(';').next().unwrap_or(size).trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| Error::HttpBadResponse)?;
+
+            if size == 0 {
+                // Skip any trailers up to the blank line that ends the body
+                while !reader.read_line(&mut line)?.is_empty() {}
+                return Ok(());
+            }
+
+            self.receive(reader, Some(size))?;
+            if !reader.read_line(&mut line)?.is_empty() {
+                return Err(Error::HttpBadResponse);
+            }
+        }
+    }
+
+    /// Make one request for `url`, carrying on from what we already have
+    fn fetch(&mut self, url: &str) -> Result<Fetch> {
+        let url = Url::parse(url)?;
+        let ip = match url.host.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => dns::resolve_host(url.host)?.into(),
+        };
+        let mut stream = TcpStream::connect(ip, url.port)?;
+
+        let range = match self.written {
+            0 => String::new(),
+            written => format!("Range: bytes={}-\r\n", written),
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             User-Agent: Bootloader-x86\r\n\
+             Accept: */*\r\n\
+             Connection: close\r\n\
+             {}\r\n",
+            url.path, url.authority, range
+        );
+        stream.write_all(request.as_bytes())?;
+
+        let mut reader = Reader::new(stream);
+        let response = reader.response()?;
+
+        let total = match response.status {
+            status::OK => {
+                // The server ignored our range so start again
+                self.written = 0;
+                response.content_length
+            }
+            status::PARTIAL_CONTENT => {
+                if response.range_start != Some(self.written) {
+                    return Err(Error::HttpBadResponse);
+                }
+                response
+                    .range_total
+                    .or(response.content_length.map(|len| self.written + len))
+            }
+            status::MOVED_PERMANENTLY
+            | status::FOUND
+            | status::SEE_OTHER
+            | status::TEMPORARY_REDIRECT
+            | status::PERMANENT_REDIRECT => {
+                let location =
+                    response.location.ok_or(Error::HttpBadResponse)?;
+                return Ok(Fetch::Redirect(url.join(&location)));
+            }
+            status::NOT_FOUND => return Err(Error::HttpNotFound),
+            status => {
+                println!("HTTP: Server returned status {}", status);
+                return Err(Error::HttpStatus);
+            }
+        };
+
+        if let Some(total) = total {
+            if total > self.region.len() {
+                return Err(Error::HttpFileTooLarge);
+            }
+            self.progress.set_total(total);
+        }
+
+        // Chunked takes priority over any length we were given
+        if response.chunked {
+            self.receive_chunked(&mut reader)?;
+        } else {
+            self.receive(&mut reader, response.content_length)?;
+        }
+
+        Ok(Fetch::Done)
+    }
+}
+
+/// Download `url` into physical memory starting at `load_addr`, which must be
+/// above 1 MiB. Fails if the file is bigger than `max_len` or the free RAM
+/// there. Returns the size of the file
+pub fn download(url: &str, load_addr: usize, max_len: usize) -> Result<usize> {
+    let region = super::load_region(load_addr, max_len)?;
+    let mut download = Download {
+        region,
+        written: 0,
+        progress: Progress::new(),
+    };
+
+    let mut url = String::from(url);
+    let mut redirects = 0;
+    let mut resumes = 0;
+    loop {
+        match download.fetch(&url) {
+            Ok(Fetch::Done) => {
+                download.progress.finish();
+                return Ok(download.written);
+            }
+            Ok(Fetch::Redirect(location)) => {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Err(Error::HttpTooManyRedirects);
+                }
+                url = location;
+            }
+            // The connection dropped, ask for the rest
+            Err(
+                Error::HttpTruncated
+                | Error::TcpConnectionReset
+                | Error::TcpTimeout
+                | Error::ReceiveTimeout,
+            ) if resumes < MAX_RESUMES => {
+                resumes += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::RefCell,
+        net::{Ipv4Addr, SocketAddrV4},
+    };
+
+    use alloc::{rc::Rc, vec, vec::Vec};
+
+    use super::*;
+    use crate::net::{self, testing};
+
+    const OUR_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const SERVER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+    /// Fetch from a server that answers with `response`, returning what was
+    /// written to the region
+    fn fetch(response: &[u8]) -> Result<Vec<u8>> {
+        let server = Rc::new(RefCell::new(testing::TcpServer::new(
+            SocketAddrV4::new(SERVER_IP, DEFAULT_PORT),
+            response,
+        )));
+        let _stack =
+            testing::take_stack(move |frame| server.borrow_mut().answer(frame));
+        net::configure_ipv4(OUR_IP, NETMASK, Ipv4Addr::UNSPECIFIED);
+
+        let mut region = vec![0u8; 64];
+        let mut download = Download {
+            region: &mut region,
+            written: 0,
+            progress: Progress::new(),
+        };
+        download.fetch("http://192.168.0.1/kernel")?;
+        let written = download.written;
+
+        Ok(region[..written].to_vec())
+    }
+
+    #[test]
+    fn url_is_split_into_its_parts() {
+        let url = Url::parse("HTTP://boot.example:8080/images/kernel").unwrap();
+        assert_eq!(url.authority, "boot.example:8080");
+        assert_eq!(url.host, "boot.example");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/images/kernel");
+
+        let url = Url::parse("http://[2001:db8::1]").unwrap();
+        assert_eq!(url.host, "2001:db8::1");
+        assert_eq!(url.port, DEFAULT_PORT);
+        assert_eq!(url.path, "/");
+
+        assert!(Url::parse("https://boot.example/kernel").is_err());
+        assert!(Url::parse("http://:80/kernel").is_err());
+        assert!(Url::parse("http://[2001:db8::1]80/kernel").is_err());
+        assert!(Url::parse("http://boot.example:http/kernel").is_err());
+    }
+
+    #[test]
+    fn location_is_relative_to_the_url() {
+        let url = Url::parse("http://boot.example:8080/images/kernel").unwrap();
+        assert_eq!(
+            url.join("http://mirror.example/kernel"),
+            "http://mirror.example/kernel"
+        );
+        assert_eq!(
+            url.join("/other/kernel"),
+            "http://boot.example:8080/other/kernel"
+        );
+        assert_eq!(
+            url.join("kernel-2"),
+            "http://boot.example:8080/images/kernel-2"
+        );
+    }
+
+    #[test]
+    fn chunked_body_is_joined() {
+        let body = fetch(
+            b"HTTP/1.1 200 OK\r\n\
+              Transfer-Encoding: chunked\r\n\
+              \r\n\
+              5;name=value\r\nhello\r\n\
+              7\r\n, world\r\n\
+              0\r\n\
+              Trailer: ignored\r\n\
+              \r\n",
+        );
+        assert_eq!(body.unwrap(), b"hello, world");
+    }
+
+    #[test]
+    fn chunk_size_that_overflows_is_refused() {
+        let body = fetch(
+            b"HTTP/1.1 200 OK\r\n\
+              Transfer-Encoding: chunked\r\n\
+              \r\n\
+              5\r\nhello\r\n\
+              FFFFFFFFFFFFFFFF\r\n",
+        );
+        assert!(matches!(body, Err(Error::HttpBadResponse)));
+    }
+}
@@ -0,0 +1,255 @@
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    error::{Error, Result},
+    pit,
+};
+
+use super::{
+    checksum,
+    ipv4::{self, IpProtocol, Ipv4},
+    nic::MacAddress,
+    Endianness, Serialise,
+};
+
+/// Identifier we put in our echo requests so we only count our own replies
+const PING_IDENTIFIER: u16 = 0xB007;
+/// How long to wait for each echo reply
+const PING_TIMEOUT_MS: u64 = 1_000;
+/// Time between the start of each echo request
+const PING_INTERVAL_MS: u64 = 1_000;
+/// Bytes of data in each echo request
+const PING_PAYLOAD_LEN: usize = 32;
+
+/// Sequence number of the last echo reply to our identifier, [`u32::MAX`] if
+/// there has not been one
+static LAST_REPLY_SEQUENCE: AtomicU32 = AtomicU32::new(u32::MAX);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IcmpType {
+    /// 0
+    EchoReply,
+
+    /// 3
+    DestinationUnreachable,
+
+    /// 8
+    EchoRequest,
+
+    /// 11
+    TimeExceeded,
+
+    /// Unsupported type
+    Unknown(u8),
+}
+
+impl From<u8> for IcmpType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::EchoReply,
+            3 => Self::DestinationUnreachable,
+            8 => Self::EchoRequest,
+            11 => Self::TimeExceeded,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<IcmpType> for u8 {
+    fn from(value: IcmpType) -> Self {
+        match value {
+            IcmpType::EchoReply => 0,
+            IcmpType::DestinationUnreachable => 3,
+            IcmpType::EchoRequest => 8,
+            IcmpType::TimeExceeded => 11,
+            IcmpType::Unknown(value) => value,
+        }
+    }
+}
+
+/// ICMP message, the checksum is validated on receive and calculated on send
+#[derive(Debug)]
+struct Icmp<'a> {
+    ty: IcmpType,
+    code: u8,
+    /// Meaning depends on the type, for echo it is the identifier and
+    /// sequence number
+    rest_of_header: [u8; 4],
+    payload: &'a [u8],
+}
+
+impl<'a> Icmp<'a> {
+    const HEADER_LEN: usize = 8;
+
+    fn echo(
+        ty: IcmpType,
+        identifier: u16,
+        sequence: u16,
+        payload: &'a [u8],
+    ) -> Self {
+        let [id_high, id_low] = identifier.to_be_bytes();
+        let [seq_high, seq_low] = sequence.to_be_bytes();
+
+        Self {
+            ty,
+            code: 0,
+            rest_of_header: [id_high, id_low, seq_high, seq_low],
+            payload,
+        }
+    }
+
+    fn identifier(&self) -> u16 {
+        u16::from_be_bytes([self.rest_of_header[0], self.rest_of_header[1]])
+    }
+
+    fn sequence(&self) -> u16 {
+        u16::from_be_bytes([self.rest_of_header[2], self.rest_of_header[3]])
+    }
+}
+
+impl<'a> Serialise<'a> for Icmp<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::HEADER_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+        if checksum(buffer) != 0 {
+            return Err(Error::InvalidChecksum);
+        }
+
+        let mut ptr = 0;
+        let ty = consume!(ptr, buffer, u8).into();
+        let code = consume!(ptr, buffer, u8);
+        let _checksum = consume!(ptr, buffer, Endianness::Big, u16);
+        let rest_of_header = consume!(ptr, buffer, [u8; 4]);
+        let payload = consume!(ptr, buffer, [u8], buffer.len() - ptr);
+
+        Ok(Self {
+            ty,
+            code,
+            rest_of_header,
+            payload,
+        })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = 0;
+        emit!(ptr, buffer, u8, self.ty.into());
+        emit!(ptr, buffer, u8, self.code);
+
+        // Zero while we calculate the checksum over the message
+        let checksum_ptr = ptr;
+        emit!(ptr, buffer, Endianness::Big, u16, 0);
+
+        emit!(ptr, buffer, [u8; 4], self.rest_of_header);
+        emit!(ptr, buffer, [u8], self.payload);
+
+        let message_checksum = checksum(&buffer[..ptr]);
+        buffer[checksum_ptr..checksum_ptr + 2]
+            .copy_from_slice(&message_checksum.to_be_bytes());
+
+        ptr
+    }
+}
+
+/// Called for every ICMP message we receive. Echo requests are answered
+/// straight back to the MAC they came from so we never wait on ARP in here
+pub(super) fn handle(ipv4: &Ipv4, src_mac: MacAddress) {
+    let Ok(icmp) = Icmp::deserialise(ipv4.payload()) else {
+        return;
+    };
+
+    match icmp.ty {
+        // Ignore requests too big for the reply to fit in one frame
+        IcmpType::EchoRequest
+            if Icmp::HEADER_LEN + icmp.payload.len()
+                <= Ipv4::MAX_PAYLOAD_LEN =>
+        {
+            let reply = Icmp::echo(
+                IcmpType::EchoReply,
+                icmp.identifier(),
+                icmp.sequence(),
+                icmp.payload,
+            );
+
+            let mut buffer = [0u8; Ipv4::MAX_PAYLOAD_LEN];
+            let len = reply.serialise(&mut buffer);
+            _ = ipv4::send_to_mac(
+                src_mac,
+                ipv4.src_ip(),
+                IpProtocol::Icmp,
+                &buffer[..len],
+            );
+        }
+        IcmpType::EchoReply if icmp.identifier() == PING_IDENTIFIER => {
+            LAST_REPLY_SEQUENCE.store(icmp.sequence() as u32, Ordering::SeqCst);
+        }
+        _ => {}
+    }
+}
+
+/// Send `count` echo requests to `dst_ip` one second apart, printing the
+/// round trip time of each reply to the console
+pub fn ping(dst_ip: Ipv4Addr, count: u16) -> Result<()> {
+    let payload: [u8; PING_PAYLOAD_LEN] =
+        core::array::from_fn(|i| b'a' + (i % 26) as u8);
+    let mut received = 0;
+
+    println!(
+        "Pinging {} with {} bytes of data:",
+        dst_ip, PING_PAYLOAD_LEN
+    );
+
+    for sequence in 0..count {
+        let request = Icmp::echo(
+            IcmpType::EchoRequest,
+            PING_IDENTIFIER,
+            sequence,
+            &payload,
+        );
+        let mut buffer = [0u8; Icmp::HEADER_LEN + PING_PAYLOAD_LEN];
+        let len = request.serialise(&mut buffer);
+
+        // Resolve first so ARP is not counted in the round trip
+        let dst_mac = ipv4::route(dst_ip)?;
+
+        LAST_REPLY_SEQUENCE.store(u32::MAX, Ordering::SeqCst);
+        let sent = pit::ticks();
+        ipv4::send_to_mac(dst_mac, dst_ip, IpProtocol::Icmp, &buffer[..len])?;
+
+        // Replies are recorded by [`super::poll`]
+        let deadline = sent + PING_TIMEOUT_MS;
+        let mut replied = false;
+        while pit::ticks() < deadline {
+            if LAST_REPLY_SEQUENCE.load(Ordering::SeqCst) == sequence as u32 {
+                replied = true;
+                break;
+            }
+            super::wait();
+        }
+
+        if replied {
+            received += 1;
+            println!(
+                "Reply from {}: seq={} time={}ms",
+                dst_ip,
+                sequence,
+                pit::ticks() - sent
+            );
+        } else {
+            println!("Request timed out: seq={}", sequence);
+        }
+
+        // Keep requests evenly spaced, answering the network meanwhile
+        let next = sent + PING_INTERVAL_MS;
+        while sequence + 1 < count && pit::ticks() < next {
+            super::wait();
+        }
+    }
+
+    println!("{}/{} replies received from {}", received, count, dst_ip);
+
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! ICMPv6 [https://www.rfc-editor.org/rfc/rfc4443], neighbor discovery
+//! messages are handed to [`ndp`]
+
+use core::net::Ipv6Addr;
+
+use crate::error::{Error, Result};
+
+use super::{
+    checksum_add, checksum_finish,
+    ipv4::IpProtocol,
+    ipv6::{self, Ipv6},
+    ndp,
+    nic::MacAddress,
+    Endianness, Serialise,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Icmpv6Type {
+    /// 1
+    DestinationUnreachable,
+
+    /// 2
+    PacketTooBig,
+
+    /// 3
+    TimeExceeded,
+
+    /// 128
+    EchoRequest,
+
+    /// 129
+    EchoReply,
+
+    /// 133
+    RouterSolicitation,
+
+    /// 134
+    RouterAdvertisement,
+
+    /// 135
+    NeighborSolicitation,
+
+    /// 136
+    NeighborAdvertisement,
+
+    /// Unsupported type
+    Unknown(u8),
+}
+
+impl From<u8> for Icmpv6Type {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::DestinationUnreachable,
+            2 => Self::PacketTooBig,
+            3 => Self::TimeExceeded,
+            128 => Self::EchoRequest,
+            129 => Self::EchoReply,
+            133 => Self::RouterSolicitation,
+            134 => Self::RouterAdvertisement,
+            135 => Self::NeighborSolicitation,
+            136 => Self::NeighborAdvertisement,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<Icmpv6Type> for u8 {
+    fn from(value: Icmpv6Type) -> Self {
+        match value {
+            Icmpv6Type::DestinationUnreachable => 1,
+            Icmpv6Type::PacketTooBig => 2,
+            Icmpv6Type::TimeExceeded => 3,
+            Icmpv6Type::EchoRequest => 128,
+            Icmpv6Type::EchoReply => 129,
+            Icmpv6Type::RouterSolicitation => 133,
+            Icmpv6Type::RouterAdvertisement => 134,
+            Icmpv6Type::NeighborSolicitation => 135,
+            Icmpv6Type::NeighborAdvertisement => 136,
+            Icmpv6Type::Unknown(value) => value,
+        }
+    }
+}
+
+/// ICMPv6 message, the checksum covers the IPv6 pseudo header so is validated
+/// in [`handle`] and calculated in [`send`]
+#[derive(Debug)]
+pub(super) struct Icmpv6<'a> {
+    pub(super) ty: Icmpv6Type,
+    pub(super) code: u8,
+    /// Everything after the checksum, its layout depends on the type
+    pub(super) body: &'a [u8],
+}
+
+impl Icmpv6<'_> {
+    const HEADER_LEN: usize = 4;
+    const CHECKSUM_OFFSET: usize = 2;
+}
+
+impl<'a> Serialise<'a> for Icmpv6<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::HEADER_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        let ty = consume!(ptr, buffer, u8).into();
+        let code = consume!(ptr, buffer, u8);
+        let _checksum = consume!(ptr, buffer, Endianness::Big, u16);
+        let body = consume!(ptr, buffer, [u8], buffer.len() - ptr);
+
+        Ok(Self { ty, code, body })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = 0;
+        emit!(ptr, buffer, u8, self.ty.into());
+        emit!(ptr, buffer, u8, self.code);
+        emit!(ptr, buffer, Endianness::Big, u16, 0);
+        emit!(ptr, buffer, [u8], self.body);
+
+        ptr
+    }
+}
+
+/// Build and send a single message with the addressing chosen by the caller
+pub(super) fn send(
+    dst_mac: MacAddress,
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    hop_limit: u8,
+    icmpv6: &Icmpv6,
+) -> Result<()> {
+    if Icmpv6::HEADER_LEN + icmpv6.body.len() > Ipv6::MAX_PAYLOAD_LEN {
+        return Err(Error::FrameTooLarge);
+    }
+
+    let mut buffer = [0u8; Ipv6::MAX_PAYLOAD_LEN];
+    let len = icmpv6.serialise(&mut buffer);
+
+    let pseudo_header =
+        ipv6::pseudo_header_sum(src_ip, dst_ip, IpProtocol::Icmpv6, len);
+    let message_checksum =
+        checksum_finish(checksum_add(pseudo_header, &buffer[..len]));
+    buffer[Icmpv6::CHECKSUM_OFFSET..Icmpv6::CHECKSUM_OFFSET + 2]
+        .copy_from_slice(&message_checksum.to_be_bytes());
+
+    ipv6::send_from(
+        dst_mac,
+        src_ip,
+        dst_ip,
+        IpProtocol::Icmpv6,
+        hop_limit,
+        &buffer[..len],
+    )
+}
+
+/// Called for every ICMPv6 message we receive. Echo requests are answered
+/// straight back to the MAC they came from so we never wait on NDP in here
+pub(super) fn handle(ipv6: &Ipv6, src_mac: MacAddress) {
+    let buffer = ipv6.payload();
+    let pseudo_header = ipv6::pseudo_header_sum(
+        ipv6.src_ip(),
+        ipv6.dst_ip(),
+        IpProtocol::Icmpv6,
+        buffer.len(),
+    );
+    if checksum_finish(checksum_add(pseudo_header, buffer)) != 0 {
+        return;
+    }
+
+    let Ok(icmpv6) = Icmpv6::deserialise(buffer) else {
+        return;
+    };
+
+    match icmpv6.ty {
+        Icmpv6Type::EchoRequest => {
+            // Answer from the address that was pinged, unless it was a group
+            let src_ip = if ipv6.dst_ip().is_multicast() {
+                ipv6::source_address(ipv6.src_ip())
+            } else {
+                ipv6.dst_ip()
+            };
+            let reply = Icmpv6 {
+                ty: Icmpv6Type::EchoReply,
+                code: 0,
+                body: icmpv6.body,
+            };
+
+            _ = send(
+                src_mac,
+                src_ip,
+                ipv6.src_ip(),
+                ipv6::DEFAULT_HOP_LIMIT,
+                &reply,
+            );
+        }
+        Icmpv6Type::RouterAdvertisement
+        | Icmpv6Type::NeighborSolicitation
+        | Icmpv6Type::NeighborAdvertisement => {
+            ndp::handle(ipv6, &icmpv6, src_mac)
+        }
+        _ => {}
+    }
+}
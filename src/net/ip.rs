@@ -0,0 +1,81 @@
+//! What UDP and TCP need from the IP layer, for either IP version
+
+use core::net::IpAddr;
+
+use crate::error::Result;
+
+use super::{
+    ipv4::{self, IpProtocol, Ipv4},
+    ipv6::{self, Ipv6},
+    nic::MacAddress,
+};
+
+/// The address we send from to reach `dst_ip`
+pub(super) fn source_address(dst_ip: IpAddr) -> IpAddr {
+    match dst_ip {
+        IpAddr::V4(_) => super::ipv4_address().into(),
+        IpAddr::V6(dst_ip) => ipv6::source_address(dst_ip).into(),
+    }
+}
+
+/// Largest upper layer message that fits in one unfragmented datagram
+pub(super) fn max_payload_len(dst_ip: IpAddr) -> usize {
+    match dst_ip {
+        IpAddr::V4(_) => Ipv4::MAX_PAYLOAD_LEN,
+        IpAddr::V6(_) => Ipv6::MAX_PAYLOAD_LEN,
+    }
+}
+
+/// Running checksum of the pseudo header for the version in use. The
+/// addresses always come from the same datagram so are the same version
+pub(super) fn pseudo_header_sum(
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    protocol: IpProtocol,
+    len: usize,
+) -> u32 {
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            ipv4::pseudo_header_sum(src_ip, dst_ip, protocol, len)
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            ipv6::pseudo_header_sum(src_ip, dst_ip, protocol, len)
+        }
+        _ => unreachable!("Pseudo header with mixed IP versions"),
+    }
+}
+
+/// See [`ipv4::route`] and [`ipv6::route`]
+pub(super) fn route(dst_ip: IpAddr) -> Result<MacAddress> {
+    match dst_ip {
+        IpAddr::V4(dst_ip) => ipv4::route(dst_ip),
+        IpAddr::V6(dst_ip) => ipv6::route(dst_ip),
+    }
+}
+
+pub(super) fn send(
+    dst_ip: IpAddr,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<()> {
+    match dst_ip {
+        IpAddr::V4(dst_ip) => ipv4::send(dst_ip, protocol, payload),
+        IpAddr::V6(dst_ip) => ipv6::send(dst_ip, protocol, payload),
+    }
+}
+
+pub(super) fn send_to_mac(
+    dst_mac: MacAddress,
+    dst_ip: IpAddr,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<()> {
+    match dst_ip {
+        IpAddr::V4(dst_ip) => {
+            ipv4::send_to_mac(dst_mac, dst_ip, protocol, payload)
+        }
+        IpAddr::V6(dst_ip) => {
+            ipv6::send_to_mac(dst_mac, dst_ip, protocol, payload)
+        }
+    }
+}
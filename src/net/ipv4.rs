@@ -0,0 +1,307 @@
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use crate::error::{Error, Result};
+
+use super::{
+    arp, checksum, checksum_add, icmp,
+    nic::MacAddress,
+    packet::{EtherType, Packet, Protocol},
+    tcp, udp, Endianness, Serialise,
+};
+
+/// TTL we give every datagram we send
+const DEFAULT_TTL: u8 = 64;
+
+/// Identification of the next datagram we send
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum IpProtocol {
+    /// 1
+    Icmp,
+
+    /// 6
+    Tcp,
+
+    /// 17
+    Udp,
+
+    /// 58, only carried by IPv6
+    Icmpv6,
+
+    /// Unsupported protocol
+    Unknown(u8),
+}
+
+impl From<u8> for IpProtocol {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Icmp,
+            6 => Self::Tcp,
+            17 => Self::Udp,
+            58 => Self::Icmpv6,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<IpProtocol> for u8 {
+    fn from(value: IpProtocol) -> Self {
+        match value {
+            IpProtocol::Icmp => 1,
+            IpProtocol::Tcp => 6,
+            IpProtocol::Udp => 17,
+            IpProtocol::Icmpv6 => 58,
+            IpProtocol::Unknown(value) => value,
+        }
+    }
+}
+
+/// IPv4 datagram, the total length and header checksum are not stored as they
+/// are validated on receive and calculated on send
+#[derive(Debug)]
+pub(super) struct Ipv4<'a> {
+    type_of_service: u8,
+    identification: u16,
+    flags_fragment: u16,
+    ttl: u8,
+    protocol: IpProtocol,
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    /// Raw options, always a multiple of 4 bytes
+    options: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> Ipv4<'a> {
+    /// Header length without options
+    const MIN_LEN: usize = 20;
+
+    /// Largest payload that fits in an ethernet frame without options
+    pub(super) const MAX_PAYLOAD_LEN: usize = 1500 - Self::MIN_LEN;
+
+    const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+    const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+    const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+
+    /// Datagram sent from our address
+    fn new(protocol: IpProtocol, dst_ip: Ipv4Addr, payload: &'a [u8]) -> Self {
+        Self {
+            type_of_service: 0,
+            identification: IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+            flags_fragment: Self::FLAG_DONT_FRAGMENT,
+            ttl: DEFAULT_TTL,
+            protocol,
+            src_ip: super::ipv4_address(),
+            dst_ip,
+            options: &[],
+            payload,
+        }
+    }
+
+    pub(super) fn src_ip(&self) -> Ipv4Addr {
+        self.src_ip
+    }
+
+    pub(super) fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    fn header_len(&self) -> usize {
+        Self::MIN_LEN + self.options.len()
+    }
+
+    /// Total length, anything after this in the frame is not ours
+    pub(super) fn len(&self) -> usize {
+        self.header_len() + self.payload.len()
+    }
+
+    /// We do not reassemble so anything that is part of a bigger datagram is
+    /// of no use to us
+    fn is_fragment(&self) -> bool {
+        (self.flags_fragment & Self::FLAG_MORE_FRAGMENTS) != 0
+            || (self.flags_fragment & Self::FRAGMENT_OFFSET_MASK) != 0
+    }
+}
+
+impl<'a> Serialise<'a> for Ipv4<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::MIN_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        let version_ihl = consume!(ptr, buffer, u8);
+        let header_len = (version_ihl & 0x0F) as usize * 4;
+        if version_ihl >> 4 != 4
+            || header_len < Self::MIN_LEN
+            || header_len > buffer.len()
+        {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        if checksum(&buffer[..header_len]) != 0 {
+            return Err(Error::InvalidChecksum);
+        }
+
+        let type_of_service = consume!(ptr, buffer, u8);
+
+        // Anything past the total length is ethernet padding
+        let total_len = consume!(ptr, buffer, Endianness::Big, u16) as usize;
+        if total_len < header_len || total_len > buffer.len() {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let identification = consume!(ptr, buffer, Endianness::Big, u16);
+        let flags_fragment = consume!(ptr, buffer, Endianness::Big, u16);
+        let ttl = consume!(ptr, buffer, u8);
+        let protocol = consume!(ptr, buffer, u8).into();
+        let _checksum = consume!(ptr, buffer, Endianness::Big, u16);
+        let src_ip = consume!(ptr, buffer, [u8; 4]).into();
+        let dst_ip = consume!(ptr, buffer, [u8; 4]).into();
+        let options = consume!(ptr, buffer, [u8], header_len - Self::MIN_LEN);
+        let payload = consume!(ptr, buffer, [u8], total_len - header_len);
+
+        Ok(Self {
+            type_of_service,
+            identification,
+            flags_fragment,
+            ttl,
+            protocol,
+            src_ip,
+            dst_ip,
+            options,
+            payload,
+        })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let header_len = self.header_len();
+        let total_len = header_len + self.payload.len();
+
+        let mut ptr = 0;
+        emit!(ptr, buffer, u8, 4 << 4 | (header_len / 4) as u8);
+        emit!(ptr, buffer, u8, self.type_of_service);
+        emit!(ptr, buffer, Endianness::Big, u16, total_len as u16);
+        emit!(ptr, buffer, Endianness::Big, u16, self.identification);
+        emit!(ptr, buffer, Endianness::Big, u16, self.flags_fragment);
+        emit!(ptr, buffer, u8, self.ttl);
+        emit!(ptr, buffer, u8, self.protocol.into());
+
+        // Zero while we calculate the checksum over the header
+        let checksum_ptr = ptr;
+        emit!(ptr, buffer, Endianness::Big, u16, 0);
+
+        emit!(ptr, buffer, [u8; 4], self.src_ip.octets());
+        emit!(ptr, buffer, [u8; 4], self.dst_ip.octets());
+        emit!(ptr, buffer, [u8], self.options);
+
+        let header_checksum = checksum(&buffer[..header_len]);
+        buffer[checksum_ptr..checksum_ptr + 2]
+            .copy_from_slice(&header_checksum.to_be_bytes());
+
+        emit!(ptr, buffer, [u8], self.payload);
+
+        ptr
+    }
+}
+
+/// Running checksum of the pseudo header that UDP and TCP include in their
+/// own checksums
+pub(super) fn pseudo_header_sum(
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    protocol: IpProtocol,
+    len: usize,
+) -> u32 {
+    let sum = checksum_add(0, &src_ip.octets());
+    let sum = checksum_add(sum, &dst_ip.octets());
+    let sum = checksum_add(sum, &[0, protocol.into()]);
+    checksum_add(sum, &(len as u16).to_be_bytes())
+}
+
+/// Is `ip` on the same network as us, or should it go via the gateway
+fn is_on_link(ip: Ipv4Addr) -> bool {
+    let netmask = u32::from(super::ipv4_netmask());
+
+    u32::from(ip) & netmask == u32::from(super::ipv4_address()) & netmask
+}
+
+/// Is `ip` a broadcast address for everyone or for our network
+fn is_broadcast(ip: Ipv4Addr) -> bool {
+    let netmask = u32::from(super::ipv4_netmask());
+
+    ip.is_broadcast()
+        || (netmask != 0
+            && is_on_link(ip)
+            && u32::from(ip) | netmask == u32::MAX)
+}
+
+/// Work out the MAC address to put a datagram for `dst_ip` on the wire to,
+/// which is either the host itself or our default gateway
+pub(super) fn route(dst_ip: Ipv4Addr) -> Result<MacAddress> {
+    if is_broadcast(dst_ip) {
+        return Ok(MacAddress::BROADCAST);
+    }
+
+    let next_hop = if is_on_link(dst_ip) {
+        dst_ip
+    } else {
+        super::ipv4_gateway().ok_or(Error::NoRoute)?
+    };
+
+    arp::resolve(next_hop)
+}
+
+/// Send `payload` to `dst_ip` as a single datagram
+pub(super) fn send(
+    dst_ip: Ipv4Addr,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<()> {
+    send_to_mac(route(dst_ip)?, dst_ip, protocol, payload)
+}
+
+/// Send `payload` to `dst_ip` with the next hop already known, used when
+/// replying to a packet where we must not wait on [`arp::resolve`]
+pub(super) fn send_to_mac(
+    dst_mac: MacAddress,
+    dst_ip: Ipv4Addr,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<()> {
+    let ipv4 = Ipv4::new(protocol, dst_ip, payload);
+
+    Packet::new(dst_mac, EtherType::IPv4, Protocol::Ipv4(ipv4)).send()
+}
+
+/// Called for every IPv4 datagram we receive, drops anything not for us and
+/// hands the rest to the upper layer protocol
+pub(super) fn handle(ipv4: &Ipv4, src_mac: MacAddress) {
+    let our_ip = super::ipv4_address();
+
+    // Until we are configured (DHCP) we have to accept everything
+    if !our_ip.is_unspecified()
+        && ipv4.dst_ip != our_ip
+        && !is_broadcast(ipv4.dst_ip)
+    {
+        return;
+    }
+
+    if ipv4.is_fragment() || ipv4.ttl == 0 {
+        return;
+    }
+
+    let src_ip = ipv4.src_ip.into();
+    let dst_ip = ipv4.dst_ip.into();
+    match ipv4.protocol {
+        IpProtocol::Icmp => icmp::handle(ipv4, src_mac),
+        IpProtocol::Tcp => tcp::handle(src_ip, dst_ip, ipv4.payload, src_mac),
+        IpProtocol::Udp => udp::handle(src_ip, dst_ip, ipv4.payload),
+        // ICMPv6 is only valid over IPv6
+        IpProtocol::Icmpv6 | IpProtocol::Unknown(_) => {}
+    }
+}
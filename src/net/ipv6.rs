@@ -0,0 +1,267 @@
+//! IPv6 [https://www.rfc-editor.org/rfc/rfc8200] without extension headers,
+//! anything that uses them is dropped
+
+use core::net::Ipv6Addr;
+
+use crate::error::{Error, Result};
+
+use super::{
+    checksum_add, icmpv6,
+    ipv4::IpProtocol,
+    ndp,
+    nic::MacAddress,
+    packet::{EtherType, Packet, Protocol},
+    tcp, udp, Endianness, Serialise,
+};
+
+/// Hop limit we give every packet we send, other than NDP which uses 255
+pub(super) const DEFAULT_HOP_LIMIT: u8 = 64;
+
+/// IPv6 packet, the payload length is not stored as it is validated on receive
+/// and calculated on send
+#[derive(Debug)]
+pub(super) struct Ipv6<'a> {
+    traffic_class: u8,
+    flow_label: u32,
+    next_header: IpProtocol,
+    hop_limit: u8,
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    payload: &'a [u8],
+}
+
+impl<'a> Ipv6<'a> {
+    const HEADER_LEN: usize = 40;
+
+    /// Largest payload that fits in an ethernet frame
+    pub(super) const MAX_PAYLOAD_LEN: usize = 1500 - Self::HEADER_LEN;
+
+    const FLOW_LABEL_MASK: u32 = 0x000F_FFFF;
+
+    pub(super) fn src_ip(&self) -> Ipv6Addr {
+        self.src_ip
+    }
+
+    pub(super) fn dst_ip(&self) -> Ipv6Addr {
+        self.dst_ip
+    }
+
+    pub(super) fn hop_limit(&self) -> u8 {
+        self.hop_limit
+    }
+
+    pub(super) fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Header and payload, anything after this in the frame is not ours
+    pub(super) fn len(&self) -> usize {
+        Self::HEADER_LEN + self.payload.len()
+    }
+}
+
+impl<'a> Serialise<'a> for Ipv6<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::HEADER_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        let version_class_flow = consume!(ptr, buffer, Endianness::Big, u32);
+        if version_class_flow >> 28 != 6 {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        // Anything past the payload length is ethernet padding
+        let payload_len = consume!(ptr, buffer, Endianness::Big, u16) as usize;
+        if Self::HEADER_LEN + payload_len > buffer.len() {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let next_header = consume!(ptr, buffer, u8).into();
+        let hop_limit = consume!(ptr, buffer, u8);
+        let src_ip = consume!(ptr, buffer, [u8; 16]).into();
+        let dst_ip = consume!(ptr, buffer, [u8; 16]).into();
+        let payload = consume!(ptr, buffer, [u8], payload_len);
+
+        Ok(Self {
+            traffic_class: (version_class_flow >> 20) as u8,
+            flow_label: version_class_flow & Self::FLOW_LABEL_MASK,
+            next_header,
+            hop_limit,
+            src_ip,
+            dst_ip,
+            payload,
+        })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let version_class_flow = 6 << 28
+            | (self.traffic_class as u32) << 20
+            | (self.flow_label & Self::FLOW_LABEL_MASK);
+
+        let mut ptr = 0;
+        emit!(ptr, buffer, Endianness::Big, u32, version_class_flow);
+        emit!(ptr, buffer, Endianness::Big, u16, self.payload.len() as u16);
+        emit!(ptr, buffer, u8, self.next_header.into());
+        emit!(ptr, buffer, u8, self.hop_limit);
+        emit!(ptr, buffer, [u8; 16], self.src_ip.octets());
+        emit!(ptr, buffer, [u8; 16], self.dst_ip.octets());
+        emit!(ptr, buffer, [u8], self.payload);
+
+        ptr
+    }
+}
+
+/// Running checksum of the pseudo header that ICMPv6, UDP and TCP include in
+/// their own checksums [RFC 8200 8.1]
+pub(super) fn pseudo_header_sum(
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    next_header: IpProtocol,
+    len: usize,
+) -> u32 {
+    let sum = checksum_add(0, &src_ip.octets());
+    let sum = checksum_add(sum, &dst_ip.octets());
+    let sum = checksum_add(sum, &(len as u32).to_be_bytes());
+    checksum_add(sum, &[0, 0, 0, next_header.into()])
+}
+
+/// IPv6 multicast goes to 33:33 followed by the low 32 bits of the address
+/// [RFC 2464 7]
+pub(super) fn multicast_mac(ip: Ipv6Addr) -> MacAddress {
+    let octets = ip.octets();
+
+    MacAddress::from([
+        0x33, 0x33, octets[12], octets[13], octets[14], octets[15],
+    ])
+}
+
+/// fe80::/10
+pub(super) fn is_link_local(ip: Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xFFC0 == 0xFE80
+}
+
+/// Is `ip` on the same link as us, or should it go via the router
+fn is_on_link(ip: Ipv6Addr) -> bool {
+    if is_link_local(ip) {
+        return true;
+    }
+
+    let address = super::ipv6_address();
+    if address.is_unspecified() {
+        return false;
+    }
+
+    let prefix_len = super::ipv6_prefix_len() as u32;
+    let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+    u128::from(ip) & mask == u128::from(address) & mask
+}
+
+/// Is `ip` one of our addresses, or a multicast group we listen to
+fn is_ours(ip: Ipv6Addr) -> bool {
+    ip.is_multicast()
+        || ip == super::ipv6_link_local()
+        || ip == super::ipv6_address()
+}
+
+/// The address we send from to reach `dst_ip`, link-local for anything on
+/// the link unless we have a global address
+pub(super) fn source_address(dst_ip: Ipv6Addr) -> Ipv6Addr {
+    let global = super::ipv6_address();
+
+    // ff02::/16 is link scope multicast
+    let link_scope = is_link_local(dst_ip) || dst_ip.segments()[0] == 0xFF02;
+    if link_scope || global.is_unspecified() {
+        super::ipv6_link_local()
+    } else {
+        global
+    }
+}
+
+/// Work out the MAC address to put a packet for `dst_ip` on the wire to,
+/// which is either the host itself or our default router
+pub(super) fn route(dst_ip: Ipv6Addr) -> Result<MacAddress> {
+    if dst_ip.is_multicast() {
+        return Ok(multicast_mac(dst_ip));
+    }
+
+    let next_hop = if is_on_link(dst_ip) {
+        dst_ip
+    } else {
+        super::ipv6_router().ok_or(Error::NoRoute)?
+    };
+
+    ndp::resolve(next_hop)
+}
+
+/// Send `payload` to `dst_ip` as a single packet
+pub(super) fn send(
+    dst_ip: Ipv6Addr,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<()> {
+    send_to_mac(route(dst_ip)?, dst_ip, protocol, payload)
+}
+
+/// Send `payload` to `dst_ip` with the next hop already known, used when
+/// replying to a packet where we must not wait on [`ndp::resolve`]
+pub(super) fn send_to_mac(
+    dst_mac: MacAddress,
+    dst_ip: Ipv6Addr,
+    protocol: IpProtocol,
+    payload: &[u8],
+) -> Result<()> {
+    send_from(
+        dst_mac,
+        source_address(dst_ip),
+        dst_ip,
+        protocol,
+        DEFAULT_HOP_LIMIT,
+        payload,
+    )
+}
+
+/// Send with everything in the header chosen by the caller, which NDP needs
+pub(super) fn send_from(
+    dst_mac: MacAddress,
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    protocol: IpProtocol,
+    hop_limit: u8,
+    payload: &[u8],
+) -> Result<()> {
+    if payload.len() > Ipv6::MAX_PAYLOAD_LEN {
+        return Err(Error::FrameTooLarge);
+    }
+
+    let ipv6 = Ipv6 {
+        traffic_class: 0,
+        flow_label: 0,
+        next_header: protocol,
+        hop_limit,
+        src_ip,
+        dst_ip,
+        payload,
+    };
+
+    Packet::new(dst_mac, EtherType::IPv6, Protocol::Ipv6(ipv6)).send()
+}
+
+/// Called for every IPv6 packet we receive, drops anything not for us and
+/// hands the rest to the upper layer protocol
+pub(super) fn handle(ipv6: &Ipv6, src_mac: MacAddress) {
+    if !is_ours(ipv6.dst_ip) {
+        return;
+    }
+
+    let src_ip = ipv6.src_ip.into();
+    let dst_ip = ipv6.dst_ip.into();
+    match ipv6.next_header {
+        IpProtocol::Icmpv6 => icmpv6::handle(ipv6, src_mac),
+        IpProtocol::Tcp => tcp::handle(src_ip, dst_ip, ipv6.payload, src_mac),
+        IpProtocol::Udp => udp::handle(src_ip, dst_ip, ipv6.payload),
+        // Extension headers and anything else
+        _ => {}
+    }
+}
@@ -1,4 +1,4 @@
-use alloc::vec::Vec;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 
 /// Consume a fixed slice from a buffer and increment the buffer
 macro_rules! consume {
@@ -24,6 +24,15 @@ macro_rules! consume {
         $buffer[start]
     }};
 
+    // Parse variable length slice, borrowed from the buffer
+    ($ptr:expr, $buffer:expr, [u8], $len:expr) => {{
+        // Increment the pointer for the length of the slice
+        let start = $ptr;
+        $ptr += $len;
+
+        &$buffer[start..$ptr]
+    }};
+
     // Parse primatives
     ($ptr:expr, $buffer:expr, $endian:expr, $ty:ty) => {{
         // Increment the pointer for size of type
@@ -40,26 +49,440 @@ macro_rules! consume {
     }};
 }
 
+/// Emit a value into a buffer and increment the buffer, the counterpart of
+/// [`consume!`]
+macro_rules! emit {
+    // Emit fixed length array
+    ($ptr:expr, $buffer:expr, [$ty:ty; $len:expr], $value:expr) => {{
+        // Increment the pointer for size of type
+        let start = $ptr;
+        $ptr += core::mem::size_of::<[$ty; $len]>();
+
+        let tmp: [$ty; $len] = $value;
+        $buffer[start..$ptr].copy_from_slice(&tmp);
+    }};
+
+    // Emit variable length slice
+    ($ptr:expr, $buffer:expr, [u8], $value:expr) => {{
+        // Increment the pointer for the length of the slice
+        let start = $ptr;
+        $ptr += $value.len();
+
+        $buffer[start..$ptr].copy_from_slice($value);
+    }};
+
+    // Emit u8
+    ($ptr:expr, $buffer:expr, u8, $value:expr) => {{
+        // Increment the pointer for size of type
+        let start = $ptr;
+        $ptr += core::mem::size_of::<u8>();
+
+        $buffer[start] = $value;
+    }};
+
+    // Emit primatives
+    ($ptr:expr, $buffer:expr, $endian:expr, $ty:ty, $value:expr) => {{
+        // Increment the pointer for size of type
+        let start = $ptr;
+        $ptr += core::mem::size_of::<$ty>();
+
+        let value: $ty = $value;
+        let tmp = match $endian {
+            Endianness::Little => value.to_le_bytes(),
+            Endianness::Big => value.to_be_bytes(),
+        };
+        $buffer[start..$ptr].copy_from_slice(&tmp);
+    }};
+}
+
 mod packet;
 
 mod arp;
+mod dhcp;
+mod dhcpv6;
+mod dns;
+pub mod http;
+mod icmp;
+mod icmpv6;
+mod ip;
+mod ipv4;
+mod ipv6;
+mod ndp;
 mod nic;
-use crate::{error::Error, net::nic::NetworkCard, pci};
+mod pcap;
+mod progress;
+mod tcp;
+#[cfg(test)]
+mod testing;
+pub mod tftp;
+mod udp;
+use core::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu,
+    error::Error,
+    net::{
+        nic::{Checksum, Frame, Link, MacAddress, NetworkCard},
+        packet::{Packet, Protocol},
+    },
+    pci, pit,
+};
+
+pub use dhcp::obtain_lease;
+pub use dhcpv6::obtain_ipv6_lease;
+pub use dns::resolve_host;
+pub use icmp::ping;
+pub use ndp::autoconfigure_ipv6;
+pub use nic::{Interface, Selector};
+pub use pcap::start_capture;
+
+/// Downloads must not clobber anything the BIOS or we are using below here
+const HIGH_MEMORY_START: usize = 0x100_000;
+
+/// Index into [`nic::interfaces`] of the card the stack sends through and
+/// listens on, frames from any other card are dropped
+static INTERFACE: AtomicUsize = AtomicUsize::new(0);
+
+/// Frames taken off the cards waiting for [`poll`], along with the index of
+/// the card each came from
+static mut RX_QUEUE: VecDeque<(usize, Frame)> = VecDeque::new();
+/// Frames [`RX_QUEUE`] holds before newer ones are dropped, as a card would
+const RX_QUEUE_LEN: usize = 32;
+/// Set while [`poll`] is handing frames to the stack, so a nested call does
+/// not handle them out of order
+static POLLING: AtomicBool = AtomicBool::new(false);
+/// Set by a card when its link comes up or goes down, [`poll`] reports it
+static LINK_CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// 802.1Q VLAN we send and receive on, 0 for untagged
+static VLAN_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Our IPv4 address, unspecified until configured
+static IPV4_ADDRESS: AtomicU32 = AtomicU32::new(0);
+/// Mask of the on-link network, anything outside goes via the gateway
+static IPV4_NETMASK: AtomicU32 = AtomicU32::new(0);
+/// Default gateway, unspecified if there is none
+static IPV4_GATEWAY: AtomicU32 = AtomicU32::new(0);
+
+/// Our global IPv6 address, prefix length and default router, all
+/// unspecified until configured. Too big for atomics so only touched with
+/// interrupts disabled
+static mut IPV6_CONFIG: (Ipv6Addr, u8, Ipv6Addr) =
+    (Ipv6Addr::UNSPECIFIED, 0, Ipv6Addr::UNSPECIFIED);
 
 enum Endianness {
     Big,
     Little,
 }
 
-trait Serialise {
-    fn deserialise(buffer: &[u8]) -> Result<Self, Error>
+trait Serialise<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self, Error>
     where
         Self: Sized;
 
-    fn serialise(&self, buffer: &mut [u8]);
+    /// Write the wire format into the buffer, returning the number of bytes
+    /// written
+    fn serialise(&self, buffer: &mut [u8]) -> usize;
 }
 
 pub fn init(devices: &Vec<pci::Device>) {
-    let nic = nic::find(devices).expect("No implented Network Cards found");
-    nic.init();
+    reserve_rx_queue();
+
+    let cards = nic::init(devices);
+    assert!(cards != 0, "No implented Network Cards found");
+}
+
+fn reserve_rx_queue() {
+    cpu::without_interrupts(|| unsafe { RX_QUEUE.reserve_exact(RX_QUEUE_LEN) })
+}
+
+/// Stop the cards raising interrupts, from then on frames only come off them
+/// when the stack is waiting on something, or [`poll`] is called. Blocking
+/// calls never halt while interrupts are disabled, and [`pit::ticks`] keeps
+/// counting from the TSC, so this also works before `sti`
+pub fn set_polled(polled: bool) {
+    for interface in nic::interfaces() {
+        interface.card.set_interrupts(!polled);
+    }
+}
+
+/// Every card we are driving, in the order they were found
+pub fn interfaces() -> &'static [Interface] {
+    nic::interfaces()
+}
+
+/// Move the stack onto `interfaces()[index]`. Anything learnt on the old
+/// one is forgotten, so addresses must be configured again
+pub fn use_interface(index: usize) {
+    assert!(index < interfaces().len(), "No interface {}", index);
+    INTERFACE.store(index, Ordering::Relaxed);
+
+    configure_ipv4(
+        Ipv4Addr::UNSPECIFIED,
+        Ipv4Addr::UNSPECIFIED,
+        Ipv4Addr::UNSPECIFIED,
+    );
+    configure_ipv6(Ipv6Addr::UNSPECIFIED, 0, Ipv6Addr::UNSPECIFIED);
+    arp::flush();
+    ndp::flush();
+}
+
+/// Put the interface on an 802.1Q VLAN, from then on everything we send is
+/// tagged with `id` and frames for any other VLAN, including untagged ones,
+/// are dropped. [`None`] goes back to untagged
+pub fn configure_vlan(id: Option<u16>) {
+    if let Some(id) = id {
+        assert!((1..=4094).contains(&id), "Invalid VLAN ID {}", id);
+    }
+    VLAN_ID.store(id.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Run the stack on an in-memory card instead of a real one, nothing in the
+/// boot uses this, it is for driving the stack from a scripted peer
+#[allow(dead_code)]
+pub fn init_loopback() -> &'static nic::loopback::Loopback {
+    reserve_rx_queue();
+
+    let loopback = Box::leak(Box::new(nic::loopback::Loopback::default()));
+    loopback.init();
+    nic::register(loopback, None, None);
+    use_interface(nic::interfaces().len() - 1);
+
+    loopback
+}
+
+/// Set the IPv4 address we answer ARP for and send from, along with the
+/// network it is on. Pass an unspecified gateway if there is none
+pub fn configure_ipv4(address: Ipv4Addr, netmask: Ipv4Addr, gateway: Ipv4Addr) {
+    IPV4_ADDRESS.store(address.into(), Ordering::Relaxed);
+    IPV4_NETMASK.store(netmask.into(), Ordering::Relaxed);
+    IPV4_GATEWAY.store(gateway.into(), Ordering::Relaxed);
+}
+
+/// Set the global IPv6 address we send from, along with the length of its
+/// on-link prefix. Pass an unspecified router if there is none
+pub fn configure_ipv6(address: Ipv6Addr, prefix_len: u8, router: Ipv6Addr) {
+    cpu::without_interrupts(|| unsafe {
+        IPV6_CONFIG = (address, prefix_len, router);
+    })
+}
+
+fn vlan_id() -> Option<u16> {
+    let id = VLAN_ID.load(Ordering::Relaxed);
+    (id != 0).then_some(id)
+}
+
+fn ipv4_address() -> Ipv4Addr {
+    IPV4_ADDRESS.load(Ordering::Relaxed).into()
+}
+
+fn ipv4_netmask() -> Ipv4Addr {
+    IPV4_NETMASK.load(Ordering::Relaxed).into()
+}
+
+fn ipv4_gateway() -> Option<Ipv4Addr> {
+    let gateway: Ipv4Addr = IPV4_GATEWAY.load(Ordering::Relaxed).into();
+    (!gateway.is_unspecified()).then_some(gateway)
+}
+
+/// Our global IPv6 address, unspecified until configured
+fn ipv6_address() -> Ipv6Addr {
+    cpu::without_interrupts(|| unsafe { IPV6_CONFIG.0 })
+}
+
+fn ipv6_prefix_len() -> u8 {
+    cpu::without_interrupts(|| unsafe { IPV6_CONFIG.1 })
+}
+
+fn ipv6_router() -> Option<Ipv6Addr> {
+    let router = cpu::without_interrupts(|| unsafe { IPV6_CONFIG.2 });
+    (!router.is_unspecified()).then_some(router)
+}
+
+/// fe80::/64 with the modified EUI-64 interface identifier from our MAC
+/// [RFC 4291 2.5.1], which is always ours without any configuration
+fn ipv6_link_local() -> Ipv6Addr {
+    let [a, b, c, d, e, f]: [u8; 6] = mac().into();
+
+    Ipv6Addr::from([
+        0xFE,
+        0x80,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        a ^ 0x02,
+        b,
+        c,
+        0xFF,
+        0xFE,
+        d,
+        e,
+        f,
+    ])
+}
+
+/// Add `data` as big endian 16 bit words to a running internet checksum
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    // Odd lengths are padded with a zero byte
+    if let [last] = words.remainder() {
+        sum += u16::from_be_bytes([*last, 0]) as u32;
+    }
+    sum
+}
+
+/// Fold the carries back in and take the ones complement
+fn checksum_finish(mut sum: u32) -> u16 {
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// RFC 1071 internet checksum, a buffer that includes a valid checksum sums
+/// to zero
+fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// The memory a download starting at `load_addr` may write to, at most
+/// `max_len` bytes of usable RAM clear of the heap
+fn load_region(
+    load_addr: usize,
+    max_len: usize,
+) -> Result<&'static mut [u8], Error> {
+    if load_addr < HIGH_MEMORY_START {
+        return Err(Error::InvalidLoadAddress);
+    }
+
+    match crate::mm::reserve(load_addr, max_len) {
+        0 => Err(Error::InvalidLoadAddress),
+        len => Ok(unsafe {
+            core::slice::from_raw_parts_mut(load_addr as *mut u8, len)
+        }),
+    }
+}
+
+fn nic() -> &'static dyn NetworkCard {
+    nic::interfaces()
+        .get(INTERFACE.load(Ordering::Relaxed))
+        .expect("Network stack used before net::init")
+        .card
+}
+
+fn mac() -> MacAddress {
+    nic().mac()
+}
+
+/// Move every frame the cards are holding onto [`RX_QUEUE`], called from the
+/// NIC interrupt and from [`poll`]
+fn fetch() {
+    cpu::without_interrupts(|| {
+        for (index, interface) in nic::interfaces().iter().enumerate() {
+            while let Some(frame) = interface.card.receive() {
+                let queue = unsafe { &mut *core::ptr::addr_of_mut!(RX_QUEUE) };
+                // Never grow the queue from an interrupt
+                if queue.len() < queue.capacity() {
+                    queue.push_back((index, frame));
+                }
+            }
+        }
+    })
+}
+
+/// Hand everything that has arrived to the stack. Blocking calls do this
+/// while they wait, so it only needs calling to answer the network while
+/// doing something else
+pub fn poll() {
+    fetch();
+
+    if POLLING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    if LINK_CHANGED.swap(false, Ordering::Relaxed) {
+        let link = nic().link();
+        println!("Link: {}", link);
+        // We may be plugged into a different network now
+        if link != Link::Down {
+            arp::flush();
+            ndp::flush();
+        }
+    }
+
+    while let Some((index, frame)) =
+        cpu::without_interrupts(|| unsafe { RX_QUEUE.pop_front() })
+    {
+        if index == INTERFACE.load(Ordering::Relaxed) {
+            receive(&frame);
+        }
+    }
+
+    POLLING.store(false, Ordering::Release);
+}
+
+/// Called by a card from its interrupt when its link changes
+pub(crate) fn link_changed() {
+    LINK_CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// Block until the interface has a link, cards that cannot tell count as
+/// having one straight away
+pub fn wait_for_link(timeout_ms: u64) -> Result<Link, Error> {
+    let deadline = pit::ticks() + timeout_ms;
+    loop {
+        let link = nic().link();
+        if link != Link::Down {
+            return Ok(link);
+        }
+        if pit::ticks() >= deadline {
+            return Err(Error::LinkDown);
+        }
+        wait();
+    }
+}
+
+/// Called by everything that blocks on the network, handles what has
+/// arrived then sleeps until the next interrupt. Before interrupts are
+/// enabled nothing would wake us, so it returns straight away
+fn wait() {
+    poll();
+
+    if cpu::interrupts_enabled() {
+        cpu::halt();
+    }
+}
+
+/// Entry point for every frame the NIC receives
+fn receive(frame: &Frame) {
+    pcap::capture(frame);
+
+    // The stack checks everything itself, but there is no point parsing a
+    // frame the card already knows is bad
+    if frame.checksum == Checksum::Invalid {
+        return;
+    }
+
+    let Ok(packet) = Packet::deserialise(frame) else {
+        return;
+    };
+    // VLAN ID 0 only carries a priority, the frame is really untagged
+    if packet.vlan().filter(|&id| id != 0) != vlan_id() {
+        return;
+    }
+
+    match &packet.protocol {
+        Protocol::Arp(arp) => arp::handle(arp),
+        Protocol::Ipv4(ipv4) => ipv4::handle(ipv4, packet.src_mac()),
+        Protocol::Ipv6(ipv6) => ipv6::handle(ipv6, packet.src_mac()),
+    }
 }
@@ -0,0 +1,542 @@
+//! Neighbor Discovery [https://www.rfc-editor.org/rfc/rfc4861] and stateless
+//! address autoconfiguration [https://www.rfc-editor.org/rfc/rfc4862]
+
+use core::{
+    net::Ipv6Addr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pit,
+};
+
+use super::{
+    icmpv6::{self, Icmpv6, Icmpv6Type},
+    ipv6::{self, Ipv6},
+    nic::MacAddress,
+    Endianness,
+};
+
+/// How many addresses we remember at once
+const CACHE_SIZE: usize = 16;
+/// How long an entry is trusted for before we ask again
+const CACHE_LIFETIME_MS: u64 = 60_000;
+/// How long to wait for an advertisement after each solicitation, this is
+/// also how long duplicate address detection waits [RFC 4861 10]
+const RETRANS_TIMER_MS: u64 = 1_000;
+/// How many solicitations [`resolve`] sends before giving up
+const MAX_MULTICAST_SOLICIT: usize = 3;
+/// Router solicitations we send before giving up on there being a router
+const MAX_RTR_SOLICITATIONS: usize = 3;
+/// Time between router solicitations
+const RTR_SOLICITATION_INTERVAL_MS: u64 = 4_000;
+
+/// Every NDP message is sent with this so receivers know it is from the link
+const HOP_LIMIT: u8 = 255;
+/// SLAAC only works with a 64 bit interface identifier [RFC 4862 5.5.3]
+const SLAAC_PREFIX_LEN: u8 = 64;
+
+/// ff02::1
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1);
+/// ff02::2
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 2);
+
+static mut CACHE: [Option<CacheEntry>; CACHE_SIZE] = [None; CACHE_SIZE];
+
+/// Address we are running duplicate address detection on
+static mut TENTATIVE: Option<Ipv6Addr> = None;
+/// Someone else is using [`TENTATIVE`]
+static DUPLICATE: AtomicBool = AtomicBool::new(false);
+
+/// What the last router advertisement told us, cleared before soliciting
+static mut ADVERTISEMENT: Option<Advertisement> = None;
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    ip: Ipv6Addr,
+    mac: MacAddress,
+    /// Tick at which this entry is no longer valid
+    expires: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Advertisement {
+    /// Set if it will be our default router
+    router: Option<Ipv6Addr>,
+    /// A prefix we can build an address from
+    prefix: Option<Ipv6Addr>,
+    /// The managed or other configuration flag was set, so there is a
+    /// DHCPv6 server to ask
+    dhcpv6: bool,
+}
+
+/// Option types
+mod option {
+    pub const SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+    pub const TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+    pub const PREFIX_INFORMATION: u8 = 3;
+}
+
+/// Neighbor advertisement flags
+mod flags {
+    pub const SOLICITED: u8 = 1 << 6;
+    pub const OVERRIDE: u8 = 1 << 5;
+}
+
+/// Router advertisement flags
+mod router_flags {
+    pub const MANAGED: u8 = 1 << 7;
+    pub const OTHER: u8 = 1 << 6;
+}
+
+/// Prefix information option flags
+mod prefix_flags {
+    pub const ON_LINK: u8 = 1 << 7;
+    pub const AUTONOMOUS: u8 = 1 << 6;
+}
+
+/// Iterator over options, yielding the type and the data after the length
+struct Options<'a> {
+    buffer: &'a [u8],
+    ptr: usize,
+}
+
+impl<'a> Options<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, ptr: 0 }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ty = *self.buffer.get(self.ptr)?;
+        // The length is in units of 8 bytes and includes the type and length
+        let len = *self.buffer.get(self.ptr + 1)? as usize * 8;
+        if len == 0 {
+            return None;
+        }
+
+        let data = self.buffer.get(self.ptr + 2..self.ptr + len)?;
+        self.ptr += len;
+        Some((ty, data))
+    }
+}
+
+/// Find a link-layer address option of type `ty`
+fn link_layer_address(options: &[u8], ty: u8) -> Option<MacAddress> {
+    Options::new(options)
+        .find(|&(option, data)| option == ty && data.len() >= 6)
+        .map(|(_, data)| {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&data[..6]);
+            mac.into()
+        })
+}
+
+/// ff02::1:ffXX:XXXX, the group anyone asking about `ip` sends to
+fn solicited_node(ip: Ipv6Addr) -> Ipv6Addr {
+    let octets = ip.octets();
+
+    Ipv6Addr::from([
+        0xFF, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xFF, octets[13], octets[14],
+        octets[15],
+    ])
+}
+
+/// Look up a non expired entry in the cache
+fn lookup(ip: Ipv6Addr) -> Option<MacAddress> {
+    let now = pit::ticks();
+    cpu::without_interrupts(|| unsafe {
+        CACHE
+            .iter()
+            .flatten()
+            .find(|entry| entry.ip == ip && entry.expires > now)
+            .map(|entry| entry.mac)
+    })
+}
+
+/// Forget every entry, they belong to the network we were on
+pub(super) fn flush() {
+    cpu::without_interrupts(|| unsafe { CACHE = [None; CACHE_SIZE] })
+}
+
+/// Insert or refresh an entry, if the cache is full the entry closest to
+/// expiring is replaced
+fn insert(ip: Ipv6Addr, mac: MacAddress) {
+    let now = pit::ticks();
+    let new_entry = CacheEntry {
+        ip,
+        mac,
+        expires: now + CACHE_LIFETIME_MS,
+    };
+
+    cpu::without_interrupts(|| unsafe {
+        let mut victim = 0;
+        for (i, slot) in CACHE.iter().enumerate() {
+            match slot {
+                Some(entry) if entry.ip == ip => {
+                    victim = i;
+                    break;
+                }
+                None => victim = i,
+                Some(entry) => {
+                    if let Some(current) = CACHE[victim] {
+                        if entry.expires < current.expires {
+                            victim = i;
+                        }
+                    }
+                }
+            }
+        }
+        CACHE[victim] = Some(new_entry);
+    })
+}
+
+/// Get the MAC address for an on-link IPv6 address, asking the network if we
+/// do not already know it. Blocks until answered or timed out
+pub(super) fn resolve(ip: Ipv6Addr) -> Result<MacAddress> {
+    for _ in 0..MAX_MULTICAST_SOLICIT {
+        if let Some(mac) = lookup(ip) {
+            return Ok(mac);
+        }
+
+        solicit(ip, ipv6::source_address(ip))?;
+
+        // Advertisements are put in the cache by [`super::poll`]
+        let deadline = pit::ticks() + RETRANS_TIMER_MS;
+        while pit::ticks() < deadline {
+            if let Some(mac) = lookup(ip) {
+                return Ok(mac);
+            }
+            super::wait();
+        }
+    }
+
+    Err(Error::NdpTimeout)
+}
+
+/// Multicast a neighbor solicitation for `target`. Sent from the unspecified
+/// address for duplicate address detection, which must not include our MAC
+fn solicit(target: Ipv6Addr, src_ip: Ipv6Addr) -> Result<()> {
+    let mut body = [0u8; 4 + 16 + 8];
+    let mut len = 0;
+    emit!(len, body, Endianness::Big, u32, 0);
+    emit!(len, body, [u8; 16], target.octets());
+    if !src_ip.is_unspecified() {
+        emit!(len, body, u8, option::SOURCE_LINK_LAYER_ADDRESS);
+        emit!(len, body, u8, 1);
+        emit!(len, body, [u8; 6], super::mac().into());
+    }
+
+    let dst_ip = solicited_node(target);
+    icmpv6::send(
+        ipv6::multicast_mac(dst_ip),
+        src_ip,
+        dst_ip,
+        HOP_LIMIT,
+        &Icmpv6 {
+            ty: Icmpv6Type::NeighborSolicitation,
+            code: 0,
+            body: &body[..len],
+        },
+    )
+}
+
+/// Tell `dst_ip` that `target` is at our MAC address
+fn advertise(
+    dst_mac: MacAddress,
+    dst_ip: Ipv6Addr,
+    target: Ipv6Addr,
+    solicited: bool,
+) -> Result<()> {
+    let mut body = [0u8; 4 + 16 + 8];
+    let mut len = 0;
+    let solicited = if solicited { flags::SOLICITED } else { 0 };
+    emit!(len, body, u8, solicited | flags::OVERRIDE);
+    emit!(len, body, [u8; 3], [0; 3]);
+    emit!(len, body, [u8; 16], target.octets());
+    emit!(len, body, u8, option::TARGET_LINK_LAYER_ADDRESS);
+    emit!(len, body, u8, 1);
+    emit!(len, body, [u8; 6], super::mac().into());
+
+    icmpv6::send(
+        dst_mac,
+        target,
+        dst_ip,
+        HOP_LIMIT,
+        &Icmpv6 {
+            ty: Icmpv6Type::NeighborAdvertisement,
+            code: 0,
+            body: &body[..len],
+        },
+    )
+}
+
+/// Ask any routers on the link to advertise themselves
+fn solicit_router() -> Result<()> {
+    let mut body = [0u8; 4 + 8];
+    let mut len = 0;
+    emit!(len, body, Endianness::Big, u32, 0);
+    emit!(len, body, u8, option::SOURCE_LINK_LAYER_ADDRESS);
+    emit!(len, body, u8, 1);
+    emit!(len, body, [u8; 6], super::mac().into());
+
+    icmpv6::send(
+        ipv6::multicast_mac(ALL_ROUTERS),
+        super::ipv6_link_local(),
+        ALL_ROUTERS,
+        HOP_LIMIT,
+        &Icmpv6 {
+            ty: Icmpv6Type::RouterSolicitation,
+            code: 0,
+            body: &body[..len],
+        },
+    )
+}
+
+/// Called for every neighbor discovery message we receive. Learns link-layer
+/// addresses, answers solicitations for our addresses and records router
+/// advertisements
+pub(super) fn handle(ipv6: &Ipv6, icmpv6: &Icmpv6, src_mac: MacAddress) {
+    // Anything that has been through a router is not from our link
+    if ipv6.hop_limit() != HOP_LIMIT || icmpv6.code != 0 {
+        return;
+    }
+
+    let body = icmpv6.body;
+    match icmpv6.ty {
+        Icmpv6Type::NeighborSolicitation if body.len() >= 20 => {
+            let mut target = [0; 16];
+            target.copy_from_slice(&body[4..20]);
+            handle_solicitation(ipv6, target.into(), &body[20..], src_mac);
+        }
+        Icmpv6Type::NeighborAdvertisement if body.len() >= 20 => {
+            let mut target = [0; 16];
+            target.copy_from_slice(&body[4..20]);
+            let target: Ipv6Addr = target.into();
+
+            if tentative() == Some(target) {
+                DUPLICATE.store(true, Ordering::SeqCst);
+                return;
+            }
+            let mac = link_layer_address(
+                &body[20..],
+                option::TARGET_LINK_LAYER_ADDRESS,
+            );
+            insert(target, mac.unwrap_or(src_mac));
+        }
+        Icmpv6Type::RouterAdvertisement if body.len() >= 12 => {
+            handle_advertisement(ipv6, body, src_mac);
+        }
+        _ => {}
+    }
+}
+
+fn tentative() -> Option<Ipv6Addr> {
+    cpu::without_interrupts(|| unsafe { TENTATIVE })
+}
+
+fn handle_solicitation(
+    ipv6: &Ipv6,
+    target: Ipv6Addr,
+    options: &[u8],
+    src_mac: MacAddress,
+) {
+    let from_unspecified = ipv6.src_ip().is_unspecified();
+
+    // Someone else is checking the address we want, so neither can have it
+    if tentative() == Some(target) {
+        if from_unspecified {
+            DUPLICATE.store(true, Ordering::SeqCst);
+        }
+        return;
+    }
+
+    if target != super::ipv6_link_local() && target != super::ipv6_address() {
+        return;
+    }
+
+    if from_unspecified {
+        // Their duplicate address detection, tell everyone it is ours
+        _ = advertise(ipv6::multicast_mac(ALL_NODES), ALL_NODES, target, false);
+    } else {
+        let mac =
+            link_layer_address(options, option::SOURCE_LINK_LAYER_ADDRESS)
+                .unwrap_or(src_mac);
+        insert(ipv6.src_ip(), mac);
+        _ = advertise(mac, ipv6.src_ip(), target, true);
+    }
+}
+
+fn handle_advertisement(ipv6: &Ipv6, body: &[u8], src_mac: MacAddress) {
+    // Routers always advertise from their link-local address
+    if !ipv6::is_link_local(ipv6.src_ip()) {
+        return;
+    }
+
+    let flags = body[1];
+    let router_lifetime = u16::from_be_bytes([body[2], body[3]]);
+    let options = &body[12..];
+
+    let mac = link_layer_address(options, option::SOURCE_LINK_LAYER_ADDRESS)
+        .unwrap_or(src_mac);
+    insert(ipv6.src_ip(), mac);
+
+    let prefix = Options::new(options)
+        .filter(|&(ty, data)| {
+            ty == option::PREFIX_INFORMATION && data.len() == 30
+        })
+        .find_map(|(_, data)| {
+            let prefix_len = data[0];
+            let flags = data[1];
+            let valid_lifetime =
+                u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+            let preferred_lifetime =
+                u32::from_be_bytes([data[6], data[7], data[8], data[9]]);
+
+            let mut prefix = [0; 16];
+            prefix.copy_from_slice(&data[14..30]);
+            let prefix: Ipv6Addr = prefix.into();
+
+            (flags & prefix_flags::AUTONOMOUS != 0
+                && flags & prefix_flags::ON_LINK != 0
+                && prefix_len == SLAAC_PREFIX_LEN
+                && valid_lifetime != 0
+                && preferred_lifetime <= valid_lifetime
+                && !ipv6::is_link_local(prefix))
+            .then_some(prefix)
+        });
+
+    let advertisement = Advertisement {
+        router: (router_lifetime != 0).then_some(ipv6.src_ip()),
+        prefix,
+        dhcpv6: (flags & (router_flags::MANAGED | router_flags::OTHER)) != 0,
+    };
+    cpu::without_interrupts(|| unsafe {
+        // Keep what an earlier advertisement told us if this one is partial
+        ADVERTISEMENT = Some(match ADVERTISEMENT {
+            Some(previous) => Advertisement {
+                router: advertisement.router.or(previous.router),
+                prefix: advertisement.prefix.or(previous.prefix),
+                dhcpv6: advertisement.dhcpv6 || previous.dhcpv6,
+            },
+            None => advertisement,
+        });
+    })
+}
+
+/// The router the last advertisement came from, even if it had no prefix
+/// for us, which is what a network that leaves addressing to DHCPv6 sends
+pub(super) fn advertised_router() -> Option<Ipv6Addr> {
+    cpu::without_interrupts(|| unsafe { ADVERTISEMENT })
+        .and_then(|advertisement| advertisement.router)
+}
+
+/// Whether a router has told us to use DHCPv6, without one there is no
+/// point waiting on a server
+pub(super) fn dhcpv6_advertised() -> bool {
+    cpu::without_interrupts(|| unsafe { ADVERTISEMENT })
+        .is_some_and(|advertisement| advertisement.dhcpv6)
+}
+
+/// Make sure nobody else on the link is using `address` before we do
+pub(super) fn check_duplicate(address: Ipv6Addr) -> Result<()> {
+    DUPLICATE.store(false, Ordering::SeqCst);
+    cpu::without_interrupts(|| unsafe { TENTATIVE = Some(address) });
+
+    // Answers are only seen by [`super::poll`], so keep it running while
+    // we wait for one
+    let result = solicit(address, Ipv6Addr::UNSPECIFIED).map(|_| {
+        let deadline = pit::ticks() + RETRANS_TIMER_MS;
+        while pit::ticks() < deadline && !DUPLICATE.load(Ordering::SeqCst) {
+            super::wait();
+        }
+    });
+
+    cpu::without_interrupts(|| unsafe { TENTATIVE = None });
+    result?;
+
+    if DUPLICATE.load(Ordering::SeqCst) {
+        return Err(Error::Ipv6DuplicateAddress);
+    }
+    Ok(())
+}
+
+/// Check our link-local address is unique, then ask for a router
+/// advertisement and build a global address from the prefix it gives us.
+/// Blocks until configured or timed out, returning the global address
+pub fn autoconfigure_ipv6() -> Result<Ipv6Addr> {
+    let link_local = super::ipv6_link_local();
+    check_duplicate(link_local)?;
+
+    cpu::without_interrupts(|| unsafe { ADVERTISEMENT = None });
+
+    for _ in 0..MAX_RTR_SOLICITATIONS {
+        solicit_router()?;
+
+        // Advertisements are recorded by [`super::poll`]
+        let deadline = pit::ticks() + RTR_SOLICITATION_INTERVAL_MS;
+        while pit::ticks() < deadline {
+            let advertisement =
+                cpu::without_interrupts(|| unsafe { ADVERTISEMENT });
+            if let Some(Advertisement {
+                router,
+                prefix: Some(prefix),
+                ..
+            }) = advertisement
+            {
+                // Our interface identifier is the same on every prefix
+                let mut address = prefix.octets();
+                address[8..].copy_from_slice(&link_local.octets()[8..]);
+                let address = address.into();
+
+                check_duplicate(address)?;
+                super::configure_ipv6(
+                    address,
+                    SLAAC_PREFIX_LEN,
+                    router.unwrap_or(Ipv6Addr::UNSPECIFIED),
+                );
+                return Ok(address);
+            }
+            super::wait();
+        }
+    }
+
+    Err(Error::Ipv6NoRouter)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn options_are_walked_in_units_of_8() {
+        let options = [
+            0x01, 1, 0x52, 0x54, 0x00, 0x12, 0x34, 0x56, // Source
+            0x19, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // Unknown
+            0x02, 1, 0x52, 0x54, 0x00, 0x65, 0x43, 0x21, // Target
+        ];
+
+        let types: Vec<_> = Options::new(&options).map(|(ty, _)| ty).collect();
+        assert_eq!(types, [1, 0x19, 2]);
+        assert_eq!(
+            link_layer_address(&options, option::TARGET_LINK_LAYER_ADDRESS),
+            Some([0x52, 0x54, 0x00, 0x65, 0x43, 0x21].into())
+        );
+    }
+
+    #[test]
+    fn zero_length_or_truncated_option_ends_the_walk() {
+        let zero = [option::SOURCE_LINK_LAYER_ADDRESS, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Options::new(&zero).count(), 0);
+
+        let truncated = [option::SOURCE_LINK_LAYER_ADDRESS, 2, 0, 0, 0, 0];
+        assert_eq!(Options::new(&truncated).count(), 0);
+    }
+}
@@ -1,40 +1,109 @@
 //! TODO: Very broken
 
-use self::reg::{ics, rctl, RCTL};
-use super::{MacAddress, NetworkCard};
+use self::reg::{ctrl, eerd, ics, rctl, rxcsum, status, tctl, RCTL, TCTL};
+use super::{Checksum, Frame, Link, MacAddress, NetworkCard};
 use crate::{
-    interrupts::Idt,
-    net::{packet::Packet, Serialise},
-    pci::{self},
-    pic,
+    error::{Error, Result},
+    pci::{self, Id},
 };
+use alloc::alloc::alloc_zeroed;
 use core::{
-    mem::MaybeUninit,
+    alloc::Layout,
+    cell::Cell,
     ptr::{read_volatile, write_volatile},
 };
 
-pub static mut DRIVER: MaybeUninit<Driver> = MaybeUninit::uninit();
-
 const PACKET_SIZE: usize = 2048;
-const RDESCS_BASE_ADDR: u64 = 0x100_000;
 const RDESCS_LENGTH: u32 = 8;
-const RECEIVE_BUFFER_BASE_ADDR: u64 = 0x108_000;
-const RECEIVE_QUEUE_HEAD_START: u32 = 20;
-const RECEIVE_QUEUE_TAIL_START: u32 = 4;
-
-//// Register offsets of the E1000
-//const REG_TDBAL: u32 = 0x3800;
-//const REG_TDBAH: u32 = 0x3804;
-//const REG_TDLEN: u32 = 0x3808;
-//const REG_TDH: u32 = 0x3810;
-//const REG_TDT: u32 = 0x3818;
-//const REG_TCTL: u32 = 0x0400;
+const TDESCS_LENGTH: u32 = 8;
+/// The causes we act on, anything else is left masked
+const INTERRUPTS: u32 = ics::LSC | ics::RXTO | ics::TXDW;
+/// The rings must be 16 byte aligned, the manual recommends a whole cache
+/// line
+const RING_ALIGN: usize = 128;
+
+/// Register reads waiting for the reset to finish or an EEPROM word, both
+/// are done well within this
+const POLLS: usize = 100_000;
+/// EEPROM word offset of the permanent MAC, three little endian words
+const EEPROM_MAC: u8 = 0x00;
+
+/// How the permanent MAC is reached, the one way the family differs that
+/// matters to us. Everything else we use is laid out the same on all of
+/// them, the 82574 and later still take the legacy descriptors and legacy
+/// interrupts unless extended descriptors or MSI-X are asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Nvm {
+    /// EEPROM through [`reg::EERD`]
+    Eeprom,
+    /// EEPROM through [`reg::EERD`], with the done bit and address moved
+    EepromExtended,
+    /// Flash that is only reachable through its own BAR, the hardware loads
+    /// the MAC from it into [`reg::RAL`]/[`reg::RAH`] on reset
+    Flash,
+}
+
+/// Every device the driver supports
+const MODELS: [(Id, Nvm); 7] = [
+    (Id::E1000, Nvm::Eeprom),
+    (Id::I82545em, Nvm::Eeprom),
+    (Id::I82574l, Nvm::EepromExtended),
+    (Id::I217Lm, Nvm::Flash),
+    (Id::I217V, Nvm::Flash),
+    (Id::I219Lm, Nvm::Flash),
+    (Id::I219V, Nvm::Flash),
+];
+
+fn nvm(id: Id) -> Option<Nvm> {
+    MODELS
+        .iter()
+        .find(|(model, _)| *model == id)
+        .map(|(_, nvm)| *nvm)
+}
+
+/// Whether the Intel device `id` is one of ours
+pub fn supports(id: Id) -> bool {
+    nvm(id).is_some()
+}
 
 /// registers
 #[allow(dead_code)]
 mod reg {
     pub const CTRL: u32 = 0x0000;
+    pub(super) mod ctrl {
+        /// Auto-Speed Detection Enable
+        pub const ASDE: u32 = 1 << 5;
+        /// Set Link Up
+        pub const SLU: u32 = 1 << 6;
+        /// Invert Loss-of-Signal
+        pub const ILOS: u32 = 1 << 7;
+        /// Device Reset, clears itself once done
+        pub const RST: u32 = 1 << 26;
+        /// VLAN Mode Enable
+        pub const VME: u32 = 1 << 30;
+        /// PHY Reset
+        pub const PHY_RST: u32 = 1 << 31;
+    }
     pub const STATUS: u32 = 0x0008;
+    pub(super) mod status {
+        /// Full Duplex
+        pub const FD: u32 = 1 << 0;
+        /// Link Up
+        pub const LU: u32 = 1 << 1;
+        pub const SPEED_SHIFT: u32 = 6;
+        pub const SPEED_MASK: u32 = 0b11;
+    }
+    /// EEPROM Read
+    pub const EERD: u32 = 0x0014;
+    pub(super) mod eerd {
+        pub const START: u32 = 1 << 0;
+        pub const DONE: u32 = 1 << 4;
+        pub const ADDR_SHIFT: u32 = 8;
+        /// 82574 and later
+        pub const DONE_EXTENDED: u32 = 1 << 1;
+        pub const ADDR_SHIFT_EXTENDED: u32 = 2;
+        pub const DATA_SHIFT: u32 = 16;
+    }
 
     /// Interrupt Cause Read Register
     pub const ICR: u32 = 0x00C0;
@@ -43,6 +112,12 @@ mod reg {
     /// Used for Software to set the interrupt conditions
     pub const ICS: u32 = 0x00C8;
     pub(super) mod ics {
+        /// Transmit Descriptor Written Back
+        pub const TXDW: u32 = 1 << 0;
+        /// Transmit Queue Empty
+        pub const TXQE: u32 = 1 << 1;
+        /// Link Status Change
+        pub const LSC: u32 = 1 << 2;
         /// Receive Timer Interrupt
         pub const RXTO: u32 = 1 << 7;
     }
@@ -57,31 +132,44 @@ mod reg {
         pub const ACCEPT_BROADCAST: u32 = 1 << 15;
         pub const STRIP_CRC: u32 = 1 << 26;
     }
+    /// Receive Checksum Control
+    pub const RXCSUM: u32 = 0x5000;
+    pub(super) mod rxcsum {
+        /// IP Checksum Offload
+        pub const IPOFLD: u32 = 1 << 8;
+        /// TCP/UDP Checksum Offload
+        pub const TUOFLD: u32 = 1 << 9;
+    }
 
     pub const RDBAL0: u32 = 0x2800;
     pub const RDBAH0: u32 = 0x2804;
     pub const RDLEN0: u32 = 0x2808;
     pub const RDH0: u32 = 0x2810;
     pub const RDT0: u32 = 0x2818;
+
+    pub const TCTL: u32 = 0x0400;
+    pub(super) mod tctl {
+        pub const ENABLE: u32 = 1 << 1;
+        pub const PAD_SHORT_PACKETS: u32 = 1 << 3;
+        /// Collision Threshold, recommended value is 0x0F
+        pub const COLLISION_THRESHOLD: u32 = 0x0F << 4;
+        /// Collision Distance, recommended value for full duplex is 0x40
+        pub const COLLISION_DISTANCE: u32 = 0x40 << 12;
+    }
+    /// Transmit Inter Packet Gap
+    pub const TIPG: u32 = 0x0410;
+
+    pub const TDBAL: u32 = 0x3800;
+    pub const TDBAH: u32 = 0x3804;
+    pub const TDLEN: u32 = 0x3808;
+    pub const TDH: u32 = 0x3810;
+    pub const TDT: u32 = 0x3818;
     /// Mac Address Low
     pub const RAL: u32 = 0x5400;
     /// Mac Address High
     pub const RAH: u32 = 0x5404;
-}
-
-isr!(irq, net::nic::e1000);
-
-fn isr(_ip: u32, _cs: u32, _flags: u32, _sp: u32, _ss: u32) {
-    let driver = unsafe { &*DRIVER.as_ptr() };
-
-    let cause = driver.read(reg::ICR);
-    if (cause & ics::RXTO) == ics::RXTO {
-        driver.receive();
-    } else {
-        print!("Cause: {}", cause);
-    }
-
-    crate::pic::end_of_interrupt();
+    /// In [`RAH`], Address Valid
+    pub const RAH_AV: u32 = 1 << 31;
 }
 
 /// This struct is the receive descriptor format that stores the packet metadata
@@ -97,13 +185,73 @@ struct Rdesc {
     special: u16,
 }
 
-#[allow(dead_code)]
+impl Rdesc {
+    /// Descriptor Done
+    const STATUS_DD: u8 = 1 << 0;
+    /// Ignore Checksum Indication
+    const STATUS_IXSM: u8 = 1 << 2;
+    /// TCP/UDP Checksum Calculated
+    const STATUS_TCPCS: u8 = 1 << 5;
+    /// IP Checksum Calculated
+    const STATUS_IPCS: u8 = 1 << 6;
+
+    /// TCP/UDP Checksum Error
+    const ERROR_TCPE: u8 = 1 << 5;
+    /// IP Checksum Error
+    const ERROR_IPE: u8 = 1 << 6;
+
+    /// What the checksum offload made of the frame
+    fn checksum(&self) -> Checksum {
+        if (self.status & Self::STATUS_IXSM) != 0 {
+            Checksum::Unchecked
+        } else if (self.errors & (Self::ERROR_IPE | Self::ERROR_TCPE)) != 0 {
+            Checksum::Invalid
+        } else if (self.status & (Self::STATUS_IPCS | Self::STATUS_TCPCS)) != 0
+        {
+            Checksum::Valid
+        } else {
+            Checksum::Unchecked
+        }
+    }
+}
+
+/// This struct is the legacy transmit descriptor format, the buffer points to
+/// the packet we want the NIC to put on the wire
+#[derive(Debug, Default)]
+#[repr(C)]
+struct Tdesc {
+    buffer: u64,
+    len: u16,
+    checksum_offset: u8,
+    cmd: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+impl Tdesc {
+    /// End Of Packet
+    const CMD_EOP: u8 = 1 << 0;
+    /// Insert Frame Check Sequence
+    const CMD_IFCS: u8 = 1 << 1;
+    /// Report Status, asks the NIC to set [`Self::STATUS_DD`] when done
+    const CMD_RS: u8 = 1 << 3;
+
+    /// Descriptor Done
+    const STATUS_DD: u8 = 1 << 0;
+}
+
 #[derive(Debug)]
 pub struct Driver {
     mmio_base: u32,
-    io_base: usize,
-    flash_base: usize,
+    nvm: Nvm,
     mac_addr: MacAddress,
+    rdescs: *mut Rdesc,
+    rx_buffers: *mut u8,
+    tdescs: *mut Tdesc,
+    tx_buffers: *mut u8,
+    /// The next receive descriptor the NIC will fill
+    rx_next: Cell<u32>,
 }
 
 impl Driver {
@@ -123,33 +271,99 @@ impl Driver {
         };
     }
 
+    /// Put the whole device back to its power on state, then let it bring
+    /// the link up by itself
+    fn reset(&self) {
+        self.write(reg::IMC, 0xFFFFFFFF);
+        self.write(reg::CTRL, self.read(reg::CTRL) | ctrl::RST);
+        for _ in 0..POLLS {
+            if (self.read(reg::CTRL) & ctrl::RST) == 0 {
+                break;
+            }
+        }
+
+        // The reset can leave interrupts enabled and pending
+        self.write(reg::IMC, 0xFFFFFFFF);
+        self.read(reg::ICR);
+
+        self.write(
+            reg::CTRL,
+            (self.read(reg::CTRL) | ctrl::SLU | ctrl::ASDE)
+                & !(ctrl::ILOS | ctrl::VME | ctrl::PHY_RST),
+        );
+    }
+
+    /// Read a 16 bit word from the EEPROM, [`None`] if there is not one
+    fn read_eeprom(&self, address: u8) -> Option<u16> {
+        let (done, addr_shift) = match self.nvm {
+            Nvm::Eeprom => (eerd::DONE, eerd::ADDR_SHIFT),
+            Nvm::EepromExtended => {
+                (eerd::DONE_EXTENDED, eerd::ADDR_SHIFT_EXTENDED)
+            }
+            Nvm::Flash => return None,
+        };
+
+        self.write(reg::EERD, eerd::START | (address as u32) << addr_shift);
+        for _ in 0..POLLS {
+            let eerd = self.read(reg::EERD);
+            if (eerd & done) != 0 {
+                return Some((eerd >> eerd::DATA_SHIFT) as u16);
+            }
+        }
+        None
+    }
+
+    /// The permanent MAC from the EEPROM, falling back to whatever the
+    /// firmware left in the first receive address
+    fn read_mac(&self) -> MacAddress {
+        let mut mac = [0; 6];
+        for (i, bytes) in mac.chunks_exact_mut(2).enumerate() {
+            let Some(word) = self.read_eeprom(EEPROM_MAC + i as u8) else {
+                let [a0, a1, b0, b1] = self.read(reg::RAL).to_le_bytes();
+                let [c0, c1, ..] = self.read(reg::RAH).to_le_bytes();
+                return [a0, a1, b0, b1, c0, c1].into();
+            };
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        mac.into()
+    }
+
     fn init_recieve(&self) {
-        // Set the Receive Descriptor Length
-        self.write(reg::RDLEN0, RDESCS_LENGTH << 8);
+        // Set the Receive Descriptor Length, in bytes
+        self.write(
+            reg::RDLEN0,
+            RDESCS_LENGTH * core::mem::size_of::<Rdesc>() as u32,
+        );
 
-        // Set the Receive Descriptor Head/Tail
-        self.write(reg::RDH0, RECEIVE_QUEUE_HEAD_START);
-        self.write(reg::RDT0, RECEIVE_QUEUE_TAIL_START);
+        // The NIC owns everything from the head up to the tail, which is
+        // always one behind the descriptor we will look at next
+        self.write(reg::RDH0, 0);
+        self.write(reg::RDT0, RDESCS_LENGTH - 1);
+        self.rx_next.set(0);
 
         // give them a size we want Set the Receive Descriptor Base Address
-        self.write(reg::RDBAH0, (RDESCS_BASE_ADDR >> 32) as u32);
-        self.write(reg::RDBAL0, RDESCS_BASE_ADDR as u32);
-
-        // Zero out the chosen memory location and place the memory location for
-        // the raw packets in the Recieve buffer field in the [`Rdesc`]
-        // struct
-        let rdesc_base_ptr = RDESCS_BASE_ADDR as *mut Rdesc;
-        for offset in 0..RDESCS_LENGTH as isize {
+        self.write(reg::RDBAH0, 0);
+        self.write(reg::RDBAL0, self.rdescs as u32);
+
+        // Zero out the descriptors and place the memory location for the
+        // raw packets in the Recieve buffer field in the [`Rdesc`] struct
+        for offset in 0..RDESCS_LENGTH as usize {
             let rdesc = Rdesc {
-                buffer: RECEIVE_BUFFER_BASE_ADDR
-                    + (offset as usize * PACKET_SIZE) as u64,
+                buffer: unsafe { self.rx_buffers.add(offset * PACKET_SIZE) }
+                    as u64,
                 ..Default::default()
             };
             unsafe {
-                write_volatile(rdesc_base_ptr.offset(offset), rdesc);
+                write_volatile(self.rdescs.add(offset), rdesc);
             }
         }
 
+        // Have the NIC check IP, TCP and UDP checksums for us
+        self.write(reg::RXCSUM, rxcsum::IPOFLD | rxcsum::TUOFLD);
+
+        // VLAN filtering and stripping (RCTL.VFE, CTRL.VME) are left off so
+        // 802.1Q tags reach the stack untouched, it tags and filters in
+        // software
         self.write(
             RCTL,
             rctl::ENABLE
@@ -159,32 +373,108 @@ impl Driver {
                 | rctl::STRIP_CRC,
         );
     }
+
+    fn init_transmit(&self) {
+        // Every descriptor starts out as done so the first pass through the
+        // ring sees them all as free
+        for offset in 0..TDESCS_LENGTH as usize {
+            let tdesc = Tdesc {
+                buffer: unsafe { self.tx_buffers.add(offset * PACKET_SIZE) }
+                    as u64,
+                status: Tdesc::STATUS_DD,
+                ..Default::default()
+            };
+            unsafe {
+                write_volatile(self.tdescs.add(offset), tdesc);
+            }
+        }
+
+        self.write(reg::TDBAH, 0);
+        self.write(reg::TDBAL, self.tdescs as u32);
+
+        // Length is in bytes
+        self.write(
+            reg::TDLEN,
+            TDESCS_LENGTH * core::mem::size_of::<Tdesc>() as u32,
+        );
+
+        self.write(reg::TDH, 0);
+        self.write(reg::TDT, 0);
+
+        // IPGT 10, IPGR1 8, IPGR2 6 as recommended for IEEE 802.3
+        self.write(reg::TIPG, 10 | 8 << 10 | 6 << 20);
+
+        self.write(
+            TCTL,
+            tctl::ENABLE
+                | tctl::PAD_SHORT_PACKETS
+                | tctl::COLLISION_THRESHOLD
+                | tctl::COLLISION_DISTANCE,
+        );
+    }
 }
 
 impl NetworkCard for Driver {
     fn new(device: &pci::Device) -> Self {
-        let mmio_base = ((device.base_addrs()[0]) & !0b1111) as u32;
-        let io_base = (device.base_addrs()[1] & !0b0011) as usize;
-        let flash_base = device.base_addrs()[2] as usize;
-
-        // Parse MAC from MMIO
-        let mac_addr = unsafe {
-            read_volatile((mmio_base + reg::RAL) as *const MacAddress)
+        // The other BARs move around between models, but we only need this
+        let mmio_base = device.base_addrs()[0] & !0b1111;
+        let nvm = nvm(device.id()).expect("Not an E1000");
+
+        // Every port gets its own rings and buffers for the NIC to DMA into
+        let ring_layout = |descs: u32, desc_len: usize| {
+            Layout::from_size_align(descs as usize * desc_len, RING_ALIGN)
+                .unwrap()
         };
+        let buffers_layout = |descs: u32| {
+            Layout::from_size_align(descs as usize * PACKET_SIZE, 16).unwrap()
+        };
+        let (rdescs, rx_buffers, tdescs, tx_buffers) = unsafe {
+            (
+                alloc_zeroed(ring_layout(
+                    RDESCS_LENGTH,
+                    core::mem::size_of::<Rdesc>(),
+                )),
+                alloc_zeroed(buffers_layout(RDESCS_LENGTH)),
+                alloc_zeroed(ring_layout(
+                    TDESCS_LENGTH,
+                    core::mem::size_of::<Tdesc>(),
+                )),
+                alloc_zeroed(buffers_layout(TDESCS_LENGTH)),
+            )
+        };
+        assert!(
+            !rdescs.is_null()
+                && !rx_buffers.is_null()
+                && !tdescs.is_null()
+                && !tx_buffers.is_null(),
+            "Out of memory for E1000 rings"
+        );
 
         // Bus master enable
         device.enable();
 
-        // Insert interrupt into IDT
-        Idt::insert(irq, device.interrupt_line());
-        pic::unmask(device.interrupt_line());
-
-        Self {
+        let mut driver = Self {
             mmio_base,
-            io_base,
-            flash_base,
-            mac_addr,
-        }
+            nvm,
+            mac_addr: MacAddress::ZERO,
+            rdescs: rdescs as *mut Rdesc,
+            rx_buffers,
+            tdescs: tdescs as *mut Tdesc,
+            tx_buffers,
+            rx_next: Cell::new(0),
+        };
+
+        driver.reset();
+
+        // Only valid in the receive address registers if firmware put it
+        // there, so always take it from the EEPROM and put it there ourselves
+        driver.mac_addr = driver.read_mac();
+        let [a0, a1, b0, b1, c0, c1]: [u8; 6] = driver.mac_addr.into();
+        driver.write(reg::RAL, u32::from_le_bytes([a0, a1, b0, b1]));
+        driver
+            .write(reg::RAH, u32::from_le_bytes([c0, c1, 0, 0]) | reg::RAH_AV);
+
+        driver
     }
 
     fn mac(&self) -> MacAddress {
@@ -195,40 +485,107 @@ impl NetworkCard for Driver {
         // Enable receiving packets
         self.init_recieve();
 
+        // Enable sending packets
+        self.init_transmit();
+
         // Enable interrupts
-        self.write(reg::IMS, 0xFFFFFFFF);
-    }
-
-    fn receive(&self) {
-        let rdesc_base_ptr = RDESCS_BASE_ADDR as *mut Rdesc;
-
-        for offset in 0..RDESCS_LENGTH as isize {
-            // Get a reference to the MMIO Receieve Descriptor buffer
-            let rdesc =
-                unsafe { &mut *(rdesc_base_ptr.offset(offset) as *mut Rdesc) };
-
-            // A non zero status means a packet has arrived and is ready for
-            // processing
-            if rdesc.status != 0 {
-                // Get a reference to the MMIO packet buffer
-                let buffer =
-                    unsafe { &*(rdesc.buffer as *const [u8; PACKET_SIZE]) };
-
-                // Turn raw bytes in a rusty packet!
-                println!(
-                    "{:X?}",
-                    Packet::deserialise(&buffer[..rdesc.len as usize])
-                );
-
-                // Tell the NIC we are done with that packet
-                rdesc.status = 0;
-
-                // Increment tail to give space for new packets
-                self.write(
-                    reg::RDT0,
-                    (self.read(reg::RDT0) + 1) % RDESCS_LENGTH,
-                )
-            }
+        self.write(reg::IMS, INTERRUPTS);
+    }
+
+    fn receive(&self) -> Option<Frame> {
+        let next = self.rx_next.get();
+
+        // Get a reference to the MMIO Receieve Descriptor buffer
+        let rdesc = unsafe { &mut *self.rdescs.add(next as usize) };
+
+        // The NIC sets Descriptor Done once a packet has arrived
+        let status = unsafe { read_volatile(&rdesc.status) };
+        if (status & Rdesc::STATUS_DD) == 0 {
+            return None;
+        }
+
+        // Get a reference to the MMIO packet buffer
+        let buffer = unsafe { &*(rdesc.buffer as *const [u8; PACKET_SIZE]) };
+        let len = (rdesc.len as usize).min(PACKET_SIZE);
+        let frame = Frame::new(&buffer[..len], rdesc.checksum());
+
+        // Tell the NIC we are done with that packet
+        unsafe { write_volatile(&mut rdesc.status, 0) };
+        self.write(reg::RDT0, next);
+        self.rx_next.set((next + 1) % RDESCS_LENGTH);
+
+        // Too big to be a frame, skip to the next
+        frame.or_else(|| self.receive())
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        if frame.len() > PACKET_SIZE {
+            return Err(Error::FrameTooLarge);
+        }
+
+        let tail = self.read(reg::TDT);
+        let tdesc = unsafe { &mut *self.tdescs.add(tail as usize) };
+
+        // The NIC has not finished with the buffer from the last time
+        // round the ring, so it is not safe to overwrite it yet
+        let status = unsafe { read_volatile(&tdesc.status) };
+        if (status & Tdesc::STATUS_DD) != Tdesc::STATUS_DD {
+            return Err(Error::TransmitQueueFull);
+        }
+
+        // Get a reference to the MMIO packet buffer and fill it
+        let buffer = unsafe { &mut *(tdesc.buffer as *mut [u8; PACKET_SIZE]) };
+        buffer[..frame.len()].copy_from_slice(frame);
+
+        unsafe {
+            write_volatile(&mut tdesc.len, frame.len() as u16);
+            write_volatile(
+                &mut tdesc.cmd,
+                Tdesc::CMD_EOP | Tdesc::CMD_IFCS | Tdesc::CMD_RS,
+            );
+            write_volatile(&mut tdesc.status, 0);
+        }
+
+        // Hand the descriptor over to the NIC
+        self.write(reg::TDT, (tail + 1) % TDESCS_LENGTH);
+
+        Ok(())
+    }
+
+    fn acknowledge_interrupt(&self) {
+        // Reading the cause clears it
+        let cause = self.read(reg::ICR);
+
+        // Received frames are picked up by polling the descriptors, and
+        // transmit completion is tracked through the DD bit of each
+        // [`Tdesc`], so there is nothing to do for those causes here
+        if (cause & ics::LSC) != 0 {
+            crate::net::link_changed();
+        }
+    }
+
+    fn link(&self) -> Link {
+        let status = self.read(reg::STATUS);
+        if (status & status::LU) == 0 {
+            return Link::Down;
+        }
+
+        let speed = match (status >> status::SPEED_SHIFT) & status::SPEED_MASK {
+            0b00 => 10,
+            0b01 => 100,
+            _ => 1000,
+        };
+        Link::Up {
+            speed,
+            full_duplex: (status & status::FD) != 0,
+        }
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        if enabled {
+            self.write(reg::IMS, INTERRUPTS);
+        } else {
+            self.write(reg::IMC, 0xFFFFFFFF);
         }
     }
 }
@@ -0,0 +1,144 @@
+//! In-memory card for driving the stack without any hardware. Frames the
+//! stack sends are queued for a scripted peer to [`Loopback::take_sent`], and
+//! frames the peer [`Loopback::inject`]s are handed to the stack by
+//! [`NetworkCard::receive`]. Nothing raises an interrupt so whoever drives
+//! the card must call [`crate::net::poll`]. The queues are global so there
+//! can only be one. Tests block in the stack, so their peer is run by the
+//! card each time it is asked for a frame
+
+#[cfg(test)]
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use super::{Checksum, Frame, MacAddress, NetworkCard};
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pci,
+};
+
+/// Locally administered so it cannot clash with a real card
+const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+
+/// Frames each queue holds before refusing more
+const QUEUE_LEN: usize = 16;
+
+/// Waiting for [`NetworkCard::receive`]
+static mut TO_STACK: VecDeque<Frame> = VecDeque::new();
+/// Waiting for [`Loopback::take_sent`]
+static mut FROM_STACK: VecDeque<Frame> = VecDeque::new();
+
+/// Answers the stack from inside [`NetworkCard::receive`], see
+/// [`Loopback::set_peer`]
+#[cfg(test)]
+type Peer = Box<dyn FnMut(&Loopback)>;
+
+#[cfg(test)]
+static mut PEER: Option<Peer> = None;
+
+/// Queue a copy of `frame`, the queues never grow past [`QUEUE_LEN`] so
+/// nothing is allocated after [`NetworkCard::init`]
+fn push(queue: &mut VecDeque<Frame>, frame: &[u8]) -> Result<()> {
+    let frame =
+        Frame::new(frame, Checksum::Unchecked).ok_or(Error::FrameTooLarge)?;
+    if queue.len() == QUEUE_LEN {
+        return Err(Error::TransmitQueueFull);
+    }
+
+    queue.push_back(frame);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Loopback {
+    mac_addr: MacAddress,
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        Self {
+            mac_addr: MAC.into(),
+        }
+    }
+}
+
+/// Used by the scripted peer, nothing in the boot calls these
+#[allow(dead_code)]
+impl Loopback {
+    /// Queue `frame` for the stack as if it had come off the wire
+    pub fn inject(&self, frame: &[u8]) -> Result<()> {
+        cpu::without_interrupts(|| unsafe { push(&mut TO_STACK, frame) })
+    }
+
+    /// Take the oldest frame the stack sent, copying as much as fits into
+    /// `buffer`. Returns the length copied
+    pub fn take_sent(&self, buffer: &mut [u8]) -> Option<usize> {
+        let frame =
+            cpu::without_interrupts(|| unsafe { FROM_STACK.pop_front() })?;
+
+        let len = frame.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&frame[..len]);
+
+        Some(len)
+    }
+
+    /// Put `peer` on the other end of an empty wire. It is run whenever the
+    /// stack looks for a frame, and answers with [`Self::take_sent`] and
+    /// [`Self::inject`]
+    #[cfg(test)]
+    pub fn set_peer(&self, peer: impl FnMut(&Loopback) + 'static) {
+        cpu::without_interrupts(|| unsafe {
+            TO_STACK.clear();
+            FROM_STACK.clear();
+            PEER = Some(Box::new(peer));
+        })
+    }
+}
+
+impl NetworkCard for Loopback {
+    /// There is no hardware behind it so the device is ignored
+    fn new(_device: &pci::Device) -> Self {
+        Self::default()
+    }
+
+    fn init(&mut self) {
+        cpu::without_interrupts(|| unsafe {
+            TO_STACK.clear();
+            TO_STACK.reserve_exact(QUEUE_LEN);
+            FROM_STACK.clear();
+            FROM_STACK.reserve_exact(QUEUE_LEN);
+        })
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac_addr
+    }
+
+    /// Hand over the oldest injected frame
+    fn receive(&self) -> Option<Frame> {
+        #[cfg(test)]
+        {
+            // Taken out while it runs, it uses the card itself
+            let peer = cpu::without_interrupts(|| unsafe { PEER.take() });
+            if let Some(mut peer) = peer {
+                peer(self);
+                cpu::without_interrupts(|| unsafe {
+                    PEER.get_or_insert(peer);
+                });
+            }
+        }
+
+        cpu::without_interrupts(|| unsafe { TO_STACK.pop_front() })
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        cpu::without_interrupts(|| unsafe { push(&mut FROM_STACK, frame) })
+    }
+
+    /// There is no interrupt to acknowledge
+    fn acknowledge_interrupt(&self) {}
+
+    /// There is no interrupt to enable
+    fn set_interrupts(&self, _enabled: bool) {}
+}
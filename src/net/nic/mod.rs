@@ -1,44 +1,266 @@
 //! Provides generic NetworkCard to OS for Network Card implementations
 
 mod e1000;
-use core::fmt::Debug;
+pub mod loopback;
+mod ne2000;
+mod rtl8139;
+mod virtio;
+use core::{
+    fmt::{self, Debug},
+    ops::Deref,
+};
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec::Vec};
 
-use crate::pci::{self, Id, Vendor};
+use crate::{
+    cpu,
+    error::Result,
+    interrupts::Idt,
+    pci::{self, Id, Vendor},
+    pic,
+};
 
-#[derive(Debug, Clone, Copy)]
+/// Every card we are driving, in the order they were found. Only grows, and
+/// only with interrupts disabled as the ISR walks it
+static mut INTERFACES: Vec<Interface> = Vec::new();
+/// Interrupt lines we have already unmasked, cards can share them
+static mut LINES: Vec<u8> = Vec::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct MacAddress([u8; 6]);
 
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+    pub const ZERO: Self = Self([0; 6]);
+}
+
 impl From<[u8; 6]> for MacAddress {
     fn from(value: [u8; 6]) -> Self {
         Self(value)
     }
 }
 
+impl From<MacAddress> for [u8; 6] {
+    fn from(value: MacAddress) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// Picks out one interface, for pinning the one we boot from
+#[derive(Debug, Clone, Copy)]
+// Only built from the config in main, which may not pick either
+#[allow(dead_code)]
+pub enum Selector {
+    Mac([u8; 6]),
+    Pci(pci::Location),
+}
+
+/// A card we are driving and where it is
+#[derive(Clone, Copy)]
+pub struct Interface {
+    pub(super) card: &'static dyn NetworkCard,
+    /// [`None`] for cards that are not on PCI
+    location: Option<pci::Location>,
+}
+
+impl Interface {
+    pub fn matches(&self, selector: &Selector) -> bool {
+        match selector {
+            Selector::Mac(mac) => self.card.mac() == (*mac).into(),
+            Selector::Pci(location) => self.location == Some(*location),
+        }
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.card.mac())?;
+        if let Some(location) = self.location {
+            write!(f, " at PCI {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the card made of the checksums in a frame it received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// The card does not check, or could not for this frame
+    Unchecked,
+    /// Every checksum the card checked was good
+    Valid,
+    /// The card found a bad checksum
+    Invalid,
+}
+
+/// State of a card's link to the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Down,
+    Up {
+        /// Mbit/s
+        speed: u16,
+        full_duplex: bool,
+    },
+    /// The card cannot tell us, so we assume it is up
+    Unknown,
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Down => write!(f, "down"),
+            Self::Up { speed, full_duplex } => write!(
+                f,
+                "up {} Mbit/s {} duplex",
+                speed,
+                if *full_duplex { "full" } else { "half" }
+            ),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A received frame (without FCS) copied out of the card's buffers, so the
+/// card can reuse them straight away
+#[derive(Clone, Copy)]
+pub struct Frame {
+    data: [u8; Frame::MAX_LEN],
+    len: usize,
+    pub checksum: Checksum,
+}
+
+impl Frame {
+    /// A tagged frame, with room for an FCS a card leaves on
+    pub const MAX_LEN: usize = 1522;
+
+    /// Copy `data` into a new frame, [`None`] if it is too big to be one
+    pub fn new(data: &[u8], checksum: Checksum) -> Option<Self> {
+        if data.len() > Self::MAX_LEN {
+            return None;
+        }
+
+        let mut frame = Self {
+            data: [0; Self::MAX_LEN],
+            len: data.len(),
+            checksum,
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+
+        Some(frame)
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
 pub trait NetworkCard {
-    fn new(device: &pci::Device) -> Self;
+    fn new(device: &pci::Device) -> Self
+    where
+        Self: Sized;
     fn init(&mut self);
     fn mac(&self) -> MacAddress;
-    fn receive(&self);
+    /// Take the oldest frame the card has received, [`None`] once it has no
+    /// more
+    fn receive(&self) -> Option<Frame>;
+    /// Queue a complete ethernet frame (without FCS) for transmission
+    fn send(&self, frame: &[u8]) -> Result<()>;
+    /// Clear whatever made the card raise its interrupt. Lines can be shared
+    /// so this is called for every card whichever one it was
+    fn acknowledge_interrupt(&self);
+    /// Let the card raise interrupts, or stop it for polled operation
+    fn set_interrupts(&self, enabled: bool);
+    /// Cards that can tell when their link changes call
+    /// [`crate::net::link_changed`] from [`Self::acknowledge_interrupt`]
+    fn link(&self) -> Link {
+        Link::Unknown
+    }
 }
 
-pub fn find(
-    devices: &Vec<pci::Device>,
-) -> Option<&mut (impl NetworkCard + Debug)> {
+isr!(irq, net::nic);
+
+fn isr() {
+    for interface in interfaces() {
+        interface.card.acknowledge_interrupt();
+    }
+
+    // Frames are only moved off the cards here, the stack parses them later
+    super::fetch();
+
+    crate::pic::end_of_interrupt();
+}
+
+/// Initialise every card we have a driver for, returning how many there are
+pub fn init(devices: &Vec<pci::Device>) -> usize {
     for device in devices {
         if !device.is_network_controller() {
             continue;
         }
 
-        match (device.vendor(), device.id()) {
-            (Vendor::Intel, Id::E1000) => unsafe {
-                e1000::DRIVER.write(e1000::Driver::new(device));
-                return Some(e1000::DRIVER.assume_init_mut());
-            },
+        let card: Box<dyn NetworkCard> = match (device.vendor(), device.id()) {
+            (Vendor::Intel, id) if e1000::supports(id) => {
+                Box::new(e1000::Driver::new(device))
+            }
+            (Vendor::Virtio, Id::VirtioNet | Id::VirtioNetModern) => {
+                Box::new(virtio::Driver::new(device))
+            }
+            (Vendor::Realtek, Id::Rtl8139) => {
+                Box::new(rtl8139::Driver::new(device))
+            }
+            (Vendor::Realtek, Id::Rtl8029) => {
+                Box::new(ne2000::Driver::new(device))
+            }
             _ => continue,
+        };
+
+        let card = Box::leak(card);
+        card.init();
+        register(card, Some(device.location()), Some(device.interrupt_line()));
+    }
+
+    interfaces().len()
+}
+
+/// Start taking frames from an initialised `card`, `interrupt_line` is
+/// [`None`] for cards that never interrupt
+pub fn register(
+    card: &'static dyn NetworkCard,
+    location: Option<pci::Location>,
+    interrupt_line: Option<u8>,
+) {
+    cpu::without_interrupts(|| unsafe {
+        INTERFACES.push(Interface { card, location })
+    });
+
+    if let Some(line) = interrupt_line {
+        // Unmasking toggles the line, so only the first card on it does
+        let first = cpu::without_interrupts(|| unsafe {
+            let first = !LINES.contains(&line);
+            if first {
+                LINES.push(line);
+            }
+            first
+        });
+        if first {
+            Idt::insert(irq, line);
+            pic::unmask(line);
         }
     }
-    None
+}
+
+pub fn interfaces() -> &'static [Interface] {
+    unsafe { &*core::ptr::addr_of!(INTERFACES) }
 }
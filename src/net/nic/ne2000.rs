@@ -0,0 +1,369 @@
+//! NE2000 compatible cards [https://wiki.osdev.org/Ne2000], a DP8390 with
+//! 16 KiB of its own RAM that is only reachable through remote DMA on the
+//! data port. Only the PCI variant (RTL8029) is found, ISA cards cannot be
+//! enumerated
+
+use super::{Checksum, Frame, MacAddress, NetworkCard};
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pci,
+};
+use core::cell::Cell;
+
+/// Card RAM is addressed in pages, the ring works in whole pages
+const PAGE_LEN: usize = 256;
+/// Enough pages for one full frame
+const TX_START_PAGE: u8 = 0x40;
+/// The rest of card RAM is the receive ring
+const RX_START_PAGE: u8 = 0x46;
+const RX_STOP_PAGE: u8 = 0x80;
+
+/// Status, next page and length in front of every frame in the ring
+const RX_HEADER_LEN: usize = 4;
+/// The card does not pad short frames itself
+const MIN_FRAME_LEN: usize = 60;
+/// Port reads waiting for the previous frame to go, each is about a
+/// microsecond on the ISA bus
+const TX_POLLS: usize = 10_000;
+
+/// registers, offsets from the I/O base. Most of them depend on the page
+/// selected in [`reg::CR`], page 0 unless noted
+#[allow(dead_code)]
+mod reg {
+    pub const CR: u16 = 0x00;
+    pub(super) mod cr {
+        pub const STOP: u8 = 1 << 0;
+        pub const START: u8 = 1 << 1;
+        pub const TRANSMIT: u8 = 1 << 2;
+        pub const REMOTE_READ: u8 = 1 << 3;
+        pub const REMOTE_WRITE: u8 = 1 << 4;
+        /// Abort or complete remote DMA
+        pub const NO_DMA: u8 = 1 << 5;
+        pub const PAGE1: u8 = 1 << 6;
+    }
+    pub const PSTART: u16 = 0x01;
+    pub const PSTOP: u16 = 0x02;
+    /// Boundary, the last page we have finished with
+    pub const BNRY: u16 = 0x03;
+    /// Transmit page start
+    pub const TPSR: u16 = 0x04;
+    /// Transmit byte count
+    pub const TBCR0: u16 = 0x05;
+    pub const TBCR1: u16 = 0x06;
+    /// Interrupt status, write 1s to clear
+    pub const ISR: u16 = 0x07;
+    pub(super) mod isr {
+        pub const PACKET_RECEIVED: u8 = 1 << 0;
+        pub const PACKET_TRANSMITTED: u8 = 1 << 1;
+        pub const RECEIVE_ERROR: u8 = 1 << 2;
+        pub const TRANSMIT_ERROR: u8 = 1 << 3;
+        pub const OVERWRITE: u8 = 1 << 4;
+        pub const REMOTE_DMA_COMPLETE: u8 = 1 << 6;
+        pub const RESET: u8 = 1 << 7;
+    }
+    /// Remote start address
+    pub const RSAR0: u16 = 0x08;
+    pub const RSAR1: u16 = 0x09;
+    /// Remote byte count
+    pub const RBCR0: u16 = 0x0A;
+    pub const RBCR1: u16 = 0x0B;
+    pub const RCR: u16 = 0x0C;
+    pub(super) mod rcr {
+        pub const ACCEPT_BROADCAST: u8 = 1 << 2;
+        pub const ACCEPT_MULTICAST: u8 = 1 << 3;
+        pub const PROMISCUOUS: u8 = 1 << 4;
+        pub const MONITOR: u8 = 1 << 5;
+    }
+    pub const TCR: u16 = 0x0D;
+    pub(super) mod tcr {
+        pub const NORMAL: u8 = 0;
+        pub const INTERNAL_LOOPBACK: u8 = 1 << 1;
+    }
+    pub const DCR: u16 = 0x0E;
+    pub(super) mod dcr {
+        pub const WORD_TRANSFER: u8 = 1 << 0;
+        /// Normal operation rather than loopback
+        pub const LOOPBACK_SELECT: u8 = 1 << 3;
+        pub const FIFO_8_BYTES: u8 = 1 << 6;
+    }
+    /// Interrupt mask
+    pub const IMR: u16 = 0x0F;
+    /// Page 1, physical address, six bytes
+    pub const PAR0: u16 = 0x01;
+    /// Page 1, current page the card is receiving into
+    pub const CURR: u16 = 0x07;
+    /// Page 1, multicast filter, eight bytes
+    pub const MAR0: u16 = 0x08;
+    /// Remote DMA port
+    pub const DATA: u16 = 0x10;
+    /// Reading then writing this resets the card
+    pub const RESET: u16 = 0x1F;
+}
+
+/// Receive status in the header in front of each frame
+const RX_STATUS_OK: u8 = 1 << 0;
+
+#[derive(Debug)]
+pub struct Driver {
+    io_base: u16,
+    mac_addr: MacAddress,
+    /// The page the next frame in the ring starts at
+    next_page: Cell<u8>,
+}
+
+impl Driver {
+    /// Copy `buffer.len()` bytes out of card RAM at `addr`
+    fn remote_read(&self, addr: usize, buffer: &mut [u8]) {
+        self.start_remote(addr, buffer.len(), reg::cr::REMOTE_READ);
+        for chunk in buffer.chunks_mut(2) {
+            let word = cpu::in16(self.io_base + reg::DATA).to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+        self.finish_remote();
+    }
+
+    /// Copy `data` into card RAM at `addr`
+    fn remote_write(&self, addr: usize, data: &[u8]) {
+        self.start_remote(addr, data.len(), reg::cr::REMOTE_WRITE);
+        for chunk in data.chunks(2) {
+            let mut word = [0; 2];
+            word[..chunk.len()].copy_from_slice(chunk);
+            cpu::out16(self.io_base + reg::DATA, u16::from_le_bytes(word));
+        }
+        self.finish_remote();
+    }
+
+    fn start_remote(&self, addr: usize, len: usize, direction: u8) {
+        // The data port moves words, so an odd count is rounded up
+        let len = len.next_multiple_of(2);
+
+        cpu::out8(self.io_base + reg::RBCR0, len as u8);
+        cpu::out8(self.io_base + reg::RBCR1, (len >> 8) as u8);
+        cpu::out8(self.io_base + reg::RSAR0, addr as u8);
+        cpu::out8(self.io_base + reg::RSAR1, (addr >> 8) as u8);
+        // Leave the card started or stopped as it was
+        let running =
+            cpu::in8(self.io_base + reg::CR) & (reg::cr::START | reg::cr::STOP);
+        cpu::out8(self.io_base + reg::CR, direction | running);
+    }
+
+    fn finish_remote(&self) {
+        while (cpu::in8(self.io_base + reg::ISR)
+            & reg::isr::REMOTE_DMA_COMPLETE)
+            == 0
+        {}
+        cpu::out8(self.io_base + reg::ISR, reg::isr::REMOTE_DMA_COMPLETE);
+    }
+
+    /// The page the card is currently receiving into
+    fn current_page(&self) -> u8 {
+        cpu::out8(
+            self.io_base + reg::CR,
+            reg::cr::PAGE1 | reg::cr::NO_DMA | reg::cr::START,
+        );
+        let current = cpu::in8(self.io_base + reg::CURR);
+        cpu::out8(self.io_base + reg::CR, reg::cr::NO_DMA | reg::cr::START);
+        current
+    }
+
+    /// Hand every page before `page` back to the card
+    fn release_until(&self, page: u8) {
+        self.next_page.set(page);
+        let boundary = if page == RX_START_PAGE {
+            RX_STOP_PAGE - 1
+        } else {
+            page - 1
+        };
+        cpu::out8(self.io_base + reg::BNRY, boundary);
+    }
+}
+
+impl NetworkCard for Driver {
+    fn new(device: &pci::Device) -> Self {
+        let io_base = (device.base_addrs()[0] & !0b11) as u16;
+
+        // I/O enable
+        device.enable();
+
+        // Reset and wait for the card to say it is done
+        cpu::out8(io_base + reg::RESET, cpu::in8(io_base + reg::RESET));
+        while (cpu::in8(io_base + reg::ISR) & reg::isr::RESET) == 0 {}
+        cpu::out8(io_base + reg::ISR, 0xFF);
+
+        // Stopped, with word transfers so the data port can be used
+        cpu::out8(io_base + reg::CR, reg::cr::NO_DMA | reg::cr::STOP);
+        cpu::out8(
+            io_base + reg::DCR,
+            reg::dcr::FIFO_8_BYTES
+                | reg::dcr::LOOPBACK_SELECT
+                | reg::dcr::WORD_TRANSFER,
+        );
+        cpu::out8(io_base + reg::RBCR0, 0);
+        cpu::out8(io_base + reg::RBCR1, 0);
+
+        let mut driver = Self {
+            io_base,
+            mac_addr: MacAddress::ZERO,
+            next_page: Cell::new(RX_START_PAGE + 1),
+        };
+
+        // The MAC address is in the PROM at the bottom of card RAM, each
+        // byte doubled up into a word
+        let mut prom = [0; 12];
+        driver.remote_read(0, &mut prom);
+        let mac_addr: [u8; 6] = core::array::from_fn(|i| prom[i * 2]);
+        driver.mac_addr = mac_addr.into();
+
+        driver
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac_addr
+    }
+
+    fn init(&mut self) {
+        let mac_addr: [u8; 6] = self.mac_addr.into();
+
+        // Nothing goes out or comes in while the ring is set up
+        cpu::out8(self.io_base + reg::RCR, reg::rcr::MONITOR);
+        cpu::out8(self.io_base + reg::TCR, reg::tcr::INTERNAL_LOOPBACK);
+
+        cpu::out8(self.io_base + reg::PSTART, RX_START_PAGE);
+        cpu::out8(self.io_base + reg::PSTOP, RX_STOP_PAGE);
+        cpu::out8(self.io_base + reg::BNRY, RX_START_PAGE);
+        cpu::out8(self.io_base + reg::TPSR, TX_START_PAGE);
+
+        cpu::out8(self.io_base + reg::ISR, 0xFF);
+
+        cpu::out8(
+            self.io_base + reg::CR,
+            reg::cr::PAGE1 | reg::cr::NO_DMA | reg::cr::STOP,
+        );
+        for (i, byte) in mac_addr.iter().enumerate() {
+            cpu::out8(self.io_base + reg::PAR0 + i as u16, *byte);
+        }
+        for i in 0..8 {
+            cpu::out8(self.io_base + reg::MAR0 + i, 0xFF);
+        }
+        cpu::out8(self.io_base + reg::CURR, RX_START_PAGE + 1);
+        self.next_page.set(RX_START_PAGE + 1);
+
+        cpu::out8(self.io_base + reg::CR, reg::cr::NO_DMA | reg::cr::START);
+        cpu::out8(self.io_base + reg::TCR, reg::tcr::NORMAL);
+        cpu::out8(
+            self.io_base + reg::RCR,
+            reg::rcr::PROMISCUOUS
+                | reg::rcr::ACCEPT_MULTICAST
+                | reg::rcr::ACCEPT_BROADCAST,
+        );
+
+        self.set_interrupts(true);
+    }
+
+    fn receive(&self) -> Option<Frame> {
+        // Remote DMA is shared with send, which must not start one halfway
+        // through ours
+        cpu::without_interrupts(|| {
+            let page = self.next_page.get();
+            let current = self.current_page();
+            if page == current {
+                return None;
+            }
+
+            let mut header = [0; RX_HEADER_LEN];
+            self.remote_read(page as usize * PAGE_LEN, &mut header);
+            let [status, next, ..] = header;
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+            // A bad header means we have lost our place in the ring, skip
+            // everything the card has received so far
+            if (status & RX_STATUS_OK) == 0
+                || !(RX_START_PAGE..RX_STOP_PAGE).contains(&next)
+                || len < RX_HEADER_LEN
+                || len - RX_HEADER_LEN > Frame::MAX_LEN
+            {
+                self.release_until(current);
+                return None;
+            }
+
+            let mut buffer = [0; Frame::MAX_LEN];
+            let frame = &mut buffer[..len - RX_HEADER_LEN];
+
+            // The frame may wrap round the end of the ring
+            let start = page as usize * PAGE_LEN + RX_HEADER_LEN;
+            let before_end =
+                frame.len().min(RX_STOP_PAGE as usize * PAGE_LEN - start);
+            self.remote_read(start, &mut frame[..before_end]);
+            if before_end < frame.len() {
+                self.remote_read(
+                    RX_START_PAGE as usize * PAGE_LEN,
+                    &mut frame[before_end..],
+                );
+            }
+
+            self.release_until(next);
+
+            Frame::new(frame, Checksum::Unchecked)
+        })
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        if frame.len() > (RX_START_PAGE - TX_START_PAGE) as usize * PAGE_LEN {
+            return Err(Error::FrameTooLarge);
+        }
+
+        // The ISR uses remote DMA to receive, so we must not be interrupted
+        cpu::without_interrupts(|| {
+            // There is only room for one frame, give the last one time to go
+            // (a full frame takes 1.2 ms at 10 Mbit/s)
+            let mut polls = 0;
+            while (cpu::in8(self.io_base + reg::CR) & reg::cr::TRANSMIT) != 0 {
+                if polls == TX_POLLS {
+                    return Err(Error::TransmitQueueFull);
+                }
+                polls += 1;
+            }
+
+            let mut padded = [0; MIN_FRAME_LEN];
+            let frame = if frame.len() < MIN_FRAME_LEN {
+                padded[..frame.len()].copy_from_slice(frame);
+                &padded[..]
+            } else {
+                frame
+            };
+
+            self.remote_write(TX_START_PAGE as usize * PAGE_LEN, frame);
+
+            cpu::out8(self.io_base + reg::TPSR, TX_START_PAGE);
+            cpu::out8(self.io_base + reg::TBCR0, frame.len() as u8);
+            cpu::out8(self.io_base + reg::TBCR1, (frame.len() >> 8) as u8);
+            cpu::out8(
+                self.io_base + reg::CR,
+                reg::cr::NO_DMA | reg::cr::TRANSMIT | reg::cr::START,
+            );
+
+            Ok(())
+        })
+    }
+
+    fn acknowledge_interrupt(&self) {
+        // Frames are picked up by polling the ring so the cause does not
+        // matter, writing it back clears it
+        let cause = cpu::in8(self.io_base + reg::ISR);
+        cpu::out8(self.io_base + reg::ISR, cause);
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        let mask = if enabled {
+            reg::isr::PACKET_RECEIVED
+                | reg::isr::PACKET_TRANSMITTED
+                | reg::isr::RECEIVE_ERROR
+                | reg::isr::TRANSMIT_ERROR
+                | reg::isr::OVERWRITE
+        } else {
+            0
+        };
+        cpu::out8(self.io_base + reg::IMR, mask);
+    }
+}
@@ -0,0 +1,291 @@
+//! Realtek RTL8139 [https://wiki.osdev.org/RTL8139], driven through its I/O
+//! port registers
+
+use super::{Checksum, Frame, MacAddress, NetworkCard};
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pci,
+};
+use alloc::alloc::alloc_zeroed;
+use core::{alloc::Layout, cell::Cell, ptr::read_volatile};
+
+/// The card wraps at 8 KiB, with [`reg::rcr::WRAP`] set a frame that runs
+/// past the end is written on past it instead, so we need room for one more
+/// frame
+const RX_RING_LEN: usize = 8192;
+const RX_BUFFER_LEN: usize = RX_RING_LEN + 16 + 1536;
+/// The card always reads CAPR this far behind where we really are
+const CAPR_OFFSET: u16 = 16;
+/// Status and length in front of every received frame
+const RX_HEADER_LEN: usize = 4;
+const FCS_LEN: usize = 4;
+
+const TX_DESCRIPTORS: usize = 4;
+const TX_BUFFER_LEN: usize = 1536;
+/// The card does not pad short frames itself
+const MIN_FRAME_LEN: usize = 60;
+
+/// registers
+#[allow(dead_code)]
+mod reg {
+    /// MAC address, six bytes
+    pub const IDR0: u16 = 0x00;
+    /// Transmit status of each descriptor, four bytes apart
+    pub const TSD0: u16 = 0x10;
+    /// Transmit start address of each descriptor, four bytes apart
+    pub const TSAD0: u16 = 0x20;
+    pub const RBSTART: u16 = 0x30;
+    pub const CR: u16 = 0x37;
+    pub(super) mod cr {
+        /// Receive buffer empty
+        pub const BUFE: u8 = 1 << 0;
+        pub const TRANSMIT_ENABLE: u8 = 1 << 2;
+        pub const RECEIVE_ENABLE: u8 = 1 << 3;
+        pub const RESET: u8 = 1 << 4;
+    }
+    /// Current address of packet read
+    pub const CAPR: u16 = 0x38;
+    /// Interrupt mask
+    pub const IMR: u16 = 0x3C;
+    /// Interrupt status, write 1s to clear
+    pub const ISR: u16 = 0x3E;
+    pub(super) mod isr {
+        pub const RECEIVE_OK: u16 = 1 << 0;
+        pub const RECEIVE_ERROR: u16 = 1 << 1;
+        pub const TRANSMIT_OK: u16 = 1 << 2;
+        pub const TRANSMIT_ERROR: u16 = 1 << 3;
+        pub const RX_BUFFER_OVERFLOW: u16 = 1 << 4;
+        pub const LINK_CHANGE: u16 = 1 << 5;
+        pub const RX_FIFO_OVERFLOW: u16 = 1 << 6;
+    }
+    pub const TCR: u16 = 0x40;
+    pub(super) mod tcr {
+        /// Unlimited DMA burst
+        pub const MXDMA_UNLIMITED: u32 = 0b111 << 8;
+        /// The standard 9.6 us inter frame gap
+        pub const IFG_NORMAL: u32 = 0b11 << 24;
+    }
+    pub const RCR: u16 = 0x44;
+    pub(super) mod rcr {
+        pub const ACCEPT_ALL: u32 = 1 << 0;
+        pub const ACCEPT_PHYSICAL_MATCH: u32 = 1 << 1;
+        pub const ACCEPT_MULTICAST: u32 = 1 << 2;
+        pub const ACCEPT_BROADCAST: u32 = 1 << 3;
+        pub const WRAP: u32 = 1 << 7;
+        /// Unlimited DMA burst
+        pub const MXDMA_UNLIMITED: u32 = 0b111 << 8;
+    }
+    pub const CONFIG1: u16 = 0x52;
+}
+
+/// Transmit status bits
+mod tsd {
+    /// The card has finished reading the buffer
+    pub const OWN: u32 = 1 << 13;
+    pub const SIZE_MASK: u32 = 0x1FFF;
+}
+
+/// Receive status bits in the header in front of each frame
+const RX_STATUS_OK: u16 = 1 << 0;
+
+#[derive(Debug)]
+pub struct Driver {
+    io_base: u16,
+    mac_addr: MacAddress,
+    rx_buffer: *mut u8,
+    /// Where the next frame starts in [`Self::rx_buffer`]
+    rx_offset: Cell<usize>,
+    tx_buffers: *mut u8,
+    /// The descriptors are used in turn, this is the next one
+    tx_next: Cell<usize>,
+}
+
+impl Driver {
+    fn tx_buffer(&self, descriptor: usize) -> *mut u8 {
+        unsafe { self.tx_buffers.add(descriptor * TX_BUFFER_LEN) }
+    }
+
+    /// Stop receiving and start again from the top of the ring, the only way
+    /// out of a corrupt ring
+    fn init_receive(&self) {
+        let command = cpu::in8(self.io_base + reg::CR);
+        cpu::out8(self.io_base + reg::CR, command & !reg::cr::RECEIVE_ENABLE);
+
+        cpu::out32(self.io_base + reg::RBSTART, self.rx_buffer as u32);
+        self.rx_offset.set(0);
+        cpu::out16(self.io_base + reg::CAPR, 0u16.wrapping_sub(CAPR_OFFSET));
+
+        cpu::out8(self.io_base + reg::CR, command | reg::cr::RECEIVE_ENABLE);
+        cpu::out32(
+            self.io_base + reg::RCR,
+            reg::rcr::ACCEPT_ALL
+                | reg::rcr::ACCEPT_PHYSICAL_MATCH
+                | reg::rcr::ACCEPT_MULTICAST
+                | reg::rcr::ACCEPT_BROADCAST
+                | reg::rcr::WRAP
+                | reg::rcr::MXDMA_UNLIMITED,
+        );
+    }
+}
+
+impl NetworkCard for Driver {
+    fn new(device: &pci::Device) -> Self {
+        let io_base = (device.base_addrs()[0] & !0b11) as u16;
+
+        // Bus master enable
+        device.enable();
+
+        // Power on, then reset and wait for the reset bit to clear
+        cpu::out8(io_base + reg::CONFIG1, 0);
+        cpu::out8(io_base + reg::CR, reg::cr::RESET);
+        while (cpu::in8(io_base + reg::CR) & reg::cr::RESET) != 0 {}
+
+        let mac_addr: [u8; 6] =
+            core::array::from_fn(|i| cpu::in8(io_base + reg::IDR0 + i as u16));
+
+        let (rx_buffer, tx_buffers) = unsafe {
+            (
+                alloc_zeroed(
+                    Layout::from_size_align(RX_BUFFER_LEN, 16).unwrap(),
+                ),
+                alloc_zeroed(
+                    Layout::from_size_align(TX_DESCRIPTORS * TX_BUFFER_LEN, 16)
+                        .unwrap(),
+                ),
+            )
+        };
+        assert!(
+            !rx_buffer.is_null() && !tx_buffers.is_null(),
+            "Out of memory for RTL8139 buffers"
+        );
+
+        Self {
+            io_base,
+            mac_addr: mac_addr.into(),
+            rx_buffer,
+            rx_offset: Cell::new(0),
+            tx_buffers,
+            tx_next: Cell::new(0),
+        }
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac_addr
+    }
+
+    fn init(&mut self) {
+        for descriptor in 0..TX_DESCRIPTORS {
+            cpu::out32(
+                self.io_base + reg::TSAD0 + descriptor as u16 * 4,
+                self.tx_buffer(descriptor) as u32,
+            );
+        }
+
+        cpu::out8(
+            self.io_base + reg::CR,
+            reg::cr::RECEIVE_ENABLE | reg::cr::TRANSMIT_ENABLE,
+        );
+        cpu::out32(
+            self.io_base + reg::TCR,
+            reg::tcr::IFG_NORMAL | reg::tcr::MXDMA_UNLIMITED,
+        );
+        self.init_receive();
+
+        self.set_interrupts(true);
+    }
+
+    fn receive(&self) -> Option<Frame> {
+        if (cpu::in8(self.io_base + reg::CR) & reg::cr::BUFE) != 0 {
+            return None;
+        }
+
+        let offset = self.rx_offset.get();
+        let header = unsafe { self.rx_buffer.add(offset) };
+        let (status, len) = unsafe {
+            (
+                read_volatile(header as *const u16),
+                read_volatile((header as *const u16).add(1)) as usize,
+            )
+        };
+
+        // A bad header means we have lost our place in the ring
+        if (status & RX_STATUS_OK) == 0
+            || !(FCS_LEN..=RX_BUFFER_LEN - RX_RING_LEN).contains(&len)
+        {
+            self.init_receive();
+            return None;
+        }
+
+        let frame = Frame::new(
+            unsafe {
+                core::slice::from_raw_parts(
+                    header.add(RX_HEADER_LEN),
+                    len - FCS_LEN,
+                )
+            },
+            Checksum::Unchecked,
+        );
+
+        // Frames start on a four byte boundary
+        let offset =
+            (offset + RX_HEADER_LEN + len).next_multiple_of(4) % RX_RING_LEN;
+        self.rx_offset.set(offset);
+        cpu::out16(
+            self.io_base + reg::CAPR,
+            (offset as u16).wrapping_sub(CAPR_OFFSET),
+        );
+
+        // Too big to be a frame, skip to the next
+        frame.or_else(|| self.receive())
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        if frame.len() > TX_BUFFER_LEN {
+            return Err(Error::FrameTooLarge);
+        }
+
+        let descriptor = self.tx_next.get();
+        let tsd = self.io_base + reg::TSD0 + descriptor as u16 * 4;
+
+        // The card has not finished with the buffer from the last time
+        // round, so it is not safe to overwrite it yet
+        if (cpu::in32(tsd) & tsd::OWN) == 0 {
+            return Err(Error::TransmitQueueFull);
+        }
+
+        let len = frame.len().max(MIN_FRAME_LEN);
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(self.tx_buffer(descriptor), len)
+        };
+        buffer[..frame.len()].copy_from_slice(frame);
+        buffer[frame.len()..].fill(0);
+
+        // Writing the size clears OWN and starts the transmit
+        cpu::out32(tsd, len as u32 & tsd::SIZE_MASK);
+        self.tx_next.set((descriptor + 1) % TX_DESCRIPTORS);
+
+        Ok(())
+    }
+
+    fn acknowledge_interrupt(&self) {
+        // Frames are picked up by polling the ring so the cause does not
+        // matter, writing it back clears it
+        let cause = cpu::in16(self.io_base + reg::ISR);
+        cpu::out16(self.io_base + reg::ISR, cause);
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        let mask = if enabled {
+            reg::isr::RECEIVE_OK
+                | reg::isr::RECEIVE_ERROR
+                | reg::isr::TRANSMIT_OK
+                | reg::isr::TRANSMIT_ERROR
+                | reg::isr::RX_BUFFER_OVERFLOW
+                | reg::isr::RX_FIFO_OVERFLOW
+        } else {
+            0
+        };
+        cpu::out16(self.io_base + reg::IMR, mask);
+    }
+}
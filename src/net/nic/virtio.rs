@@ -0,0 +1,634 @@
+//! Virtio network device over PCI
+//! [https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html], using
+//! the modern interface when its registers are below 4 GiB and the legacy I/O
+//! port interface otherwise
+
+use super::{Checksum, Frame, MacAddress, NetworkCard};
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pci::{self, Id},
+};
+use alloc::alloc::alloc_zeroed;
+use core::{
+    alloc::Layout,
+    cell::Cell,
+    ptr::{read_volatile, write_volatile},
+};
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Buffers we give each queue, however big the device makes it
+const BUFFERS: u16 = 16;
+/// Room for the virtio header and a full tagged frame
+const BUFFER_LEN: usize = 2048;
+/// The legacy interface puts the used ring on the next page
+const PAGE_SIZE: usize = 4096;
+
+/// Header in front of every frame, `num_buffers` is only there in modern
+const LEGACY_HEADER_LEN: usize = 10;
+const MODERN_HEADER_LEN: usize = 12;
+
+/// Used when the device does not have a MAC, locally administered
+const DEFAULT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+/// Device status bits
+mod status {
+    pub const ACKNOWLEDGE: u8 = 1 << 0;
+    pub const DRIVER: u8 = 1 << 1;
+    pub const DRIVER_OK: u8 = 1 << 2;
+    pub const FEATURES_OK: u8 = 1 << 3;
+    pub const FAILED: u8 = 1 << 7;
+}
+
+/// Feature bits
+mod features {
+    /// The device config holds its MAC
+    pub const NET_MAC: u64 = 1 << 5;
+    /// Legacy devices take the header and frame in one buffer
+    pub const ANY_LAYOUT: u64 = 1 << 27;
+    /// We speak the modern interface
+    pub const VERSION_1: u64 = 1 << 32;
+}
+
+/// Legacy registers, offsets into the I/O port BAR
+mod legacy {
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const DRIVER_FEATURES: u16 = 0x04;
+    /// Page number of the queue
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0C;
+    pub const QUEUE_SELECT: u16 = 0x0E;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+    pub const ISR_STATUS: u16 = 0x13;
+    /// Device specific config, while MSI-X is disabled
+    pub const CONFIG: u16 = 0x14;
+}
+
+/// Modern common configuration registers
+mod common_cfg {
+    pub const DEVICE_FEATURE_SELECT: u32 = 0x00;
+    pub const DEVICE_FEATURE: u32 = 0x04;
+    pub const DRIVER_FEATURE_SELECT: u32 = 0x08;
+    pub const DRIVER_FEATURE: u32 = 0x0C;
+    pub const DEVICE_STATUS: u32 = 0x14;
+    pub const QUEUE_SELECT: u32 = 0x16;
+    pub const QUEUE_SIZE: u32 = 0x18;
+    pub const QUEUE_ENABLE: u32 = 0x1C;
+    pub const QUEUE_NOTIFY_OFF: u32 = 0x1E;
+    pub const QUEUE_DESC: u32 = 0x20;
+    pub const QUEUE_DRIVER: u32 = 0x28;
+    pub const QUEUE_DEVICE: u32 = 0x30;
+}
+
+/// Vendor specific PCI capability that points the modern interface at a BAR
+mod capability {
+    pub const ID: u8 = 0x09;
+
+    /// Offsets into the capability
+    pub const CFG_TYPE: u8 = 3;
+    pub const BAR: u8 = 4;
+    pub const OFFSET: u8 = 8;
+    pub const NOTIFY_OFF_MULTIPLIER: u8 = 16;
+
+    /// Config types
+    pub const COMMON_CFG: u8 = 1;
+    pub const NOTIFY_CFG: u8 = 2;
+    pub const ISR_CFG: u8 = 3;
+    pub const DEVICE_CFG: u8 = 4;
+}
+
+fn mmio_read<T>(address: u32) -> T {
+    unsafe { read_volatile(address as *const T) }
+}
+
+fn mmio_write<T>(address: u32, value: T) {
+    unsafe { write_volatile(address as *mut T, value) }
+}
+
+/// How we reach the device registers
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Legacy {
+        io_base: u16,
+    },
+    Modern {
+        common: u32,
+        notify: u32,
+        notify_off_multiplier: u32,
+        isr: u32,
+        device: u32,
+    },
+}
+
+impl Transport {
+    /// Find the modern registers from the PCI capabilities, [`None`] if the
+    /// device does not have them or they are somewhere we cannot reach
+    fn modern(device: &pci::Device) -> Option<Self> {
+        let base_addrs = device.base_addrs();
+        let (mut common, mut notify, mut isr, mut config) =
+            (None, None, None, None);
+        let mut notify_off_multiplier = 0;
+
+        for (id, offset) in device.capabilities() {
+            if id != capability::ID {
+                continue;
+            }
+
+            let cfg_type =
+                (device.read32(offset) >> (capability::CFG_TYPE * 8)) as u8;
+            let bar = device.read32(offset + capability::BAR) as u8 as usize;
+            let bar_offset = device.read32(offset + capability::OFFSET);
+
+            // Must be a memory BAR, and if it is 64 bit within our 4 GiB
+            let Some(&base_addr) = base_addrs.get(bar) else {
+                continue;
+            };
+            let is_64_bit = (base_addr >> 1) & 0b11 == 0b10;
+            if (base_addr & 1) != 0
+                || (is_64_bit && base_addrs.get(bar + 1) != Some(&0))
+            {
+                continue;
+            }
+            let address = (base_addr & !0b1111) + bar_offset;
+
+            // The first of each type is the one to use
+            match cfg_type {
+                capability::COMMON_CFG => {
+                    common = common.or(Some(address));
+                }
+                capability::NOTIFY_CFG if notify.is_none() => {
+                    notify = Some(address);
+                    notify_off_multiplier = device
+                        .read32(offset + capability::NOTIFY_OFF_MULTIPLIER);
+                }
+                capability::ISR_CFG => isr = isr.or(Some(address)),
+                capability::DEVICE_CFG => config = config.or(Some(address)),
+                _ => {}
+            }
+        }
+
+        Some(Self::Modern {
+            common: common?,
+            notify: notify?,
+            notify_off_multiplier,
+            isr: isr?,
+            device: config?,
+        })
+    }
+
+    /// Transitional devices have the legacy registers in an I/O port BAR 0
+    fn legacy(device: &pci::Device) -> Option<Self> {
+        let base_addr = device.base_addrs()[0];
+        if !matches!(device.id(), Id::VirtioNet) || (base_addr & 1) == 0 {
+            return None;
+        }
+
+        Some(Self::Legacy {
+            io_base: (base_addr & !0b11) as u16,
+        })
+    }
+
+    fn header_len(&self) -> usize {
+        match self {
+            Self::Legacy { .. } => LEGACY_HEADER_LEN,
+            Self::Modern { .. } => MODERN_HEADER_LEN,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Self::Legacy { io_base } => {
+                cpu::in8(io_base + legacy::DEVICE_STATUS)
+            }
+            Self::Modern { common, .. } => {
+                mmio_read(common + common_cfg::DEVICE_STATUS)
+            }
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Self::Legacy { io_base } => {
+                cpu::out8(io_base + legacy::DEVICE_STATUS, status)
+            }
+            Self::Modern { common, .. } => {
+                mmio_write(common + common_cfg::DEVICE_STATUS, status)
+            }
+        }
+    }
+
+    fn isr_status(&self) -> u8 {
+        match *self {
+            Self::Legacy { io_base } => cpu::in8(io_base + legacy::ISR_STATUS),
+            Self::Modern { isr, .. } => mmio_read(isr),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Self::Legacy { io_base } => {
+                cpu::in32(io_base + legacy::DEVICE_FEATURES) as u64
+            }
+            Self::Modern { common, .. } => {
+                mmio_write(common + common_cfg::DEVICE_FEATURE_SELECT, 0u32);
+                let low: u32 = mmio_read(common + common_cfg::DEVICE_FEATURE);
+                mmio_write(common + common_cfg::DEVICE_FEATURE_SELECT, 1u32);
+                let high: u32 = mmio_read(common + common_cfg::DEVICE_FEATURE);
+
+                (high as u64) << 32 | low as u64
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Self::Legacy { io_base } => {
+                cpu::out32(io_base + legacy::DRIVER_FEATURES, features as u32)
+            }
+            Self::Modern { common, .. } => {
+                mmio_write(common + common_cfg::DRIVER_FEATURE_SELECT, 0u32);
+                mmio_write(
+                    common + common_cfg::DRIVER_FEATURE,
+                    features as u32,
+                );
+                mmio_write(common + common_cfg::DRIVER_FEATURE_SELECT, 1u32);
+                mmio_write(
+                    common + common_cfg::DRIVER_FEATURE,
+                    (features >> 32) as u32,
+                );
+            }
+        }
+    }
+
+    fn config8(&self, offset: u16) -> u8 {
+        match *self {
+            Self::Legacy { io_base } => {
+                cpu::in8(io_base + legacy::CONFIG + offset)
+            }
+            Self::Modern { device, .. } => mmio_read(device + offset as u32),
+        }
+    }
+
+    /// Allocate queue `index` and hand it to the device, [`None`] if the
+    /// device does not have it
+    fn setup_queue(&self, index: u16) -> Option<Virtqueue> {
+        match *self {
+            Self::Legacy { io_base } => {
+                cpu::out16(io_base + legacy::QUEUE_SELECT, index);
+                // Legacy queues are always the size the device says
+                let size = cpu::in16(io_base + legacy::QUEUE_SIZE);
+                if size == 0 {
+                    return None;
+                }
+
+                let queue = Virtqueue::new(index, size, 0);
+                cpu::out32(
+                    io_base + legacy::QUEUE_ADDRESS,
+                    queue.descs as u32 / PAGE_SIZE as u32,
+                );
+                Some(queue)
+            }
+            Self::Modern {
+                common,
+                notify,
+                notify_off_multiplier,
+                ..
+            } => {
+                mmio_write(common + common_cfg::QUEUE_SELECT, index);
+                let max_size: u16 = mmio_read(common + common_cfg::QUEUE_SIZE);
+                if max_size == 0 {
+                    return None;
+                }
+
+                // Both are powers of 2, as split queues must be
+                let size = max_size.min(BUFFERS);
+                let notify_off: u16 =
+                    mmio_read(common + common_cfg::QUEUE_NOTIFY_OFF);
+                let queue = Virtqueue::new(
+                    index,
+                    size,
+                    notify + notify_off as u32 * notify_off_multiplier,
+                );
+
+                mmio_write(common + common_cfg::QUEUE_SIZE, size);
+                for (register, address) in [
+                    (common_cfg::QUEUE_DESC, queue.descs as u32),
+                    (common_cfg::QUEUE_DRIVER, queue.avail as u32),
+                    (common_cfg::QUEUE_DEVICE, queue.used as u32),
+                ] {
+                    mmio_write(common + register, address);
+                    mmio_write(common + register + 4, 0u32);
+                }
+                mmio_write(common + common_cfg::QUEUE_ENABLE, 1u16);
+
+                Some(queue)
+            }
+        }
+    }
+
+    /// Tell the device there are new buffers in `queue`
+    fn notify(&self, queue: &Virtqueue) {
+        match *self {
+            Self::Legacy { io_base } => {
+                cpu::out16(io_base + legacy::QUEUE_NOTIFY, queue.index)
+            }
+            Self::Modern { .. } => mmio_write(queue.notify, queue.index),
+        }
+    }
+}
+
+/// Split virtqueue descriptor
+#[derive(Debug, Default)]
+#[repr(C)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl Desc {
+    /// The device writes into the buffer rather than reading it
+    const FLAG_WRITE: u16 = 1 << 1;
+}
+
+/// In the available ring flags, asks the device not to interrupt when it
+/// uses a buffer
+const AVAIL_NO_INTERRUPT: u16 = 1 << 0;
+
+/// A split virtqueue with one buffer per descriptor, only the first
+/// [`BUFFERS`] descriptors are ever used
+#[derive(Debug)]
+struct Virtqueue {
+    index: u16,
+    size: u16,
+    descs: *mut Desc,
+    /// Flags, index, then a ring of descriptor IDs
+    avail: *mut u16,
+    /// Flags, index, then a ring of (ID, length) pairs
+    used: *mut u32,
+    buffers: *mut u8,
+    buffer_count: u16,
+    /// Modern notify register, unused on legacy
+    notify: u32,
+    /// Next free entry in the available ring
+    avail_idx: Cell<u16>,
+    /// Next entry in the used ring we have not looked at
+    last_used: Cell<u16>,
+    /// Bit per buffer the device does not own, only used for TX
+    free: Cell<u32>,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify: u32) -> Self {
+        let size_usize = size as usize;
+        let descs_len = core::mem::size_of::<Desc>() * size_usize;
+        let avail_len = 6 + 2 * size_usize;
+        let used_offset = (descs_len + avail_len).next_multiple_of(PAGE_SIZE);
+        let used_len = 6 + 8 * size_usize;
+        let buffer_count = size.min(BUFFERS);
+
+        let (rings, buffers) = unsafe {
+            (
+                alloc_zeroed(
+                    Layout::from_size_align(used_offset + used_len, PAGE_SIZE)
+                        .unwrap(),
+                ),
+                alloc_zeroed(
+                    Layout::from_size_align(
+                        buffer_count as usize * BUFFER_LEN,
+                        PAGE_SIZE,
+                    )
+                    .unwrap(),
+                ),
+            )
+        };
+        assert!(
+            !rings.is_null() && !buffers.is_null(),
+            "Out of memory for virtqueue"
+        );
+
+        let queue = Self {
+            index,
+            size,
+            descs: rings as *mut Desc,
+            avail: unsafe { rings.add(descs_len) } as *mut u16,
+            used: unsafe { rings.add(used_offset) } as *mut u32,
+            buffers,
+            buffer_count,
+            notify,
+            avail_idx: Cell::new(0),
+            last_used: Cell::new(0),
+            free: Cell::new((1 << buffer_count) - 1),
+        };
+
+        for id in 0..buffer_count {
+            let desc = Desc {
+                addr: queue.buffer(id) as u64,
+                len: BUFFER_LEN as u32,
+                ..Default::default()
+            };
+            unsafe { write_volatile(queue.descs.add(id as usize), desc) };
+        }
+
+        queue
+    }
+
+    fn buffer(&self, id: u16) -> *mut u8 {
+        unsafe { self.buffers.add(id as usize * BUFFER_LEN) }
+    }
+
+    fn set_desc(&self, id: u16, len: usize, flags: u16) {
+        let desc = unsafe { &mut *self.descs.add(id as usize) };
+        unsafe {
+            write_volatile(&mut desc.len, len as u32);
+            write_volatile(&mut desc.flags, flags);
+        }
+    }
+
+    /// Give descriptor `id` to the device, it is told with
+    /// [`Transport::notify`]
+    fn push(&self, id: u16) {
+        let avail_idx = self.avail_idx.get();
+        let slot = 2 + (avail_idx % self.size) as usize;
+
+        unsafe {
+            write_volatile(self.avail.add(slot), id);
+            // The ring entry must be visible before the index that covers it
+            write_volatile(self.avail.add(1), avail_idx.wrapping_add(1));
+        }
+        self.avail_idx.set(avail_idx.wrapping_add(1));
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        let flags = if enabled { 0 } else { AVAIL_NO_INTERRUPT };
+        unsafe { write_volatile(self.avail, flags) };
+    }
+
+    /// Take the next descriptor the device has finished with, returning its
+    /// ID and how much the device wrote into it
+    fn pop_used(&self) -> Option<(u16, usize)> {
+        let last_used = self.last_used.get();
+        let used_idx =
+            unsafe { read_volatile((self.used as *const u16).add(1)) };
+        if used_idx == last_used {
+            return None;
+        }
+
+        let slot = 1 + 2 * (last_used % self.size) as usize;
+        let (id, len) = unsafe {
+            (
+                read_volatile(self.used.add(slot)),
+                read_volatile(self.used.add(slot + 1)),
+            )
+        };
+        self.last_used.set(last_used.wrapping_add(1));
+
+        Some((id as u16, len as usize))
+    }
+}
+
+#[derive(Debug)]
+pub struct Driver {
+    transport: Transport,
+    mac_addr: MacAddress,
+    header_len: usize,
+    rx: Virtqueue,
+    tx: Virtqueue,
+}
+
+impl NetworkCard for Driver {
+    fn new(device: &pci::Device) -> Self {
+        // Bus master enable
+        device.enable();
+
+        let transport = Transport::modern(device)
+            .or_else(|| Transport::legacy(device))
+            .expect("Virtio registers are out of reach");
+
+        // Reset, then tell the device we found it and can drive it
+        transport.set_status(0);
+        transport.set_status(status::ACKNOWLEDGE);
+        transport.set_status(status::ACKNOWLEDGE | status::DRIVER);
+
+        let wanted = match transport {
+            Transport::Legacy { .. } => {
+                features::NET_MAC | features::ANY_LAYOUT
+            }
+            Transport::Modern { .. } => features::NET_MAC | features::VERSION_1,
+        };
+        let negotiated = transport.device_features() & wanted;
+        transport.set_driver_features(negotiated);
+
+        // Only the modern interface can refuse what we picked
+        if let Transport::Modern { .. } = transport {
+            transport.set_status(transport.status() | status::FEATURES_OK);
+            if (transport.status() & status::FEATURES_OK) == 0 {
+                transport.set_status(status::FAILED);
+                panic!("Virtio device refused our features");
+            }
+        }
+
+        let mac_addr = if (negotiated & features::NET_MAC) != 0 {
+            core::array::from_fn::<u8, 6, _>(|i| transport.config8(i as u16))
+        } else {
+            DEFAULT_MAC
+        };
+
+        let (Some(rx), Some(tx)) = (
+            transport.setup_queue(RX_QUEUE),
+            transport.setup_queue(TX_QUEUE),
+        ) else {
+            transport.set_status(status::FAILED);
+            panic!("Virtio device is missing its queues");
+        };
+
+        Self {
+            header_len: transport.header_len(),
+            transport,
+            mac_addr: mac_addr.into(),
+            rx,
+            tx,
+        }
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac_addr
+    }
+
+    fn init(&mut self) {
+        // Every receive buffer starts out with the device
+        for id in 0..self.rx.buffer_count {
+            self.rx.set_desc(id, BUFFER_LEN, Desc::FLAG_WRITE);
+            self.rx.push(id);
+        }
+
+        self.transport
+            .set_status(self.transport.status() | status::DRIVER_OK);
+        self.transport.notify(&self.rx);
+    }
+
+    fn receive(&self) -> Option<Frame> {
+        let (id, len) = self.rx.pop_used()?;
+
+        let frame = if len > self.header_len && len <= BUFFER_LEN {
+            let buffer =
+                unsafe { core::slice::from_raw_parts(self.rx.buffer(id), len) };
+            Frame::new(&buffer[self.header_len..], Checksum::Unchecked)
+        } else {
+            None
+        };
+
+        // The descriptor is untouched so it can go straight back
+        self.rx.push(id);
+        self.transport.notify(&self.rx);
+
+        // Too short or long to be a frame, skip to the next
+        frame.or_else(|| self.receive())
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        if self.header_len + frame.len() > BUFFER_LEN {
+            return Err(Error::FrameTooLarge);
+        }
+
+        // Take back the buffers the device has finished sending
+        while let Some((id, _)) = self.tx.pop_used() {
+            self.tx.free.set(self.tx.free.get() | 1 << id);
+        }
+
+        let free = self.tx.free.get();
+        if free == 0 {
+            return Err(Error::TransmitQueueFull);
+        }
+        let id = free.trailing_zeros() as u16;
+        self.tx.free.set(free & !(1 << id));
+
+        // No offloads so the header is all zero
+        let len = self.header_len + frame.len();
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(self.tx.buffer(id), len) };
+        buffer[..self.header_len].fill(0);
+        buffer[self.header_len..].copy_from_slice(frame);
+
+        self.tx.set_desc(id, len, 0);
+        self.tx.push(id);
+        self.transport.notify(&self.tx);
+
+        Ok(())
+    }
+
+    fn acknowledge_interrupt(&self) {
+        // Reading the ISR status deasserts the interrupt, used buffers are
+        // picked up by polling the rings so the cause does not matter
+        self.transport.isr_status();
+    }
+
+    fn set_interrupts(&self, enabled: bool) {
+        self.rx.set_interrupts(enabled);
+        self.tx.set_interrupts(enabled);
+    }
+}
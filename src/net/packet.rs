@@ -1,15 +1,20 @@
-use crate::error::Error;
+use crate::error::{Error, Result};
 
-use super::{arp::Arp, nic::MacAddress, Serialise};
+use super::{
+    arp::Arp, ipv4::Ipv4, ipv6::Ipv6, nic::MacAddress, Endianness, Serialise,
+};
 
-#[derive(Debug)]
-enum EtherType {
+#[derive(Debug, Clone, Copy)]
+pub(super) enum EtherType {
     /// 0x0800
     IPv4,
 
     /// 0x0806
     Arp,
 
+    /// 0x8100, an 802.1Q tag follows
+    Vlan,
+
     /// 0x86DD
     IPv6,
 
@@ -25,6 +30,7 @@ impl From<[u8; 2]> for EtherType {
         match value {
             [0x08, 0x00] => Self::IPv4,
             [0x08, 0x06] => Self::Arp,
+            [0x81, 0x00] => Self::Vlan,
             [0x86, 0xDD] => Self::IPv6,
             [0x88, 0xE1] => Self::HomePlugAV,
             _ => Self::Unknown(value),
@@ -32,66 +38,252 @@ impl From<[u8; 2]> for EtherType {
     }
 }
 
-#[allow(dead_code)]
+impl From<EtherType> for [u8; 2] {
+    fn from(value: EtherType) -> Self {
+        match value {
+            EtherType::IPv4 => [0x08, 0x00],
+            EtherType::Arp => [0x08, 0x06],
+            EtherType::Vlan => [0x81, 0x00],
+            EtherType::IPv6 => [0x86, 0xDD],
+            EtherType::HomePlugAV => [0x88, 0xE1],
+            EtherType::Unknown(value) => value,
+        }
+    }
+}
+
+/// Ethernet header with an optional 802.1Q tag
+/// [https://standards.ieee.org/ieee/802.1Q/10323/], the tag is dealt with here
+/// rather than by the NIC so it works the same on every card
 #[derive(Debug)]
-struct Ethernet {
+pub(super) struct Ethernet {
     dst_mac: MacAddress,
     src_mac: MacAddress,
+    /// Tag control information, [`None`] for untagged frames. Kept whole so
+    /// the priority of a received frame is not lost
+    tci: Option<u16>,
+    /// Type of the payload, never [`EtherType::Vlan`]
     ether_type: EtherType,
 }
 
 impl Ethernet {
     const LEN: usize = 14;
+    const TAG_LEN: usize = 4;
+
+    /// The VLAN ID is the low 12 bits of the tag control information, the
+    /// rest is priority which we leave at 0 when sending
+    const VLAN_ID_MASK: u16 = 0x0FFF;
+
+    fn len(&self) -> usize {
+        match self.tci {
+            Some(_) => Self::LEN + Self::TAG_LEN,
+            None => Self::LEN,
+        }
+    }
 }
 
-impl Serialise for Ethernet {
-    fn deserialise(buffer: &[u8]) -> Result<Self, Error> {
+impl Serialise<'_> for Ethernet {
+    fn deserialise(buffer: &[u8]) -> Result<Self> {
+        if buffer.len() < Self::LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
         let mut ptr = 0;
+        let dst_mac = consume!(ptr, buffer, [u8; 6]).into();
+        let src_mac = consume!(ptr, buffer, [u8; 6]).into();
+        let mut ether_type = consume!(ptr, buffer, [u8; 2]).into();
+
+        let mut tci = None;
+        if let EtherType::Vlan = ether_type {
+            if buffer.len() < Self::LEN + Self::TAG_LEN {
+                return Err(Error::CouldNotParsePacket);
+            }
+
+            tci = Some(consume!(ptr, buffer, Endianness::Big, u16));
+            ether_type = consume!(ptr, buffer, [u8; 2]).into();
+        }
+
         Ok(Self {
-            dst_mac: consume!(ptr, buffer, [u8; 6]).into(),
-            src_mac: consume!(ptr, buffer, [u8; 6]).into(),
-            ether_type: consume!(ptr, buffer, [u8; 2]).into(),
+            dst_mac,
+            src_mac,
+            tci,
+            ether_type,
         })
     }
 
-    fn serialise(&self, _: &mut [u8]) {
-        todo!()
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = 0;
+
+        emit!(ptr, buffer, [u8; 6], self.dst_mac.into());
+        emit!(ptr, buffer, [u8; 6], self.src_mac.into());
+        if let Some(tci) = self.tci {
+            emit!(ptr, buffer, [u8; 2], EtherType::Vlan.into());
+            emit!(ptr, buffer, Endianness::Big, u16, tci);
+        }
+        emit!(ptr, buffer, [u8; 2], self.ether_type.into());
+
+        ptr
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
-enum Protocol {
+pub(super) enum Protocol<'a> {
     Arp(Arp),
+    Ipv4(Ipv4<'a>),
+    Ipv6(Ipv6<'a>),
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
-pub(super) struct Packet {
+pub(super) struct Packet<'a> {
     ethernet: Ethernet,
-    protocol: Protocol,
+    pub(super) protocol: Protocol<'a>,
+    /// Whatever followed the protocol in the frame, usually padding up to
+    /// the 60 byte minimum. Kept so a parsed frame serialises back to the
+    /// same bytes, packets we build have none
+    trailer: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Largest frame we will build, excluding the FCS
+    const MAX_LEN: usize = 1514 + Ethernet::TAG_LEN;
+
+    /// Build a packet sent from our MAC address, tagged if we are on a VLAN
+    pub(super) fn new(
+        dst_mac: MacAddress,
+        ether_type: EtherType,
+        protocol: Protocol<'a>,
+    ) -> Self {
+        Self {
+            ethernet: Ethernet {
+                dst_mac,
+                src_mac: super::mac(),
+                tci: super::vlan_id(),
+                ether_type,
+            },
+            protocol,
+            trailer: &[],
+        }
+    }
+
+    pub(super) fn src_mac(&self) -> MacAddress {
+        self.ethernet.src_mac
+    }
+
+    /// VLAN ID from the tag, [`None`] if the frame was untagged
+    pub(super) fn vlan(&self) -> Option<u16> {
+        self.ethernet.tci.map(|tci| tci & Ethernet::VLAN_ID_MASK)
+    }
+
+    /// Serialise and put the packet on the wire
+    pub(super) fn send(&self) -> Result<()> {
+        let mut buffer = [0u8; Self::MAX_LEN];
+        let len = self.serialise(&mut buffer);
+
+        super::nic().send(&buffer[..len])?;
+        super::pcap::capture(&buffer[..len]);
+
+        Ok(())
+    }
 }
 
-impl Serialise for Packet {
-    fn deserialise(buffer: &[u8]) -> Result<Self, Error> {
-        let ethernet = Ethernet::deserialise(&buffer[..Ethernet::LEN])?;
+impl<'a> Serialise<'a> for Packet<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        let ethernet = Ethernet::deserialise(buffer)?;
+        let payload = &buffer[ethernet.len()..];
 
-        match &ethernet.ether_type {
+        let (protocol, len) = match &ethernet.ether_type {
             EtherType::Arp => {
-                let arp = Arp::deserialise(
-                    &buffer[Ethernet::LEN..Ethernet::LEN + Arp::LEN],
-                )?;
-
-                Ok(Self {
-                    ethernet,
-                    protocol: Protocol::Arp(arp),
-                })
+                if payload.len() < Arp::LEN {
+                    return Err(Error::CouldNotParsePacket);
+                }
+                let arp = Arp::deserialise(&payload[..Arp::LEN])?;
+
+                (Protocol::Arp(arp), Arp::LEN)
             }
-            _ => Err(Error::CouldNotParsePacket),
-        }
+            EtherType::IPv4 => {
+                let ipv4 = Ipv4::deserialise(payload)?;
+                let len = ipv4.len();
+
+                (Protocol::Ipv4(ipv4), len)
+            }
+            EtherType::IPv6 => {
+                let ipv6 = Ipv6::deserialise(payload)?;
+                let len = ipv6.len();
+
+                (Protocol::Ipv6(ipv6), len)
+            }
+            _ => return Err(Error::CouldNotParsePacket),
+        };
+
+        Ok(Self {
+            ethernet,
+            protocol,
+            trailer: &payload[len..],
+        })
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = self.ethernet.serialise(buffer);
+
+        ptr += match &self.protocol {
+            Protocol::Arp(arp) => arp.serialise(&mut buffer[ptr..]),
+            Protocol::Ipv4(ipv4) => ipv4.serialise(&mut buffer[ptr..]),
+            Protocol::Ipv6(ipv6) => ipv6.serialise(&mut buffer[ptr..]),
+        };
+        emit!(ptr, buffer, [u8], self.trailer);
+
+        ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_arp_round_trips() {
+        let mut frame = [0u8; 60];
+        frame[..14].copy_from_slice(&[
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Broadcast
+            0x52, 0x54, 0x00, 0x12, 0x34, 0x56, // Sender
+            0x08, 0x06, // ARP
+        ]);
+        frame[14..42].copy_from_slice(&[
+            0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01, // Request
+            0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 192, 168, 0, 1, // Sender
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 192, 168, 0, 2, // Target
+        ]);
+        // Padding is meant to be zero, but whatever is there comes back
+        frame[42..].fill(0x5A);
+
+        let packet = Packet::deserialise(&frame).unwrap();
+        assert!(matches!(packet.protocol, Protocol::Arp(_)));
+
+        let mut buffer = [0u8; Packet::MAX_LEN];
+        let len = packet.serialise(&mut buffer);
+        assert_eq!(buffer[..len], frame);
     }
 
-    fn serialise(&self, _: &mut [u8]) {
-        todo!()
+    #[test]
+    fn priority_tagged_arp_round_trips() {
+        let mut frame = [0u8; 64];
+        frame[..18].copy_from_slice(&[
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Broadcast
+            0x52, 0x54, 0x00, 0x12, 0x34, 0x56, // Sender
+            0x81, 0x00, 0xB0, 0x00, // Priority 5, drop eligible, VLAN 0
+            0x08, 0x06, // ARP
+        ]);
+        frame[18..46].copy_from_slice(&[
+            0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01, // Request
+            0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 192, 168, 0, 1, // Sender
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 192, 168, 0, 2, // Target
+        ]);
+
+        let packet = Packet::deserialise(&frame).unwrap();
+        assert!(matches!(packet.protocol, Protocol::Arp(_)));
+        assert_eq!(packet.vlan(), Some(0));
+
+        let mut buffer = [0u8; Packet::MAX_LEN];
+        let len = packet.serialise(&mut buffer);
+        assert_eq!(buffer[..len], frame);
     }
 }
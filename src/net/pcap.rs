@@ -0,0 +1,77 @@
+//! Capture of every frame we send and receive as a pcap stream
+//! [https://www.ietf.org/archive/id/draft-ietf-opsawg-pcap-04.html] on COM1,
+//! point QEMU at it with `-serial file:capture.pcap` and open that in
+//! Wireshark. Nothing else may write to the serial port while capturing
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{pit, serial};
+
+use super::Endianness;
+
+/// Written in our byte order so readers can tell which that is
+const MAGIC: u32 = 0xA1B2_C3D4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+/// Longest frame that could be recorded, ours are far shorter
+const SNAP_LEN: u32 = 0xFFFF;
+/// LINKTYPE_ETHERNET
+const LINK_TYPE: u32 = 1;
+
+const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+/// Held while a record is going out, so no other record lands in the
+/// middle of it
+static WRITING: AtomicBool = AtomicBool::new(false);
+
+/// Write the file header and start recording every frame. Timestamps are
+/// from [`pit::ticks`] so count from boot rather than the epoch
+pub fn start_capture() {
+    serial::init();
+
+    let mut header = [0u8; FILE_HEADER_LEN];
+    let mut ptr = 0;
+    emit!(ptr, header, Endianness::Little, u32, MAGIC);
+    emit!(ptr, header, Endianness::Little, u16, VERSION_MAJOR);
+    emit!(ptr, header, Endianness::Little, u16, VERSION_MINOR);
+    // Time zone offset and timestamp accuracy, both unused
+    emit!(ptr, header, Endianness::Little, u32, 0);
+    emit!(ptr, header, Endianness::Little, u32, 0);
+    emit!(ptr, header, Endianness::Little, u32, SNAP_LEN);
+    emit!(ptr, header, Endianness::Little, u32, LINK_TYPE);
+
+    serial::write(&header[..ptr]);
+    CAPTURING.store(true, Ordering::SeqCst);
+}
+
+/// Record one complete frame if we are capturing
+pub(super) fn capture(frame: &[u8]) {
+    if !CAPTURING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let ticks = pit::ticks();
+    let seconds = (ticks / 1000) as u32;
+    let microseconds = (ticks % 1000) as u32 * 1000;
+
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    let mut ptr = 0;
+    emit!(ptr, header, Endianness::Little, u32, seconds);
+    emit!(ptr, header, Endianness::Little, u32, microseconds);
+    // Captured and original length, we always have the whole frame
+    emit!(ptr, header, Endianness::Little, u32, frame.len() as u32);
+    emit!(ptr, header, Endianness::Little, u32, frame.len() as u32);
+
+    // A whole frame takes over 100 ms at 115200 baud, far too long to hold
+    // off interrupts for. Frames are only captured by the stack, never from
+    // an interrupt, so this should never be taken, but if it is the record
+    // is dropped rather than split
+    if WRITING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    serial::write(&header[..ptr]);
+    serial::write(frame);
+    WRITING.store(false, Ordering::Release);
+}
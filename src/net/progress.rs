@@ -0,0 +1,94 @@
+//! Live download progress on a single console line
+
+use core::fmt::Write;
+
+use alloc::string::String;
+
+use crate::pit;
+
+/// Don't spend all our time drawing
+const REDRAW_INTERVAL_MS: u64 = 100;
+/// Characters in the bar between the brackets
+const BAR_WIDTH: usize = 20;
+
+pub(super) struct Progress {
+    /// Tick the download started
+    start: u64,
+    /// Tick we last drew the line
+    last_draw: u64,
+    received: usize,
+    /// Size of the download if we know it
+    total: Option<usize>,
+    retransmits: u32,
+}
+
+impl Progress {
+    pub(super) fn new() -> Self {
+        let now = pit::ticks();
+
+        Self {
+            start: now,
+            last_draw: now,
+            received: 0,
+            total: None,
+            retransmits: 0,
+        }
+    }
+
+    pub(super) fn set_total(&mut self, total: usize) {
+        self.total = Some(total);
+    }
+
+    /// Record the bytes received so far, redrawing if it has been a while
+    pub(super) fn update(&mut self, received: usize) {
+        self.received = received;
+
+        let now = pit::ticks();
+        if now - self.last_draw >= REDRAW_INTERVAL_MS {
+            self.last_draw = now;
+            self.draw();
+        }
+    }
+
+    pub(super) fn retransmit(&mut self) {
+        self.retransmits += 1;
+        self.draw();
+    }
+
+    /// Draw the final state and move off the progress line
+    pub(super) fn finish(&self) {
+        self.draw();
+        print!("\n");
+    }
+
+    fn draw(&self) {
+        // Avoid dividing by zero in the first millisecond
+        let elapsed_ms = (pit::ticks() - self.start).max(1);
+        let kib_per_sec = self.received as u64 * 1000 / elapsed_ms / 1024;
+
+        // Built up first so the console is written once per redraw
+        let mut line = String::from("\r");
+        if let Some(total) = self.total.filter(|&total| total != 0) {
+            let percent = (self.received * 100 / total).min(100);
+            let filled = percent * BAR_WIDTH / 100;
+
+            _ = write!(
+                line,
+                "[{:<width$}] {:>3}% {}/{} bytes",
+                "#".repeat(filled),
+                percent,
+                self.received,
+                total,
+                width = BAR_WIDTH
+            );
+        } else {
+            _ = write!(line, "{} bytes", self.received);
+        }
+        _ = write!(line, " {} KiB/s", kib_per_sec);
+        if self.retransmits != 0 {
+            _ = write!(line, " {} retransmits", self.retransmits);
+        }
+
+        print!("{}", line);
+    }
+}
@@ -0,0 +1,1022 @@
+//! Minimal TCP client [https://www.rfc-editor.org/rfc/rfc9293]
+//!
+//! Segments are handled by [`super::poll`], which acknowledges data straight
+//! away and queues it on the connection. Retransmission timers are driven by
+//! whoever is blocked on the connection polling it against [`pit::ticks`]
+
+use core::{
+    net::IpAddr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use alloc::collections::VecDeque;
+
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pit,
+};
+
+use super::{
+    checksum_add, checksum_finish, ip,
+    ipv4::{IpProtocol, Ipv4},
+    nic::MacAddress,
+    Endianness, Serialise,
+};
+
+/// How many connections can be open at once
+const MAX_CONNECTIONS: usize = 4;
+/// Bytes we buffer for the reader, this is also the largest window we offer
+const RX_BUFFER_LEN: usize = 32 * 1024;
+/// Bytes we buffer for the writer until they are acknowledged
+const TX_BUFFER_LEN: usize = 8 * 1024;
+/// Start of the IANA dynamic port range we pick ephemeral ports from
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Largest segment we can receive without fragmentation, over IPv4 which
+/// has the smaller header
+const MSS: usize = Ipv4::MAX_PAYLOAD_LEN - Tcp::MIN_LEN;
+/// Segment size to assume if the peer does not tell us [RFC 9293 3.7.1]
+const DEFAULT_MSS: usize = 536;
+
+const INITIAL_RTO_MS: u64 = 1_000;
+const MAX_RTO_MS: u64 = 16_000;
+/// Give up once the same segment has been sent this many more times
+const MAX_RETRANSMITS: u32 = 6;
+/// How long a read waits for data before giving up
+const READ_TIMEOUT_MS: u64 = 30_000;
+/// How long dropping a stream waits for the close handshake
+const CLOSE_TIMEOUT_MS: u64 = 2_000;
+
+/// Next ephemeral port to hand out
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORT_START);
+
+/// Slots are allocated the first time they are needed and then reused, as the
+/// allocator never gives memory back
+static mut CONNECTIONS: [Option<Connection>; MAX_CONNECTIONS] = {
+    const EMPTY: Option<Connection> = None;
+    [EMPTY; MAX_CONNECTIONS]
+};
+
+mod flags {
+    pub const FIN: u8 = 1 << 0;
+    pub const SYN: u8 = 1 << 1;
+    pub const RST: u8 = 1 << 2;
+    pub const PSH: u8 = 1 << 3;
+    pub const ACK: u8 = 1 << 4;
+}
+
+mod option {
+    pub const END: u8 = 0;
+    pub const NOP: u8 = 1;
+    pub const MSS: u8 = 2;
+}
+
+/// `a` comes before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// TCP segment, the checksum is not stored as it is validated on receive and
+/// calculated on send
+#[derive(Debug)]
+struct Tcp<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    options: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> Tcp<'a> {
+    const MIN_LEN: usize = 20;
+    const CHECKSUM_OFFSET: usize = 16;
+
+    /// Sequence space the segment takes up, SYN and FIN count as one each
+    fn seq_len(&self) -> u32 {
+        self.payload.len() as u32
+            + (self.flags & flags::SYN != 0) as u32
+            + (self.flags & flags::FIN != 0) as u32
+    }
+
+    /// Maximum segment size the sender will accept, if it told us
+    fn mss(&self) -> Option<usize> {
+        let mut ptr = 0;
+        while let Some(&kind) = self.options.get(ptr) {
+            match kind {
+                option::END => break,
+                option::NOP => ptr += 1,
+                kind => {
+                    let len = *self.options.get(ptr + 1)? as usize;
+                    if len < 2 {
+                        return None;
+                    }
+                    if kind == option::MSS && len == 4 {
+                        let value = self.options.get(ptr + 2..ptr + 4)?;
+                        return Some(
+                            u16::from_be_bytes([value[0], value[1]]) as usize
+                        );
+                    }
+                    ptr += len;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Serialise<'a> for Tcp<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::MIN_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        let src_port = consume!(ptr, buffer, Endianness::Big, u16);
+        let dst_port = consume!(ptr, buffer, Endianness::Big, u16);
+        let seq = consume!(ptr, buffer, Endianness::Big, u32);
+        let ack = consume!(ptr, buffer, Endianness::Big, u32);
+        let header_len = (consume!(ptr, buffer, u8) >> 4) as usize * 4;
+        let flags = consume!(ptr, buffer, u8);
+        let window = consume!(ptr, buffer, Endianness::Big, u16);
+        let _checksum = consume!(ptr, buffer, Endianness::Big, u16);
+        let _urgent_pointer = consume!(ptr, buffer, Endianness::Big, u16);
+
+        if header_len < Self::MIN_LEN || header_len > buffer.len() {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        Ok(Self {
+            src_port,
+            dst_port,
+            seq,
+            ack,
+            flags,
+            window,
+            options: consume!(ptr, buffer, [u8], header_len - Self::MIN_LEN),
+            payload: &buffer[header_len..],
+        })
+    }
+
+    /// The checksum is left as zero, it needs the IP pseudo header so is
+    /// filled in by [`send`]. Options must be padded to a multiple of 4
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let header_len = Self::MIN_LEN + self.options.len();
+
+        let mut ptr = 0;
+        emit!(ptr, buffer, Endianness::Big, u16, self.src_port);
+        emit!(ptr, buffer, Endianness::Big, u16, self.dst_port);
+        emit!(ptr, buffer, Endianness::Big, u32, self.seq);
+        emit!(ptr, buffer, Endianness::Big, u32, self.ack);
+        emit!(ptr, buffer, u8, ((header_len / 4) as u8) << 4);
+        emit!(ptr, buffer, u8, self.flags);
+        emit!(ptr, buffer, Endianness::Big, u16, self.window);
+        emit!(ptr, buffer, Endianness::Big, u16, 0);
+        emit!(ptr, buffer, Endianness::Big, u16, 0);
+        emit!(ptr, buffer, [u8], self.options);
+        emit!(ptr, buffer, [u8], self.payload);
+
+        ptr
+    }
+}
+
+/// Build and send a single segment
+fn send(dst_mac: MacAddress, dst_ip: IpAddr, tcp: &Tcp) -> Result<()> {
+    let mut buffer = [0u8; Ipv4::MAX_PAYLOAD_LEN];
+    let len = tcp.serialise(&mut buffer);
+
+    let pseudo_header = ip::pseudo_header_sum(
+        ip::source_address(dst_ip),
+        dst_ip,
+        IpProtocol::Tcp,
+        len,
+    );
+    let tcp_checksum =
+        checksum_finish(checksum_add(pseudo_header, &buffer[..len]));
+    buffer[Tcp::CHECKSUM_OFFSET..Tcp::CHECKSUM_OFFSET + 2]
+        .copy_from_slice(&tcp_checksum.to_be_bytes());
+
+    ip::send_to_mac(dst_mac, dst_ip, IpProtocol::Tcp, &buffer[..len])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Established,
+    /// We sent our FIN
+    FinWait1,
+    /// Our FIN was acknowledged, waiting on theirs
+    FinWait2,
+    /// Both sides sent a FIN at once
+    Closing,
+    /// Both FINs are acknowledged. We do not linger here as we do not reuse
+    /// ports, a retransmitted FIN after the slot is freed just gets a reset
+    TimeWait,
+    /// They sent a FIN, we can still send
+    CloseWait,
+    /// We sent our FIN after theirs
+    LastAck,
+    /// Finished, if it was not clean [`Connection::error`] says why
+    Closed,
+}
+
+struct Connection {
+    /// Local port, [`None`] if the slot is free
+    local_port: Option<u16>,
+    remote_ip: IpAddr,
+    remote_port: u16,
+    /// Next hop, resolved once when connecting so incoming segments can be
+    /// answered without waiting
+    remote_mac: MacAddress,
+    state: State,
+    error: Option<Error>,
+
+    /// Oldest unacknowledged sequence number
+    snd_una: u32,
+    /// Next sequence number we send
+    snd_nxt: u32,
+    /// Window the peer last offered
+    snd_wnd: usize,
+    /// Largest segment the peer accepts
+    mss: usize,
+    /// Data from `snd_una` onwards, both sent and not yet sent
+    tx: VecDeque<u8>,
+    /// The writer is done, send a FIN once `tx` is drained
+    fin_queued: bool,
+    /// Our FIN has been given a sequence number
+    fin_sent: bool,
+    /// When to resend the oldest unacknowledged segment
+    retransmit_at: Option<u64>,
+    rto: u64,
+    retransmits: u32,
+
+    /// Next sequence number we expect
+    rcv_nxt: u32,
+    /// Window we last put in a segment
+    rcv_wnd: usize,
+    /// Data waiting for the reader
+    rx: VecDeque<u8>,
+    /// They sent a FIN, the reader gets end of stream once `rx` is drained
+    fin_received: bool,
+}
+
+impl Connection {
+    fn matches(&self, local_port: u16, ip: IpAddr, port: u16) -> bool {
+        self.local_port == Some(local_port)
+            && self.remote_ip == ip
+            && self.remote_port == port
+    }
+
+    /// Largest segment we can take from the peer
+    fn local_mss(&self) -> usize {
+        ip::max_payload_len(self.remote_ip) - Tcp::MIN_LEN
+    }
+
+    fn receive_window(&self) -> usize {
+        RX_BUFFER_LEN - self.rx.len()
+    }
+
+    /// Bytes of `tx` that have been sent but not acknowledged
+    fn data_in_flight(&self) -> usize {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        match self.state {
+            State::SynSent => 0,
+            _ => {
+                in_flight
+                    - (self.fin_sent && self.snd_nxt != self.snd_una) as usize
+            }
+        }
+    }
+
+    /// Send a segment to the peer, acknowledging everything we have received
+    fn send_segment(&mut self, seq: u32, flags: u8, payload: &[u8]) {
+        // The only option we send is our MSS, and only on our SYN
+        let [mss_high, mss_low] = (self.local_mss() as u16).to_be_bytes();
+        let mss_option = [option::MSS, 4, mss_high, mss_low];
+        let options: &[u8] = if flags & flags::SYN != 0 {
+            &mss_option
+        } else {
+            &[]
+        };
+
+        let window = self.receive_window().min(u16::MAX as usize);
+        self.rcv_wnd = window;
+
+        let tcp = Tcp {
+            src_port: self.local_port.unwrap_or(0),
+            dst_port: self.remote_port,
+            seq,
+            ack: self.rcv_nxt,
+            flags: flags
+                | if self.state == State::SynSent {
+                    0
+                } else {
+                    flags::ACK
+                },
+            window: window as u16,
+            options,
+            payload,
+        };
+        // Losing a segment to a full transmit queue is recovered like any
+        // other loss
+        let _ = send(self.remote_mac, self.remote_ip, &tcp);
+    }
+
+    fn send_ack(&mut self) {
+        self.send_segment(self.snd_nxt, 0, &[]);
+    }
+
+    /// Send `len` bytes of `tx` from `offset` as one segment
+    fn send_data(&mut self, offset: usize, len: usize) {
+        self.tx.make_contiguous();
+        let mut data = [0u8; MSS];
+        data[..len]
+            .copy_from_slice(&self.tx.as_slices().0[offset..offset + len]);
+
+        let seq = self.snd_una.wrapping_add(offset as u32);
+        let push = if offset + len == self.tx.len() {
+            flags::PSH
+        } else {
+            0
+        };
+        self.send_segment(seq, push, &data[..len]);
+    }
+
+    fn arm_timer(&mut self) {
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(pit::ticks() + self.rto);
+        }
+    }
+
+    fn abort(&mut self, error: Error) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.retransmit_at = None;
+    }
+
+    /// Send as much new data as the peer's window allows, then our FIN if the
+    /// writer is done
+    fn transmit(&mut self) {
+        if !matches!(self.state, State::Established | State::CloseWait) {
+            return;
+        }
+
+        loop {
+            let in_flight = self.data_in_flight();
+            let unsent = self.tx.len() - in_flight;
+            let len = unsent
+                .min(self.snd_wnd.saturating_sub(in_flight))
+                .min(self.mss);
+
+            if len == 0 {
+                if unsent == 0 && self.fin_queued {
+                    self.send_segment(self.snd_nxt, flags::FIN, &[]);
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                    self.fin_sent = true;
+                    self.state = match self.state {
+                        State::CloseWait => State::LastAck,
+                        _ => State::FinWait1,
+                    };
+                    self.arm_timer();
+                } else if unsent != 0 && in_flight == 0 {
+                    // Zero window, the timer sends a probe
+                    self.arm_timer();
+                }
+                return;
+            }
+
+            self.send_data(in_flight, len);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            self.arm_timer();
+        }
+    }
+
+    /// Check the retransmission timer, resending the oldest unacknowledged
+    /// segment with exponential backoff if it has fired
+    fn poll(&mut self) {
+        let Some(retransmit_at) = self.retransmit_at else {
+            return self.transmit();
+        };
+        if pit::ticks() < retransmit_at {
+            return;
+        }
+
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            return self.abort(Error::TcpTimeout);
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO_MS);
+        self.retransmit_at = Some(pit::ticks() + self.rto);
+
+        let in_flight = self.data_in_flight();
+        if self.state == State::SynSent {
+            self.send_segment(self.snd_una, flags::SYN, &[]);
+        } else if in_flight != 0 {
+            self.send_data(0, in_flight.min(self.mss));
+        } else if self.fin_sent && self.snd_nxt != self.snd_una {
+            self.send_segment(self.snd_una, flags::FIN, &[]);
+        } else if !self.tx.is_empty() {
+            // Probe a zero window with one byte, the peer will either take it
+            // or tell us the window again
+            self.send_data(0, 1);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        } else {
+            self.retransmit_at = None;
+        }
+    }
+
+    /// Process a segment for this connection
+    fn receive(&mut self, tcp: &Tcp) {
+        if self.state == State::SynSent {
+            return self.receive_syn_sent(tcp);
+        }
+        if self.state == State::Closed {
+            return;
+        }
+
+        if tcp.flags & flags::RST != 0 {
+            // Only believe a reset that is exactly in sequence [RFC 5961 3.2]
+            if tcp.seq == self.rcv_nxt {
+                self.abort(Error::TcpConnectionReset);
+            }
+            return;
+        }
+
+        // Only accept segments that start at or before where we expect, we
+        // do not keep anything that arrives out of order
+        let offset = self.rcv_nxt.wrapping_sub(tcp.seq);
+        if (offset as i32) < 0 || offset as usize > tcp.payload.len() {
+            // Tell them where we are so they resend from there
+            return self.send_ack();
+        }
+
+        if tcp.flags & flags::ACK == 0 {
+            return;
+        }
+        self.receive_ack(tcp);
+
+        // New data, only as much as fits as we never offered more than that
+        let new = &tcp.payload[offset as usize..];
+        let len = new.len().min(self.receive_window());
+        if !self.fin_received {
+            self.rx.extend(&new[..len]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+        }
+
+        if tcp.flags & flags::FIN != 0 && len == new.len() && !self.fin_received
+        {
+            self.fin_received = true;
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.state = match self.state {
+                State::Established => State::CloseWait,
+                State::FinWait1 => State::Closing,
+                State::FinWait2 => State::TimeWait,
+                state => state,
+            };
+        }
+
+        // Acknowledge anything that takes up sequence space, even if we had
+        // it already as our last acknowledgement may have been lost
+        if tcp.seq_len() != 0 {
+            self.send_ack();
+        }
+        self.transmit();
+    }
+
+    fn receive_syn_sent(&mut self, tcp: &Tcp) {
+        let ack_ok = tcp.flags & flags::ACK != 0 && tcp.ack == self.snd_nxt;
+
+        if tcp.flags & flags::ACK != 0 && !ack_ok {
+            if tcp.flags & flags::RST == 0 {
+                reset(self.remote_mac, self.remote_ip, tcp);
+            }
+            return;
+        }
+        if tcp.flags & flags::RST != 0 {
+            if ack_ok {
+                self.abort(Error::TcpConnectionRefused);
+            }
+            return;
+        }
+        if tcp.flags & flags::SYN == 0 || !ack_ok {
+            return;
+        }
+
+        self.rcv_nxt = tcp.seq.wrapping_add(1);
+        self.snd_una = tcp.ack;
+        self.snd_wnd = tcp.window as usize;
+        self.mss = tcp.mss().unwrap_or(DEFAULT_MSS).min(self.local_mss());
+        self.state = State::Established;
+        self.retransmit_at = None;
+        self.retransmits = 0;
+        self.rto = INITIAL_RTO_MS;
+
+        self.send_ack();
+    }
+
+    fn receive_ack(&mut self, tcp: &Tcp) {
+        if seq_lt(self.snd_nxt, tcp.ack) {
+            // Acknowledging something we never sent
+            return self.send_ack();
+        }
+        if seq_le(tcp.ack, self.snd_una) {
+            // Duplicate, but the window may have changed
+            if tcp.ack == self.snd_una {
+                self.snd_wnd = tcp.window as usize;
+            }
+            return;
+        }
+
+        let acked = tcp.ack.wrapping_sub(self.snd_una) as usize;
+        let fin_acked = self.fin_sent && tcp.ack == self.snd_nxt;
+        self.tx.drain(..acked.min(self.tx.len()));
+        self.snd_una = tcp.ack;
+        self.snd_wnd = tcp.window as usize;
+
+        // Progress, start the timer again from the beginning
+        self.rto = INITIAL_RTO_MS;
+        self.retransmits = 0;
+        self.retransmit_at = None;
+        if self.snd_una != self.snd_nxt {
+            self.arm_timer();
+        }
+
+        if fin_acked {
+            self.state = match self.state {
+                State::FinWait1 => State::FinWait2,
+                State::Closing => State::TimeWait,
+                State::LastAck => State::Closed,
+                state => state,
+            };
+        }
+    }
+}
+
+/// Reset a segment that does not belong to any connection
+fn reset(dst_mac: MacAddress, dst_ip: IpAddr, tcp: &Tcp) {
+    let (seq, ack, reset_flags) = if tcp.flags & flags::ACK != 0 {
+        (tcp.ack, 0, flags::RST)
+    } else {
+        (
+            0,
+            tcp.seq.wrapping_add(tcp.seq_len()),
+            flags::RST | flags::ACK,
+        )
+    };
+
+    let _ = send(
+        dst_mac,
+        dst_ip,
+        &Tcp {
+            src_port: tcp.dst_port,
+            dst_port: tcp.src_port,
+            seq,
+            ack,
+            flags: reset_flags,
+            window: 0,
+            options: &[],
+            payload: &[],
+        },
+    );
+}
+
+/// Called for every TCP segment we receive
+pub(super) fn handle(
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    buffer: &[u8],
+    src_mac: MacAddress,
+) {
+    let Ok(tcp) = Tcp::deserialise(buffer) else {
+        return;
+    };
+
+    let pseudo_header =
+        ip::pseudo_header_sum(src_ip, dst_ip, IpProtocol::Tcp, buffer.len());
+    if checksum_finish(checksum_add(pseudo_header, buffer)) != 0 {
+        return;
+    }
+
+    cpu::without_interrupts(|| unsafe {
+        match CONNECTIONS.iter_mut().flatten().find(|connection| {
+            connection.matches(tcp.dst_port, src_ip, tcp.src_port)
+        }) {
+            Some(connection) => connection.receive(&tcp),
+            // Never answer a reset with a reset
+            None if tcp.flags & flags::RST == 0 => reset(src_mac, src_ip, &tcp),
+            None => {}
+        }
+    })
+}
+
+/// A connection to a remote host, closed when dropped
+#[derive(Debug)]
+pub struct TcpStream {
+    port: u16,
+}
+
+impl TcpStream {
+    /// Open a connection, blocking until the handshake is done
+    pub fn connect(ip: IpAddr, port: u16) -> Result<Self> {
+        let remote_mac = ip::route(ip)?;
+
+        let stream = cpu::without_interrupts(|| unsafe {
+            let local_port = Self::ephemeral_port();
+            // Clock driven, like RFC 9293 3.4.1 without the hash
+            let iss = (pit::ticks() as u32).wrapping_mul(250);
+
+            // Prefer reusing a slot that already has its buffers allocated
+            let index = CONNECTIONS
+                .iter()
+                .position(|slot| {
+                    slot.as_ref().is_some_and(|connection| {
+                        connection.local_port.is_none()
+                    })
+                })
+                .or_else(|| CONNECTIONS.iter().position(|slot| slot.is_none()))
+                .ok_or(Error::NoFreeSockets)?;
+            let (mut tx, mut rx) = match CONNECTIONS[index].take() {
+                Some(connection) => (connection.tx, connection.rx),
+                None => (
+                    VecDeque::with_capacity(TX_BUFFER_LEN),
+                    VecDeque::with_capacity(RX_BUFFER_LEN),
+                ),
+            };
+            tx.clear();
+            rx.clear();
+
+            let connection = CONNECTIONS[index].insert(Connection {
+                local_port: Some(local_port),
+                remote_ip: ip,
+                remote_port: port,
+                remote_mac,
+                state: State::SynSent,
+                error: None,
+                snd_una: iss,
+                snd_nxt: iss.wrapping_add(1),
+                snd_wnd: 0,
+                mss: DEFAULT_MSS,
+                tx,
+                fin_queued: false,
+                fin_sent: false,
+                retransmit_at: None,
+                rto: INITIAL_RTO_MS,
+                retransmits: 0,
+                rcv_nxt: 0,
+                rcv_wnd: 0,
+                rx,
+                fin_received: false,
+            });
+
+            connection.send_segment(iss, flags::SYN, &[]);
+            connection.arm_timer();
+
+            Ok(Self { port: local_port })
+        })?;
+
+        // The slot is freed by drop if this fails
+        loop {
+            match stream.with_connection(|connection| {
+                connection.poll();
+                (connection.state, connection.error)
+            }) {
+                (State::SynSent, _) => super::wait(),
+                (_, Some(error)) => return Err(error),
+                _ => return Ok(stream),
+            }
+        }
+    }
+
+    /// Must be called with interrupts disabled as it reads the connection
+    /// table
+    unsafe fn ephemeral_port() -> u16 {
+        loop {
+            let port = NEXT_EPHEMERAL_PORT
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |port| {
+                    Some(port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START))
+                })
+                .unwrap();
+
+            if !CONNECTIONS
+                .iter()
+                .flatten()
+                .any(|connection| connection.local_port == Some(port))
+            {
+                return port;
+            }
+        }
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> T {
+        cpu::without_interrupts(|| unsafe {
+            f(CONNECTIONS
+                .iter_mut()
+                .flatten()
+                .find(|connection| connection.local_port == Some(self.port))
+                .expect("TCP connection slot freed while in use"))
+        })
+    }
+
+    /// Read what has arrived into `buffer`, blocking until something does.
+    /// Returns 0 once the peer has closed and everything has been read
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let deadline = pit::ticks() + READ_TIMEOUT_MS;
+        loop {
+            let read = self.with_connection(|connection| {
+                connection.poll();
+
+                if !connection.rx.is_empty() {
+                    let len = connection.rx.len().min(buffer.len());
+                    for (byte, data) in
+                        buffer.iter_mut().zip(connection.rx.drain(..len))
+                    {
+                        *byte = data;
+                    }
+
+                    // Tell them about the space once it is worth a segment
+                    if connection.receive_window()
+                        >= connection.rcv_wnd + connection.mss
+                        && connection.state != State::Closed
+                    {
+                        connection.send_ack();
+                    }
+                    return Some(Ok(len));
+                }
+
+                if let Some(error) = connection.error {
+                    return Some(Err(error));
+                }
+                if connection.fin_received || connection.state == State::Closed
+                {
+                    return Some(Ok(0));
+                }
+                None
+            });
+
+            if let Some(read) = read {
+                return read;
+            }
+            if pit::ticks() >= deadline {
+                return Err(Error::ReceiveTimeout);
+            }
+            super::wait();
+        }
+    }
+
+    /// Send all of `data`, blocking while the send buffer is full
+    pub fn write_all(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let written = self.with_connection(|connection| {
+                connection.poll();
+
+                if let Some(error) = connection.error {
+                    return Err(error);
+                }
+                if connection.fin_queued
+                    || !matches!(
+                        connection.state,
+                        State::Established | State::CloseWait
+                    )
+                {
+                    return Err(Error::TcpConnectionClosed);
+                }
+
+                let len = (TX_BUFFER_LEN - connection.tx.len()).min(data.len());
+                connection.tx.extend(&data[..len]);
+                connection.transmit();
+                Ok(len)
+            })?;
+
+            data = &data[written..];
+            if !data.is_empty() {
+                super::wait();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    /// Send our FIN and give the peer a moment to finish the handshake,
+    /// resetting the connection if it does not
+    fn drop(&mut self) {
+        self.with_connection(|connection| {
+            connection.fin_queued = true;
+            connection.transmit();
+        });
+
+        let deadline = pit::ticks() + CLOSE_TIMEOUT_MS;
+        loop {
+            let state = self.with_connection(|connection| {
+                connection.poll();
+                connection.state
+            });
+            if matches!(state, State::TimeWait | State::Closed) {
+                break;
+            }
+            if pit::ticks() >= deadline {
+                self.with_connection(|connection| {
+                    connection.send_segment(connection.snd_nxt, flags::RST, &[])
+                });
+                break;
+            }
+            super::wait();
+        }
+
+        self.with_connection(|connection| {
+            connection.local_port = None;
+            connection.state = State::Closed;
+            connection.tx.clear();
+            connection.rx.clear();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::RefCell,
+        net::{Ipv4Addr, SocketAddrV4},
+    };
+
+    use std::sync::MutexGuard;
+
+    use alloc::rc::Rc;
+
+    use super::*;
+    use crate::net::{
+        self,
+        testing::{self, TcpServer},
+    };
+
+    const OUR_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const SERVER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+    const SERVER_PORT: u16 = 80;
+
+    /// Hold the stack with `server` on the other end
+    fn serve(
+        server: TcpServer,
+    ) -> (Rc<RefCell<TcpServer>>, MutexGuard<'static, ()>) {
+        let server = Rc::new(RefCell::new(server));
+        let peer = server.clone();
+        let stack =
+            testing::take_stack(move |frame| peer.borrow_mut().answer(frame));
+        net::configure_ipv4(OUR_IP, NETMASK, Ipv4Addr::UNSPECIFIED);
+
+        (server, stack)
+    }
+
+    fn state(stream: &TcpStream) -> State {
+        stream.with_connection(|connection| connection.state)
+    }
+
+    /// Read until the server closes
+    fn read_to_end(stream: &mut TcpStream) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 256];
+        loop {
+            match stream.read(&mut buffer).unwrap() {
+                0 => return received,
+                len => received.extend_from_slice(&buffer[..len]),
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(seq_lt(u32::MAX - 10, 5));
+        assert!(!seq_lt(5, u32::MAX - 10));
+        assert!(seq_le(7, 7));
+    }
+
+    #[test]
+    fn segment_round_trips_with_its_mss() {
+        // Padded to a multiple of 4
+        let options = [1, 1, 2, 4, 0x05, 0xB4, 0, 0];
+        let segment = Tcp {
+            src_port: 80,
+            dst_port: 49152,
+            seq: 1000,
+            ack: 2000,
+            flags: flags::SYN | flags::ACK,
+            window: 4096,
+            options: &options,
+            payload: b"",
+        };
+
+        let mut buffer = [0u8; 64];
+        let len = segment.serialise(&mut buffer);
+        let parsed = Tcp::deserialise(&buffer[..len]).unwrap();
+        assert_eq!((parsed.seq, parsed.ack), (1000, 2000));
+        assert_eq!(parsed.flags, flags::SYN | flags::ACK);
+        assert_eq!(parsed.mss(), Some(1460));
+        assert_eq!(parsed.seq_len(), 1);
+    }
+
+    #[test]
+    fn bad_option_length_has_no_mss() {
+        let segment = Tcp {
+            src_port: 80,
+            dst_port: 49152,
+            seq: 0,
+            ack: 0,
+            flags: flags::SYN,
+            window: 0,
+            options: &[option::MSS, 1, 0x05, 0xB4],
+            payload: &[],
+        };
+        assert_eq!(segment.mss(), None);
+    }
+
+    #[test]
+    fn server_closes_first() {
+        let response: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let (server, _stack) = serve(TcpServer::new(
+            SocketAddrV4::new(SERVER_IP, SERVER_PORT),
+            &response,
+        ));
+
+        let mut stream =
+            TcpStream::connect(SERVER_IP.into(), SERVER_PORT).unwrap();
+        assert_eq!(state(&stream), State::Established);
+        stream.write_all(b"request").unwrap();
+
+        assert_eq!(read_to_end(&mut stream), response);
+        assert_eq!(state(&stream), State::CloseWait);
+
+        // Our FIN is acknowledged straight away, no reset needed
+        let start = pit::ticks();
+        drop(stream);
+        assert!(pit::ticks() - start < CLOSE_TIMEOUT_MS);
+        let received = &server.borrow().received;
+        assert_ne!(received.last().unwrap() & flags::FIN, 0);
+        assert!(received.iter().all(|&flags| flags & flags::RST == 0));
+    }
+
+    #[test]
+    fn we_close_first() {
+        let mut server = TcpServer::new(
+            SocketAddrV4::new(SERVER_IP, SERVER_PORT),
+            b"response",
+        );
+        server.wait_for_close = true;
+        let (server, _stack) = serve(server);
+
+        let mut stream =
+            TcpStream::connect(SERVER_IP.into(), SERVER_PORT).unwrap();
+        stream.write_all(b"request").unwrap();
+        let mut buffer = [0u8; 16];
+        assert_eq!(stream.read(&mut buffer).unwrap(), 8);
+
+        stream.with_connection(|connection| {
+            connection.fin_queued = true;
+            connection.transmit();
+            assert_eq!(connection.state, State::FinWait1);
+        });
+        // The server acknowledges our FIN and sends its own in one go
+        while state(&stream) != State::TimeWait {
+            net::wait();
+        }
+        assert_eq!(stream.read(&mut buffer).unwrap(), 0);
+
+        drop(stream);
+        // Hand the server what we sent last
+        net::poll();
+        let received = &server.borrow().received;
+        // The last thing we sent acknowledges their FIN
+        assert_eq!(*received.last().unwrap(), flags::ACK);
+        assert!(received.iter().all(|&flags| flags & flags::RST == 0));
+    }
+
+    #[test]
+    fn lost_syn_is_retransmitted() {
+        let mut server =
+            TcpServer::new(SocketAddrV4::new(SERVER_IP, SERVER_PORT), b"");
+        server.syns_to_drop = 1;
+        server.wait_for_close = true;
+        let (server, _stack) = serve(server);
+
+        let start = pit::ticks();
+        let stream = TcpStream::connect(SERVER_IP.into(), SERVER_PORT).unwrap();
+        assert!(pit::ticks() - start >= INITIAL_RTO_MS);
+        assert_eq!(state(&stream), State::Established);
+
+        let syns = server
+            .borrow()
+            .received
+            .iter()
+            .filter(|&&flags| flags & flags::SYN != 0)
+            .count();
+        assert_eq!(syns, 2);
+    }
+}
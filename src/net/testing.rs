@@ -0,0 +1,302 @@
+//! Scripted peers for driving the stack over [`Loopback`] on the host. The
+//! stack is global, so a test holds it with [`take_stack`] and they run one
+//! at a time
+
+use core::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use alloc::vec::Vec;
+
+use super::{checksum, checksum_add, checksum_finish, nic::loopback::Loopback};
+
+/// MAC address of the peer
+pub(super) const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+const ETHERNET_LEN: usize = 14;
+const ETHER_TYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHER_TYPE_ARP: [u8; 2] = [0x08, 0x06];
+
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const IPV4_LEN: usize = 20;
+const IPV4_PROTOCOL_TCP: u8 = 6;
+const IPV4_PROTOCOL_UDP: u8 = 17;
+const UDP_LEN: usize = 8;
+
+const TCP_LEN: usize = 20;
+const TCP_FIN: u8 = 1 << 0;
+const TCP_SYN: u8 = 1 << 1;
+const TCP_ACK: u8 = 1 << 4;
+/// Segments from [`TcpServer`] stay under the MSS the stack assumes
+const TCP_SEGMENT_LEN: usize = 512;
+
+static STACK: Mutex<()> = Mutex::new(());
+static LOOPBACK: OnceLock<&'static Loopback> = OnceLock::new();
+
+/// Hold the stack until the guard is dropped, with its interface reset and
+/// `peer` answering each frame the stack sends with any number of its own
+pub(super) fn take_stack(
+    mut peer: impl FnMut(&[u8]) -> Vec<Vec<u8>> + 'static,
+) -> MutexGuard<'static, ()> {
+    // A failed test poisons the lock, which is fine as we reset everything
+    let guard = STACK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let loopback = LOOPBACK.get_or_init(|| {
+        crate::pit::init(1000);
+        super::init_loopback()
+    });
+    super::use_interface(super::interfaces().len() - 1);
+
+    loopback.set_peer(move |loopback| {
+        let mut frame = [0u8; 1518];
+        while let Some(len) = loopback.take_sent(&mut frame) {
+            for reply in peer(&frame[..len]) {
+                loopback.inject(&reply).unwrap();
+            }
+        }
+    });
+
+    guard
+}
+
+/// Hand the stack a frame the peer sends unprompted
+pub(super) fn inject(frame: &[u8]) {
+    LOOPBACK.get().unwrap().inject(frame).unwrap();
+}
+
+fn be16(buffer: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(buffer.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn ipv4(buffer: &[u8], at: usize) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = buffer.get(at..at + 4)?.try_into().ok()?;
+    Some(octets.into())
+}
+
+/// Frame from the peer to the stack
+fn ethernet(ether_type: [u8; 2], payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHERNET_LEN + payload.len());
+    frame.extend_from_slice(&<[u8; 6]>::from(super::mac()));
+    frame.extend_from_slice(&PEER_MAC);
+    frame.extend_from_slice(&ether_type);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reply to the stack's ARP request if it asks for `ip`
+pub(super) fn answer_arp(frame: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    let arp = frame.get(ETHERNET_LEN..ETHERNET_LEN + ARP_LEN)?;
+    if frame[12..14] != ETHER_TYPE_ARP
+        || be16(arp, 6)? != ARP_REQUEST
+        || ipv4(arp, 24)? != ip
+    {
+        return None;
+    }
+
+    let mut reply = Vec::with_capacity(ARP_LEN);
+    // Same hardware and protocol types and lengths
+    reply.extend_from_slice(&arp[..6]);
+    reply.extend_from_slice(&ARP_REPLY.to_be_bytes());
+    reply.extend_from_slice(&PEER_MAC);
+    reply.extend_from_slice(&ip.octets());
+    // Back to whoever asked
+    reply.extend_from_slice(&arp[8..18]);
+
+    Some(ethernet(ETHER_TYPE_ARP, &reply))
+}
+
+/// IPv4 frame from the peer carrying `payload`
+fn ipv4_frame(
+    protocol: u8,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    payload: &[u8],
+) -> Vec<u8> {
+    let total_len = IPV4_LEN + payload.len();
+
+    let mut datagram = Vec::with_capacity(total_len);
+    datagram.extend_from_slice(&[0x45, 0]);
+    datagram.extend_from_slice(&(total_len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0]);
+    datagram.extend_from_slice(&src.octets());
+    datagram.extend_from_slice(&dst.octets());
+    let header_checksum = checksum(&datagram);
+    datagram[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    datagram.extend_from_slice(payload);
+
+    ethernet(ETHER_TYPE_IPV4, &datagram)
+}
+
+/// Source, destination and payload if the stack sent an IPv4 datagram
+/// carrying `protocol`
+fn parse_ipv4(
+    frame: &[u8],
+    protocol: u8,
+) -> Option<(Ipv4Addr, Ipv4Addr, &[u8])> {
+    if frame.get(12..14)? != ETHER_TYPE_IPV4 {
+        return None;
+    }
+
+    let datagram = &frame[ETHERNET_LEN..];
+    let header_len = (*datagram.first()? & 0x0F) as usize * 4;
+    let total_len = be16(datagram, 2)? as usize;
+    if *datagram.get(9)? != protocol {
+        return None;
+    }
+
+    Some((
+        ipv4(datagram, 12)?,
+        ipv4(datagram, 16)?,
+        datagram.get(header_len..total_len)?,
+    ))
+}
+
+/// UDP datagram from the peer, without a checksum as IPv4 allows
+pub(super) fn udp(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = UDP_LEN + payload.len();
+
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    ipv4_frame(IPV4_PROTOCOL_UDP, *src.ip(), *dst.ip(), &udp)
+}
+
+/// Source, destination and payload if the stack sent a UDP datagram
+pub(super) fn parse_udp(
+    frame: &[u8],
+) -> Option<(SocketAddrV4, SocketAddrV4, &[u8])> {
+    let (src_ip, dst_ip, udp) = parse_ipv4(frame, IPV4_PROTOCOL_UDP)?;
+    let src = SocketAddrV4::new(src_ip, be16(udp, 0)?);
+    let dst = SocketAddrV4::new(dst_ip, be16(udp, 2)?);
+
+    Some((src, dst, udp.get(UDP_LEN..be16(udp, 4)? as usize)?))
+}
+
+/// A TCP server for a single connection. It answers the first data from the
+/// stack with `response`, then closes unless it was told to wait for the
+/// stack to close first
+pub(super) struct TcpServer {
+    address: SocketAddrV4,
+    response: Vec<u8>,
+    /// SYNs left to ignore, to make the stack retransmit
+    pub(super) syns_to_drop: usize,
+    /// Send our FIN only after the stack sends its own
+    pub(super) wait_for_close: bool,
+    /// Flags of every segment the stack sent us
+    pub(super) received: Vec<u8>,
+    client: Option<SocketAddrV4>,
+    /// Next sequence number we send
+    seq: u32,
+    /// Next sequence number we expect
+    ack: u32,
+}
+
+impl TcpServer {
+    pub(super) fn new(address: SocketAddrV4, response: &[u8]) -> Self {
+        Self {
+            address,
+            response: Vec::from(response),
+            syns_to_drop: 0,
+            wait_for_close: false,
+            received: Vec::new(),
+            client: None,
+            seq: 10_000,
+            ack: 0,
+        }
+    }
+
+    /// Segment to the client, advancing our sequence number past it
+    fn segment(&mut self, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let client = self.client.unwrap();
+
+        let mut segment = Vec::with_capacity(TCP_LEN + payload.len());
+        segment.extend_from_slice(&self.address.port().to_be_bytes());
+        segment.extend_from_slice(&client.port().to_be_bytes());
+        segment.extend_from_slice(&self.seq.to_be_bytes());
+        segment.extend_from_slice(&self.ack.to_be_bytes());
+        segment.extend_from_slice(&[(TCP_LEN as u8 / 4) << 4, flags]);
+        segment.extend_from_slice(&[0xFF, 0xFF, 0, 0, 0, 0]);
+        segment.extend_from_slice(payload);
+
+        let mut pseudo_header = Vec::with_capacity(12);
+        pseudo_header.extend_from_slice(&self.address.ip().octets());
+        pseudo_header.extend_from_slice(&client.ip().octets());
+        pseudo_header.extend_from_slice(&[0, IPV4_PROTOCOL_TCP]);
+        pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        let segment_checksum = checksum_finish(checksum_add(
+            checksum_add(0, &pseudo_header),
+            &segment,
+        ));
+        segment[16..18].copy_from_slice(&segment_checksum.to_be_bytes());
+
+        self.seq = self.seq.wrapping_add(
+            payload.len() as u32 + (flags & (TCP_SYN | TCP_FIN) != 0) as u32,
+        );
+        ipv4_frame(
+            IPV4_PROTOCOL_TCP,
+            *self.address.ip(),
+            *client.ip(),
+            &segment,
+        )
+    }
+
+    pub(super) fn answer(&mut self, frame: &[u8]) -> Vec<Vec<u8>> {
+        if let Some(reply) = answer_arp(frame, *self.address.ip()) {
+            return Vec::from([reply]);
+        }
+        let Some((src_ip, _, tcp)) = parse_ipv4(frame, IPV4_PROTOCOL_TCP)
+        else {
+            return Vec::new();
+        };
+        let (Some(src_port), Some(seq), Some(&flags)) =
+            (be16(tcp, 0), tcp.get(4..8), tcp.get(13))
+        else {
+            return Vec::new();
+        };
+        let seq = u32::from_be_bytes(seq.try_into().unwrap());
+        let payload = &tcp[(tcp[12] >> 4) as usize * 4..];
+        self.received.push(flags);
+
+        if flags & TCP_SYN != 0 {
+            if self.syns_to_drop != 0 {
+                self.syns_to_drop -= 1;
+                return Vec::new();
+            }
+            self.client = Some(SocketAddrV4::new(src_ip, src_port));
+            self.ack = seq.wrapping_add(1);
+            return Vec::from([self.segment(TCP_SYN | TCP_ACK, &[])]);
+        }
+
+        let mut replies = Vec::new();
+        if !payload.is_empty() {
+            self.ack = seq.wrapping_add(payload.len() as u32);
+            let response = core::mem::take(&mut self.response);
+            for chunk in response.chunks(TCP_SEGMENT_LEN) {
+                replies.push(self.segment(TCP_ACK, chunk));
+            }
+            if !self.wait_for_close {
+                replies.push(self.segment(TCP_FIN | TCP_ACK, &[]));
+            }
+        }
+        if flags & TCP_FIN != 0 {
+            self.ack = seq.wrapping_add(payload.len() as u32 + 1);
+            replies.push(self.segment(TCP_ACK, &[]));
+            if self.wait_for_close {
+                replies.push(self.segment(TCP_FIN | TCP_ACK, &[]));
+            }
+        }
+        replies
+    }
+}
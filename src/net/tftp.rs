@@ -0,0 +1,743 @@
+//! TFTP read client [https://www.rfc-editor.org/rfc/rfc1350] with option
+//! negotiation [https://www.rfc-editor.org/rfc/rfc2347] for the block size
+//! (RFC 2348), transfer size (RFC 2349) and window size (RFC 7440)
+
+use core::net::IpAddr;
+
+use crate::error::{Error, Result};
+
+use super::{
+    dns,
+    progress::Progress,
+    udp::{self, UdpSocket},
+    Endianness, Serialise,
+};
+
+const SERVER_PORT: u16 = 69;
+
+/// Data in every block but the last, unless we negotiate otherwise
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Biggest block that fits in an unfragmented IPv4 datagram
+const BLOCK_SIZE_OPTION: usize = 1468;
+/// The IPv6 header is 20 bytes bigger, this leaves room for some extension
+/// headers too
+const BLOCK_SIZE_OPTION_IPV6: usize = 1428;
+/// Blocks the server may send before waiting for our ACK. A whole window
+/// can arrive before we next read the socket, so no more than it queues
+const WINDOW_SIZE_OPTION: usize = udp::SOCKET_QUEUE_LEN;
+/// Sent as 0 in the request, the server replies with the size of the file
+const TRANSFER_SIZE_OPTION: usize = 0;
+
+/// How long to wait for the next packet before resending our last one
+const TIMEOUT_MS: u64 = 1_000;
+/// How many timeouts in a row before we give up
+const MAX_RETRIES: u32 = 5;
+
+/// Error codes sent in [`Tftp::Error`]
+mod error_code {
+    pub const NOT_DEFINED: u16 = 0;
+    pub const FILE_NOT_FOUND: u16 = 1;
+    pub const ACCESS_VIOLATION: u16 = 2;
+    pub const DISK_FULL: u16 = 3;
+    pub const ILLEGAL_OPERATION: u16 = 4;
+    pub const UNKNOWN_TRANSFER_ID: u16 = 5;
+    pub const FILE_EXISTS: u16 = 6;
+    pub const NO_SUCH_USER: u16 = 7;
+    pub const OPTION_NEGOTIATION: u16 = 8;
+}
+
+/// Option names
+mod option {
+    pub const BLOCK_SIZE: &str = "blksize";
+    pub const TRANSFER_SIZE: &str = "tsize";
+    pub const WINDOW_SIZE: &str = "windowsize";
+}
+
+#[derive(Debug)]
+enum Tftp<'a> {
+    /// Opcode 1, options are NUL terminated name and value pairs
+    ReadRequest {
+        filename: &'a str,
+        mode: &'a str,
+        options: &'a [u8],
+    },
+
+    /// Opcode 3
+    Data { block: u16, data: &'a [u8] },
+
+    /// Opcode 4
+    Ack { block: u16 },
+
+    /// Opcode 5
+    Error { code: u16, message: &'a str },
+
+    /// Opcode 6, the options the server accepted
+    OptionAck { options: &'a [u8] },
+}
+
+impl Tftp<'_> {
+    const READ_REQUEST: u16 = 1;
+    const DATA: u16 = 3;
+    const ACK: u16 = 4;
+    const ERROR: u16 = 5;
+    const OPTION_ACK: u16 = 6;
+}
+
+/// Iterator over the (name, value) pairs in a request or option ack
+struct Options<'a> {
+    buffer: &'a [u8],
+    ptr: usize,
+}
+
+impl<'a> Options<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, ptr: 0 }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ptr >= self.buffer.len() {
+            return None;
+        }
+
+        let name = consume_str(&mut self.ptr, self.buffer).ok()?;
+        let value = consume_str(&mut self.ptr, self.buffer).ok()?;
+
+        Some((name, value))
+    }
+}
+
+/// Consume a NUL terminated string from the buffer
+fn consume_str<'a>(ptr: &mut usize, buffer: &'a [u8]) -> Result<&'a str> {
+    let len = buffer[*ptr..]
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or(Error::CouldNotParsePacket)?;
+    let bytes = consume!(*ptr, buffer, [u8], len);
+    *ptr += 1;
+
+    core::str::from_utf8(bytes).map_err(|_| Error::CouldNotParsePacket)
+}
+
+/// Emit a NUL terminated string into the buffer
+fn emit_str(ptr: &mut usize, buffer: &mut [u8], value: &str) {
+    emit!(*ptr, buffer, [u8], value.as_bytes());
+    emit!(*ptr, buffer, u8, 0);
+}
+
+/// Emit a number as a NUL terminated decimal string, how option values are
+/// sent
+fn emit_number(ptr: &mut usize, buffer: &mut [u8], mut value: usize) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+
+    emit!(*ptr, buffer, [u8], &digits[start..]);
+    emit!(*ptr, buffer, u8, 0);
+}
+
+impl<'a> Serialise<'a> for Tftp<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < 4 {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        match consume!(ptr, buffer, Endianness::Big, u16) {
+            Self::READ_REQUEST => Ok(Self::ReadRequest {
+                filename: consume_str(&mut ptr, buffer)?,
+                mode: consume_str(&mut ptr, buffer)?,
+                options: consume!(ptr, buffer, [u8], buffer.len() - ptr),
+            }),
+            Self::DATA => Ok(Self::Data {
+                block: consume!(ptr, buffer, Endianness::Big, u16),
+                data: consume!(ptr, buffer, [u8], buffer.len() - ptr),
+            }),
+            Self::ACK => Ok(Self::Ack {
+                block: consume!(ptr, buffer, Endianness::Big, u16),
+            }),
+            Self::ERROR => Ok(Self::Error {
+                code: consume!(ptr, buffer, Endianness::Big, u16),
+                // Some servers forget the terminator
+                message: consume_str(&mut ptr, buffer).unwrap_or(""),
+            }),
+            Self::OPTION_ACK => Ok(Self::OptionAck {
+                options: consume!(ptr, buffer, [u8], buffer.len() - ptr),
+            }),
+            _ => Err(Error::CouldNotParsePacket),
+        }
+    }
+
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let mut ptr = 0;
+
+        match self {
+            Self::ReadRequest {
+                filename,
+                mode,
+                options,
+            } => {
+                emit!(ptr, buffer, Endianness::Big, u16, Self::READ_REQUEST);
+                emit_str(&mut ptr, buffer, filename);
+                emit_str(&mut ptr, buffer, mode);
+                emit!(ptr, buffer, [u8], options);
+            }
+            Self::Data { block, data } => {
+                emit!(ptr, buffer, Endianness::Big, u16, Self::DATA);
+                emit!(ptr, buffer, Endianness::Big, u16, *block);
+                emit!(ptr, buffer, [u8], data);
+            }
+            Self::Ack { block } => {
+                emit!(ptr, buffer, Endianness::Big, u16, Self::ACK);
+                emit!(ptr, buffer, Endianness::Big, u16, *block);
+            }
+            Self::Error { code, message } => {
+                emit!(ptr, buffer, Endianness::Big, u16, Self::ERROR);
+                emit!(ptr, buffer, Endianness::Big, u16, *code);
+                emit_str(&mut ptr, buffer, message);
+            }
+            Self::OptionAck { options } => {
+                emit!(ptr, buffer, Endianness::Big, u16, Self::OPTION_ACK);
+                emit!(ptr, buffer, [u8], options);
+            }
+        }
+
+        ptr
+    }
+}
+
+/// Map an error code the server sent us to our own error
+fn server_error(code: u16) -> Error {
+    match code {
+        error_code::FILE_NOT_FOUND => Error::TftpFileNotFound,
+        error_code::ACCESS_VIOLATION => Error::TftpAccessViolation,
+        error_code::DISK_FULL => Error::TftpDiskFull,
+        error_code::ILLEGAL_OPERATION => Error::TftpIllegalOperation,
+        error_code::UNKNOWN_TRANSFER_ID => Error::TftpUnknownTransferId,
+        error_code::FILE_EXISTS => Error::TftpFileExists,
+        error_code::NO_SUCH_USER => Error::TftpNoSuchUser,
+        error_code::OPTION_NEGOTIATION => Error::TftpOptionNegotiation,
+        error_code::NOT_DEFINED => Error::TftpNotDefined,
+        // Codes past 8 are not defined either
+        _ => Error::TftpNotDefined,
+    }
+}
+
+/// State of a single read transfer
+struct Transfer<'a> {
+    socket: UdpSocket,
+    server: IpAddr,
+    /// The port the server answers from, learnt from its first reply
+    server_tid: Option<u16>,
+    /// Where the file is written to
+    region: &'a mut [u8],
+    written: usize,
+    /// Block we want next
+    expected_block: u16,
+    /// Blocks received in order since our last ACK
+    unacked_blocks: usize,
+    /// Set once we have ACKed a gap so the rest of the window does not
+    /// trigger an ACK each
+    gap_acked: bool,
+    block_size: usize,
+    window_size: usize,
+    progress: Progress,
+    /// Last packet we sent, resent when we time out
+    last_sent: [u8; DEFAULT_BLOCK_SIZE + 4],
+    last_sent_len: usize,
+}
+
+impl<'a> Transfer<'a> {
+    fn new(server: IpAddr, region: &'a mut [u8]) -> Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(0)?,
+            server,
+            server_tid: None,
+            region,
+            written: 0,
+            expected_block: 1,
+            unacked_blocks: 0,
+            gap_acked: false,
+            block_size: DEFAULT_BLOCK_SIZE,
+            window_size: 1,
+            progress: Progress::new(),
+            last_sent: [0; DEFAULT_BLOCK_SIZE + 4],
+            last_sent_len: 0,
+        })
+    }
+
+    /// Send a packet to the server, remembering it in case it needs resending
+    fn send(&mut self, packet: Tftp) -> Result<()> {
+        self.last_sent_len = packet.serialise(&mut self.last_sent);
+        self.resend()
+    }
+
+    fn resend(&self) -> Result<()> {
+        self.socket.send_to(
+            &self.last_sent[..self.last_sent_len],
+            self.server,
+            self.server_tid.unwrap_or(SERVER_PORT),
+        )
+    }
+
+    /// ACK everything up to the last block we have in order
+    fn ack(&mut self) -> Result<()> {
+        self.unacked_blocks = 0;
+        self.send(Tftp::Ack {
+            block: self.expected_block.wrapping_sub(1),
+        })
+    }
+
+    /// Tell the server we are giving up on the transfer
+    fn abort(&self, code: u16, message: &str) {
+        let mut buffer = [0u8; 64];
+        let len = Tftp::Error { code, message }.serialise(&mut buffer);
+        _ = self.socket.send_to(
+            &buffer[..len],
+            self.server,
+            self.server_tid.unwrap_or(SERVER_PORT),
+        );
+    }
+
+    /// Biggest block we ask for, it has to fit in one datagram to `server`
+    fn max_block_size(&self) -> usize {
+        match self.server {
+            IpAddr::V4(_) => BLOCK_SIZE_OPTION,
+            IpAddr::V6(_) => BLOCK_SIZE_OPTION_IPV6,
+        }
+    }
+
+    /// Send the read request, with our options if `negotiate` is set
+    fn request(&mut self, filename: &str, negotiate: bool) -> Result<()> {
+        let mut options = [0u8; 48];
+        let mut len = 0;
+        if negotiate {
+            for (name, value) in [
+                (option::BLOCK_SIZE, self.max_block_size()),
+                (option::TRANSFER_SIZE, TRANSFER_SIZE_OPTION),
+                (option::WINDOW_SIZE, WINDOW_SIZE_OPTION),
+            ] {
+                emit_str(&mut len, &mut options, name);
+                emit_number(&mut len, &mut options, value);
+            }
+        }
+
+        // Opcode, then the file name and mode each NUL terminated
+        let mode = "octet";
+        if 2 + filename.len() + 1 + mode.len() + 1 + len > self.last_sent.len()
+        {
+            return Err(Error::TftpFilenameTooLong);
+        }
+
+        self.server_tid = None;
+        self.send(Tftp::ReadRequest {
+            filename,
+            mode,
+            options: &options[..len],
+        })
+    }
+
+    /// Apply the options the server accepted, anything it left out stays at
+    /// the default
+    fn negotiate(&mut self, options: &[u8]) -> Result<()> {
+        for (name, value) in Options::new(options) {
+            let value: usize =
+                value.parse().map_err(|_| Error::TftpOptionNegotiation)?;
+
+            if name.eq_ignore_ascii_case(option::BLOCK_SIZE) {
+                // The server may only lower what we asked for
+                if !(8..=self.max_block_size()).contains(&value) {
+                    return Err(Error::TftpOptionNegotiation);
+                }
+                self.block_size = value;
+            } else if name.eq_ignore_ascii_case(option::WINDOW_SIZE) {
+                if !(1..=WINDOW_SIZE_OPTION).contains(&value) {
+                    return Err(Error::TftpOptionNegotiation);
+                }
+                self.window_size = value;
+            } else if name.eq_ignore_ascii_case(option::TRANSFER_SIZE) {
+                // Check the whole file fits before we write any of it
+                if value > self.region.len() {
+                    return Err(Error::TftpFileTooLarge);
+                }
+                self.progress.set_total(value);
+            } else {
+                // We never asked for anything else
+                return Err(Error::TftpOptionNegotiation);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a DATA packet, returns true once the file is complete
+    fn data(&mut self, block: u16, data: &[u8]) -> Result<bool> {
+        if block != self.expected_block {
+            // Ahead of what we expected means we lost a block, ACK what we
+            // have so the server starts the window again from there
+            let ahead = block.wrapping_sub(self.expected_block) < 0x8000;
+            if ahead && !self.gap_acked {
+                self.gap_acked = true;
+                self.progress.retransmit();
+                self.ack()?;
+            }
+            // Behind is a duplicate, without windows that means the server
+            // missed our ACK. With windows the timeout takes care of it
+            if !ahead && self.window_size == 1 {
+                self.resend()?;
+            }
+            return Ok(false);
+        }
+
+        if self.written + data.len() > self.region.len() {
+            self.abort(error_code::DISK_FULL, "File too large");
+            return Err(Error::TftpFileTooLarge);
+        }
+        self.region[self.written..self.written + data.len()]
+            .copy_from_slice(data);
+        self.written += data.len();
+        self.progress.update(self.written);
+
+        self.expected_block = self.expected_block.wrapping_add(1);
+        self.unacked_blocks += 1;
+        self.gap_acked = false;
+
+        // A short block marks the end of the file
+        let complete = data.len() < self.block_size;
+        if complete || self.unacked_blocks == self.window_size {
+            self.ack()?;
+        }
+
+        Ok(complete)
+    }
+
+    /// Run the transfer to completion, returning the size of the file
+    fn run(&mut self, filename: &str) -> Result<usize> {
+        let mut buffer = [0u8; udp::MAX_PAYLOAD_LEN];
+        let mut retries = 0;
+        let mut negotiating = true;
+
+        self.request(filename, negotiating)?;
+
+        loop {
+            let (len, src_ip, src_port) = match self
+                .socket
+                .recv_from(&mut buffer, TIMEOUT_MS)
+            {
+                Ok(received) => received,
+                Err(Error::ReceiveTimeout) if retries < MAX_RETRIES => {
+                    retries += 1;
+                    self.progress.retransmit();
+                    // Until the server answers the request is what we resend,
+                    // after that we ACK whatever we have in order
+                    match self.server_tid {
+                        None => self.resend()?,
+                        Some(_) => self.ack()?,
+                    }
+                    continue;
+                }
+                Err(Error::ReceiveTimeout) => return Err(Error::TftpTimeout),
+                Err(error) => return Err(error),
+            };
+
+            if src_ip != self.server {
+                continue;
+            }
+
+            // The first reply picks the port for the rest of the transfer,
+            // anyone else gets told to go away
+            match self.server_tid {
+                None => self.server_tid = Some(src_port),
+                Some(tid) if tid != src_port => {
+                    let mut error = [0u8; 32];
+                    let len = Tftp::Error {
+                        code: error_code::UNKNOWN_TRANSFER_ID,
+                        message: "Unknown transfer ID",
+                    }
+                    .serialise(&mut error);
+                    _ = self.socket.send_to(&error[..len], src_ip, src_port);
+                    continue;
+                }
+                Some(_) => {}
+            }
+
+            match Tftp::deserialise(&buffer[..len]) {
+                Ok(Tftp::OptionAck { options }) if negotiating => {
+                    negotiating = false;
+                    retries = 0;
+
+                    if let Err(error) = self.negotiate(options) {
+                        self.abort(
+                            error_code::OPTION_NEGOTIATION,
+                            "Options not acceptable",
+                        );
+                        return Err(error);
+                    }
+
+                    // ACK 0 tells the server to start sending data
+                    self.ack()?;
+                }
+                Ok(Tftp::Data { block, data }) => {
+                    // Data straight away means the server ignored our options
+                    negotiating = false;
+                    retries = 0;
+
+                    if self.data(block, data)? {
+                        self.progress.finish();
+                        return Ok(self.written);
+                    }
+                }
+                // Servers that reject options rather than ignore them get
+                // asked again without any
+                Ok(Tftp::Error { code, .. })
+                    if negotiating
+                        && (code == error_code::OPTION_NEGOTIATION
+                            || code == error_code::ILLEGAL_OPERATION) =>
+                {
+                    negotiating = false;
+                    self.request(filename, negotiating)?;
+                }
+                Ok(Tftp::Error { code, message }) => {
+                    println!("TFTP error {}: {}", code, message);
+                    return Err(server_error(code));
+                }
+                // Our ACK 0 was lost so the server sent its options again,
+                // once data has arrived it is just a stale duplicate
+                Ok(Tftp::OptionAck { .. }) => {
+                    if self.written == 0 {
+                        self.ack()?;
+                    }
+                }
+                Ok(_) => {
+                    self.abort(
+                        error_code::ILLEGAL_OPERATION,
+                        "Unexpected packet",
+                    );
+                    return Err(Error::TftpIllegalOperation);
+                }
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// Download `filename` from `server` into physical memory starting at
+/// `load_addr`, which must be above 1 MiB. Fails if the file is bigger than
+/// `max_len` or the free RAM there. Returns the size of the file
+pub fn download(
+    server: IpAddr,
+    filename: &str,
+    load_addr: usize,
+    max_len: usize,
+) -> Result<usize> {
+    let region = super::load_region(load_addr, max_len)?;
+
+    Transfer::new(server, region)?.run(filename)
+}
+
+/// Split a `tftp://host/file` URL [RFC 3617] into its host and file name
+fn parse_url(url: &str) -> Result<(&str, &str)> {
+    const SCHEME: &str = "tftp://";
+
+    let rest = url
+        .get(..SCHEME.len())
+        .filter(|scheme| scheme.eq_ignore_ascii_case(SCHEME))
+        .map(|_| &url[SCHEME.len()..])
+        .ok_or(Error::InvalidUrl)?;
+
+    match rest.split_once('/') {
+        Some((host, filename)) if !host.is_empty() && !filename.is_empty() => {
+            Ok((host, filename))
+        }
+        _ => Err(Error::InvalidUrl),
+    }
+}
+
+/// Download the file named by a `tftp://` URL, see [`download`]. The host
+/// may be a name, a dotted IP or an IPv6 literal in brackets
+pub fn download_url(
+    url: &str,
+    load_addr: usize,
+    max_len: usize,
+) -> Result<usize> {
+    let (host, filename) = parse_url(url)?;
+    let server = match host.strip_prefix('[') {
+        Some(literal) => literal
+            .strip_suffix(']')
+            .and_then(|literal| literal.parse().ok())
+            .map(IpAddr::V6)
+            .ok_or(Error::InvalidUrl)?,
+        None => IpAddr::V4(dns::resolve_host(host)?),
+    };
+
+    download(server, filename, load_addr, max_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::RefCell,
+        net::{Ipv4Addr, SocketAddrV4},
+    };
+
+    use alloc::{rc::Rc, vec, vec::Vec};
+
+    use super::*;
+    use crate::{
+        net::{self, testing},
+        pit,
+    };
+
+    const OUR_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const SERVER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+    /// Port the server answers from
+    const SERVER_TID: u16 = 50_000;
+    const BLOCK_SIZE: usize = 512;
+    const WINDOW_SIZE: usize = 4;
+    /// Dropped the first time the server sends it
+    const LOST_BLOCK: u16 = 2;
+
+    /// Server side of a windowed transfer
+    struct Server {
+        file: Vec<u8>,
+        client: Option<SocketAddrV4>,
+        lost: bool,
+        /// Every block put on the wire, in order
+        sent: Vec<u16>,
+    }
+
+    impl Server {
+        fn packet(&self, tftp: Tftp) -> Vec<u8> {
+            let mut buffer = [0u8; BLOCK_SIZE + 4];
+            let len = tftp.serialise(&mut buffer);
+
+            testing::udp(
+                SocketAddrV4::new(SERVER_IP, SERVER_TID),
+                self.client.unwrap(),
+                &buffer[..len],
+            )
+        }
+
+        /// The window that follows an ACK of `acked`
+        fn window(&mut self, acked: u16) -> Vec<Vec<u8>> {
+            let last = (self.file.len() / BLOCK_SIZE + 1) as u16;
+
+            let mut window = Vec::new();
+            for block in acked + 1..=last.min(acked + WINDOW_SIZE as u16) {
+                if block == LOST_BLOCK && !self.lost {
+                    self.lost = true;
+                    continue;
+                }
+
+                let start = (block as usize - 1) * BLOCK_SIZE;
+                let end = (start + BLOCK_SIZE).min(self.file.len());
+                self.sent.push(block);
+                window.push(self.packet(Tftp::Data {
+                    block,
+                    data: &self.file[start..end],
+                }));
+            }
+            window
+        }
+
+        fn answer(&mut self, frame: &[u8]) -> Vec<Vec<u8>> {
+            if let Some(reply) = testing::answer_arp(frame, SERVER_IP) {
+                return vec![reply];
+            }
+            let Some((src, _, payload)) = testing::parse_udp(frame) else {
+                return Vec::new();
+            };
+
+            match Tftp::deserialise(payload) {
+                Ok(Tftp::ReadRequest { filename, .. }) => {
+                    assert_eq!(filename, "kernel");
+                    self.client = Some(src);
+
+                    let mut options = [0u8; 64];
+                    let mut len = 0;
+                    for (name, value) in [
+                        (option::BLOCK_SIZE, BLOCK_SIZE),
+                        (option::TRANSFER_SIZE, self.file.len()),
+                        (option::WINDOW_SIZE, WINDOW_SIZE),
+                    ] {
+                        emit_str(&mut len, &mut options, name);
+                        emit_number(&mut len, &mut options, value);
+                    }
+                    vec![self.packet(Tftp::OptionAck {
+                        options: &options[..len],
+                    })]
+                }
+                Ok(Tftp::Ack { block }) => self.window(block),
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn windowed_transfer_recovers_a_lost_block() {
+        let server = Rc::new(RefCell::new(Server {
+            file: (0..BLOCK_SIZE * 5 + 100).map(|i| i as u8).collect(),
+            client: None,
+            lost: false,
+            sent: Vec::new(),
+        }));
+        let peer = server.clone();
+        let _stack =
+            testing::take_stack(move |frame| peer.borrow_mut().answer(frame));
+        net::configure_ipv4(OUR_IP, NETMASK, Ipv4Addr::UNSPECIFIED);
+
+        let mut region = vec![0u8; BLOCK_SIZE * 8];
+        let start = pit::ticks();
+        let len = Transfer::new(SERVER_IP.into(), &mut region)
+            .unwrap()
+            .run("kernel")
+            .unwrap();
+
+        let server = server.borrow();
+        assert_eq!(&region[..len], server.file.as_slice());
+        // The gap after block 1 is ACKed and the window starts again there,
+        // straight away rather than after a timeout
+        assert_eq!(server.sent, [1, 3, 4, 2, 3, 4, 5, 6]);
+        assert!(pit::ticks() - start < TIMEOUT_MS);
+    }
+
+    #[test]
+    fn url_names_host_and_file() {
+        assert_eq!(
+            parse_url("TFTP://[2001:db8::1]/boot/kernel").unwrap(),
+            ("[2001:db8::1]", "boot/kernel")
+        );
+        assert_eq!(
+            parse_url("tftp://boot.example/kernel").unwrap(),
+            ("boot.example", "kernel")
+        );
+        assert!(parse_url("tftp://boot.example/").is_err());
+        assert!(parse_url("tftp:///kernel").is_err());
+        assert!(parse_url("http://boot.example/kernel").is_err());
+    }
+
+    #[test]
+    fn long_filename_is_refused() {
+        let _stack = testing::take_stack(|_| Vec::new());
+        net::configure_ipv4(OUR_IP, NETMASK, Ipv4Addr::UNSPECIFIED);
+
+        let filename = "k".repeat(DEFAULT_BLOCK_SIZE);
+        let mut region = [0u8; 16];
+        let result = Transfer::new(SERVER_IP.into(), &mut region)
+            .unwrap()
+            .run(&filename);
+        assert!(matches!(result, Err(Error::TftpFilenameTooLong)));
+    }
+}
@@ -0,0 +1,363 @@
+use core::{
+    net::IpAddr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use alloc::collections::VecDeque;
+
+use crate::{
+    cpu,
+    error::{Error, Result},
+    pit,
+};
+
+use super::{
+    checksum_add, checksum_finish, ip,
+    ipv4::{IpProtocol, Ipv4},
+    Endianness, Serialise,
+};
+
+/// How many ports can be bound at once
+const MAX_SOCKETS: usize = 8;
+/// How many datagrams a socket holds before we start dropping
+pub const SOCKET_QUEUE_LEN: usize = 4;
+/// Start of the IANA dynamic port range we pick ephemeral ports from
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Largest payload a single unfragmented datagram can carry, over IPv6 it is
+/// 20 bytes less
+pub const MAX_PAYLOAD_LEN: usize = Ipv4::MAX_PAYLOAD_LEN - Udp::HEADER_LEN;
+
+/// Next ephemeral port to hand out
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORT_START);
+
+/// Slots are allocated the first time they are needed and then reused, as the
+/// allocator never gives memory back
+static mut SOCKETS: [Option<Socket>; MAX_SOCKETS] = {
+    const EMPTY: Option<Socket> = None;
+    [EMPTY; MAX_SOCKETS]
+};
+
+struct Datagram {
+    src_ip: IpAddr,
+    src_port: u16,
+    len: usize,
+    data: [u8; MAX_PAYLOAD_LEN],
+}
+
+struct Socket {
+    /// Local port, [`None`] if the slot is free
+    port: Option<u16>,
+    queue: VecDeque<Datagram>,
+}
+
+/// UDP datagram, the length and checksum are not stored as they are validated
+/// on receive and calculated on send
+#[derive(Debug)]
+struct Udp<'a> {
+    src_port: u16,
+    dst_port: u16,
+    payload: &'a [u8],
+}
+
+impl Udp<'_> {
+    const HEADER_LEN: usize = 8;
+    const CHECKSUM_OFFSET: usize = 6;
+}
+
+impl<'a> Serialise<'a> for Udp<'a> {
+    fn deserialise(buffer: &'a [u8]) -> Result<Self> {
+        if buffer.len() < Self::HEADER_LEN {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        let mut ptr = 0;
+        let src_port = consume!(ptr, buffer, Endianness::Big, u16);
+        let dst_port = consume!(ptr, buffer, Endianness::Big, u16);
+        let len = consume!(ptr, buffer, Endianness::Big, u16) as usize;
+        let _checksum = consume!(ptr, buffer, Endianness::Big, u16);
+
+        if len < Self::HEADER_LEN || len > buffer.len() {
+            return Err(Error::CouldNotParsePacket);
+        }
+
+        Ok(Self {
+            src_port,
+            dst_port,
+            payload: consume!(ptr, buffer, [u8], len - Self::HEADER_LEN),
+        })
+    }
+
+    /// The checksum is left as zero, it needs the IP pseudo header so is
+    /// filled in by [`send`]
+    fn serialise(&self, buffer: &mut [u8]) -> usize {
+        let len = Self::HEADER_LEN + self.payload.len();
+
+        let mut ptr = 0;
+        emit!(ptr, buffer, Endianness::Big, u16, self.src_port);
+        emit!(ptr, buffer, Endianness::Big, u16, self.dst_port);
+        emit!(ptr, buffer, Endianness::Big, u16, len as u16);
+        emit!(ptr, buffer, Endianness::Big, u16, 0);
+        emit!(ptr, buffer, [u8], self.payload);
+
+        ptr
+    }
+}
+
+/// Build and send a single datagram
+fn send(
+    src_port: u16,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    payload: &[u8],
+) -> Result<()> {
+    if payload.len() > ip::max_payload_len(dst_ip) - Udp::HEADER_LEN {
+        return Err(Error::FrameTooLarge);
+    }
+
+    let udp = Udp {
+        src_port,
+        dst_port,
+        payload,
+    };
+    let mut buffer = [0u8; Ipv4::MAX_PAYLOAD_LEN];
+    let len = udp.serialise(&mut buffer);
+
+    let pseudo_header = ip::pseudo_header_sum(
+        ip::source_address(dst_ip),
+        dst_ip,
+        IpProtocol::Udp,
+        len,
+    );
+    let mut udp_checksum =
+        checksum_finish(checksum_add(pseudo_header, &buffer[..len]));
+    // Zero means no checksum, so a real zero is sent as all ones
+    if udp_checksum == 0 {
+        udp_checksum = 0xFFFF;
+    }
+    buffer[Udp::CHECKSUM_OFFSET..Udp::CHECKSUM_OFFSET + 2]
+        .copy_from_slice(&udp_checksum.to_be_bytes());
+
+    ip::send(dst_ip, IpProtocol::Udp, &buffer[..len])
+}
+
+/// Called for every UDP datagram we receive, queues it on the socket bound to
+/// the destination port if there is one
+pub(super) fn handle(src_ip: IpAddr, dst_ip: IpAddr, buffer: &[u8]) {
+    let Ok(udp) = Udp::deserialise(buffer) else {
+        return;
+    };
+
+    // A full size frame can carry more than a socket queues
+    if udp.payload.len() > MAX_PAYLOAD_LEN {
+        return;
+    }
+
+    // A zero checksum means the sender did not calculate one, which is only
+    // allowed over IPv4
+    let udp_len = Udp::HEADER_LEN + udp.payload.len();
+    let received_checksum = u16::from_be_bytes([
+        buffer[Udp::CHECKSUM_OFFSET],
+        buffer[Udp::CHECKSUM_OFFSET + 1],
+    ]);
+    if received_checksum == 0 && dst_ip.is_ipv6() {
+        return;
+    }
+    if received_checksum != 0 {
+        let pseudo_header =
+            ip::pseudo_header_sum(src_ip, dst_ip, IpProtocol::Udp, udp_len);
+        if checksum_finish(checksum_add(pseudo_header, &buffer[..udp_len])) != 0
+        {
+            return;
+        }
+    }
+
+    cpu::without_interrupts(|| unsafe {
+        let Some(socket) = SOCKETS
+            .iter_mut()
+            .flatten()
+            .find(|socket| socket.port == Some(udp.dst_port))
+        else {
+            return;
+        };
+
+        // Drop rather than allocate if the owner is not keeping up
+        if socket.queue.len() == SOCKET_QUEUE_LEN {
+            return;
+        }
+
+        let mut datagram = Datagram {
+            src_ip,
+            src_port: udp.src_port,
+            len: udp.payload.len(),
+            data: [0; MAX_PAYLOAD_LEN],
+        };
+        datagram.data[..udp.payload.len()].copy_from_slice(udp.payload);
+        socket.queue.push_back(datagram);
+    })
+}
+
+/// A bound local UDP port, unbound again when dropped
+#[derive(Debug)]
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind to `port`, or to a free ephemeral port if `port` is 0
+    pub fn bind(port: u16) -> Result<Self> {
+        cpu::without_interrupts(|| unsafe {
+            let port = match port {
+                0 => Self::ephemeral_port(),
+                port => port,
+            };
+
+            if SOCKETS
+                .iter()
+                .flatten()
+                .any(|socket| socket.port == Some(port))
+            {
+                return Err(Error::PortInUse);
+            }
+
+            // Prefer reusing a slot that already has its queue allocated
+            if let Some(socket) = SOCKETS
+                .iter_mut()
+                .flatten()
+                .find(|socket| socket.port.is_none())
+            {
+                socket.port = Some(port);
+                return Ok(Self { port });
+            }
+
+            let slot = SOCKETS
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(Error::NoFreeSockets)?;
+            *slot = Some(Socket {
+                port: Some(port),
+                queue: VecDeque::with_capacity(SOCKET_QUEUE_LEN),
+            });
+
+            Ok(Self { port })
+        })
+    }
+
+    /// Must be called with interrupts disabled as it reads the socket table
+    unsafe fn ephemeral_port() -> u16 {
+        loop {
+            let port = NEXT_EPHEMERAL_PORT
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |port| {
+                    Some(port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START))
+                })
+                .unwrap();
+
+            if !SOCKETS
+                .iter()
+                .flatten()
+                .any(|socket| socket.port == Some(port))
+            {
+                return port;
+            }
+        }
+    }
+
+    pub fn send_to(
+        &self,
+        payload: &[u8],
+        dst_ip: IpAddr,
+        dst_port: u16,
+    ) -> Result<()> {
+        send(self.port, dst_ip, dst_port, payload)
+    }
+
+    /// Take the oldest queued datagram without blocking, copying as much as
+    /// fits into `buffer`. Returns the length copied and who sent it
+    pub fn try_recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> Option<(usize, IpAddr, u16)> {
+        let datagram = cpu::without_interrupts(|| unsafe {
+            SOCKETS
+                .iter_mut()
+                .flatten()
+                .find(|socket| socket.port == Some(self.port))
+                .and_then(|socket| socket.queue.pop_front())
+        })?;
+
+        let len = datagram.len.min(buffer.len());
+        buffer[..len].copy_from_slice(&datagram.data[..len]);
+
+        Some((len, datagram.src_ip, datagram.src_port))
+    }
+
+    /// Wait up to `timeout_ms` for a datagram, see [`Self::try_recv_from`]
+    pub fn recv_from(
+        &self,
+        buffer: &mut [u8],
+        timeout_ms: u64,
+    ) -> Result<(usize, IpAddr, u16)> {
+        // Datagrams are queued by [`super::poll`]
+        let deadline = pit::ticks() + timeout_ms;
+        loop {
+            if let Some(received) = self.try_recv_from(buffer) {
+                return Ok(received);
+            }
+            if pit::ticks() >= deadline {
+                return Err(Error::ReceiveTimeout);
+            }
+            super::wait();
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        cpu::without_interrupts(|| unsafe {
+            if let Some(socket) = SOCKETS
+                .iter_mut()
+                .flatten()
+                .find(|socket| socket.port == Some(self.port))
+            {
+                socket.port = None;
+                socket.queue.clear();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{Ipv4Addr, SocketAddrV4};
+
+    use alloc::vec::Vec;
+
+    use crate::net::{self, testing};
+
+    use super::{UdpSocket, MAX_PAYLOAD_LEN};
+
+    const OUR_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const PEER_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+    #[test]
+    fn oversize_datagram_is_dropped() {
+        let _stack = testing::take_stack(|_| Vec::new());
+        net::configure_ipv4(OUR_IP, NETMASK, Ipv4Addr::UNSPECIFIED);
+
+        let socket = UdpSocket::bind(0).unwrap();
+        let src = SocketAddrV4::new(PEER_IP, 4000);
+        let dst = SocketAddrV4::new(OUR_IP, socket.port);
+
+        // As big as the card takes, more than a socket queues
+        testing::inject(&testing::udp(src, dst, &[0xAA; 1476]));
+        testing::inject(&testing::udp(src, dst, b"small"));
+
+        let mut buffer = [0u8; MAX_PAYLOAD_LEN];
+        let (len, src_ip, src_port) =
+            socket.recv_from(&mut buffer, 100).unwrap();
+        assert_eq!(&buffer[..len], b"small");
+        assert_eq!((src_ip, src_port), (PEER_IP.into(), 4000));
+        assert!(socket.try_recv_from(&mut buffer).is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use core::mem::size_of;
+use core::{fmt, mem::size_of};
 
 use alloc::vec::Vec;
 
@@ -11,20 +11,44 @@ const FUNCTIONS: u8 = 7;
 #[repr(u16)]
 pub enum Vendor {
     Intel = 0x8086,
+    /// Red Hat, every virtio device uses it
+    Virtio = 0x1AF4,
+    Realtek = 0x10EC,
     Unknown,
 }
 impl From<u16> for Vendor {
     fn from(value: u16) -> Self {
         match value {
             0x8086 => Self::Intel,
+            0x1AF4 => Self::Virtio,
+            0x10EC => Self::Realtek,
             _ => Self::Unknown,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 pub enum Id {
+    /// 82540EM
     E1000 = 0x100E,
+    /// 82545EM copper
+    I82545em = 0x100F,
+    /// e1000e
+    I82574l = 0x10D3,
+    I217Lm = 0x153A,
+    I217V = 0x153B,
+    /// Each chipset generation since has its own IDs, they all map here
+    I219Lm = 0x156F,
+    /// Likewise for every generation
+    I219V = 0x1570,
+    /// Transitional virtio-net, has both the legacy and modern interfaces
+    VirtioNet = 0x1000,
+    /// Modern only virtio-net
+    VirtioNetModern = 0x1041,
+    Rtl8139 = 0x8139,
+    /// NE2000 compatible
+    Rtl8029 = 0x8029,
     Unknown,
 }
 
@@ -32,6 +56,22 @@ impl From<u16> for Id {
     fn from(value: u16) -> Self {
         match value {
             0x100E => Self::E1000,
+            0x100F => Self::I82545em,
+            0x10D3 => Self::I82574l,
+            0x153A => Self::I217Lm,
+            0x153B => Self::I217V,
+            // Sunrise Point, Kaby Lake, Cannon Lake, Ice Lake, Comet Lake,
+            // Tiger Lake, Alder Lake and Meteor Lake
+            0x156F | 0x15B7 | 0x15B9 | 0x15D7 | 0x15E3 | 0x15BD | 0x15BB
+            | 0x15DF | 0x15E1 | 0x0D4E | 0x0D4C | 0x0D53 | 0x15FB | 0x15F9
+            | 0x15F4 | 0x1A1E | 0x1A1C | 0x550A | 0x550C => Self::I219Lm,
+            0x1570 | 0x15B8 | 0x15D8 | 0x15D6 | 0x15BE | 0x15BC | 0x15E0
+            | 0x15E2 | 0x0D4F | 0x0D4D | 0x0D55 | 0x15FC | 0x15FA | 0x15F5
+            | 0x1A1F | 0x1A1D | 0x550B | 0x550D => Self::I219V,
+            0x1000 => Self::VirtioNet,
+            0x1041 => Self::VirtioNetModern,
+            0x8139 => Self::Rtl8139,
+            0x8029 => Self::Rtl8029,
             _ => Self::Unknown,
         }
     }
@@ -103,6 +143,11 @@ impl Header {
     const DID_VID_OFFSET: u8 = 0;
     const COMMAND_OFFSET: u8 = 4;
 
+    /// In the status register, [`Self::capabilities_ptr`] is valid
+    const STATUS_CAPABILITIES: u16 = 1 << 4;
+    /// Guards against a capability list that loops
+    const MAX_CAPABILITIES: usize = 48;
+
     const IO_ENABLE: u8 = 1 << 0;
     const MMIO_ENABLE: u8 = 1 << 1;
     const BUS_MASTER: u8 = 1 << 2;
@@ -164,6 +209,20 @@ fn get_devices() -> Vec<Device> {
     devices
 }
 
+/// Where a device is on the bus, shown the usual bus:slot.function way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{:x}", self.bus, self.slot, self.function)
+    }
+}
+
 #[derive(Debug)]
 pub struct Device {
     header: Header,
@@ -182,17 +241,42 @@ impl Device {
         }
     }
 
-    fn read32(&self, offset: u8) -> u32 {
+    pub fn read32(&self, offset: u8) -> u32 {
         Header::read32(self.bus, self.slot, self.function, offset)
     }
     fn write32(&self, offset: u8, value: u32) {
         Header::write32(self.bus, self.slot, self.function, offset, value)
     }
 
+    pub fn location(&self) -> Location {
+        Location {
+            bus: self.bus,
+            slot: self.slot,
+            function: self.function,
+        }
+    }
+
     pub fn base_addrs(&self) -> [u32; 6] {
         self.header.base_addrs
     }
 
+    /// The (ID, config space offset) of every capability the device has
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if self.header.status & Header::STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+
+        let mut offset = self.header.capabilities_ptr & 0xFC;
+        while offset != 0 && capabilities.len() < Header::MAX_CAPABILITIES {
+            let capability = self.read32(offset);
+            capabilities.push((capability as u8, offset));
+            offset = (capability >> 8) as u8 & 0xFC;
+        }
+
+        capabilities
+    }
+
     pub fn interrupt_line(&self) -> u8 {
         self.header.interrupt_line
     }
@@ -1,14 +1,26 @@
 //! Programmable Interval Timer
 //! [https://wiki.osdev.org/Programmable_Interval_Timer]
 
-use crate::cpu::{cli, halt, in8, out8, sti};
+use crate::cpu::{self, cli, halt, in8, out8, sti};
 use core::sync::atomic::{AtomicU64, Ordering};
 
 const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
 const COMMAND: u16 = 0x43;
 const IRQ_PIN: u8 = 0;
 const CLOCK_SPEED: u32 = 1193182;
 
+/// Channel 2 is gated and read back through the PC speaker port
+const SPEAKER: u16 = 0x61;
+mod speaker {
+    pub const GATE: u8 = 1 << 0;
+    pub const ENABLE: u8 = 1 << 1;
+    /// Output of channel 2
+    pub const OUT: u8 = 1 << 5;
+}
+/// How long the TSC is timed against channel 2 for
+const CALIBRATE_MS: u32 = 10;
+
 #[allow(dead_code)]
 #[repr(u8)]
 enum Channel {
@@ -38,22 +50,89 @@ enum OperatingMode {
 }
 
 static TICKS: AtomicU64 = AtomicU64::new(0);
+/// TSC cycles in a tick, 0 until [`init`] has measured it
+static TSC_PER_TICK: AtomicU64 = AtomicU64::new(0);
+/// TSC when [`TICKS`] last moved
+static LAST_TSC: AtomicU64 = AtomicU64::new(0);
 
 isr!(irq, pit);
 fn isr() {
     TICKS.fetch_add(1, Ordering::SeqCst);
+    LAST_TSC.store(cpu::rdtsc(), Ordering::Relaxed);
     crate::pic::end_of_interrupt();
 }
 
-pub fn sleep_ms(ticks: u64) {
-    let current_ticks = TICKS.load(Ordering::Relaxed);
-    let target_ticks = current_ticks + ticks;
-    while TICKS.load(Ordering::Relaxed) < target_ticks {
-        halt();
+/// Ticks since [`init`], at the 1000 hertz main uses this is milliseconds.
+/// With interrupts disabled IRQ0 cannot count them, so they are made up
+/// from the TSC instead
+pub fn ticks() -> u64 {
+    if !cpu::interrupts_enabled() {
+        catch_up();
+    }
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Count the ticks IRQ0 has missed since it last fired, only called with
+/// interrupts disabled so it cannot race the ISR
+fn catch_up() {
+    let per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+    if per_tick == 0 {
+        return;
+    }
+
+    let last = LAST_TSC.load(Ordering::Relaxed);
+    let missed = cpu::rdtsc().wrapping_sub(last) / per_tick;
+    if missed != 0 {
+        TICKS.fetch_add(missed, Ordering::SeqCst);
+        LAST_TSC.store(last + missed * per_tick, Ordering::Relaxed);
+    }
+}
+
+pub fn sleep_ms(ms: u64) {
+    let target_ticks = ticks() + ms;
+    while ticks() < target_ticks {
+        // Nothing would wake us with interrupts disabled
+        if cpu::interrupts_enabled() {
+            halt();
+        }
     }
 }
 
+/// Time the TSC against a one shot of channel 2, which needs no interrupts,
+/// returning TSC cycles per millisecond
+#[cfg(not(test))]
+fn calibrate_tsc() -> u64 {
+    // Gate channel 2 on without sounding the speaker
+    out8(SPEAKER, (in8(SPEAKER) & !speaker::ENABLE) | speaker::GATE);
+
+    out8(
+        COMMAND,
+        (Channel::Two as u8) << 6
+            | (AccessMode::BothBytes as u8) << 4
+            | (OperatingMode::Zero as u8) << 1,
+    );
+    let count = (CLOCK_SPEED / 1000 * CALIBRATE_MS) as u16;
+    out8(CHANNEL_2, count as u8);
+    out8(CHANNEL_2, (count >> 8) as u8);
+
+    // In mode zero the output goes high once the count runs out
+    let start = cpu::rdtsc();
+    while (in8(SPEAKER) & speaker::OUT) == 0 {}
+    let end = cpu::rdtsc();
+
+    (end - start) / CALIBRATE_MS as u64
+}
+
+/// The host has no PIT to time against, so tests use the OS clock
+#[cfg(test)]
+fn calibrate_tsc() -> u64 {
+    let start = (std::time::Instant::now(), cpu::rdtsc());
+    std::thread::sleep(std::time::Duration::from_millis(CALIBRATE_MS.into()));
+    (cpu::rdtsc() - start.1) / start.0.elapsed().as_millis() as u64
+}
+
 /// Hertz is the amount of times per second the interupt fires
+#[cfg(not(test))]
 pub fn init(hertz: u32) {
     cli();
 
@@ -69,11 +148,28 @@ pub fn init(hertz: u32) {
     out8(CHANNEL_0, divisor as u8);
     out8(CHANNEL_0, (divisor >> 8) as u8);
 
+    start_tsc(hertz);
+
     crate::interrupts::Idt::insert(irq, IRQ_PIN);
     crate::pic::unmask(IRQ_PIN);
     sti();
 }
 
+/// IRQ0 never fires on the host, so tests only ever count ticks from the TSC
+#[cfg(test)]
+pub fn init(hertz: u32) {
+    start_tsc(hertz);
+}
+
+/// Measure the TSC so [`ticks`] can count without IRQ0
+fn start_tsc(hertz: u32) {
+    TSC_PER_TICK.store(
+        (calibrate_tsc() * 1000 / hertz as u64).max(1),
+        Ordering::Relaxed,
+    );
+    LAST_TSC.store(cpu::rdtsc(), Ordering::Relaxed);
+}
+
 fn read(channel: Channel) -> u16 {
     cli();
 
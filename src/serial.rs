@@ -0,0 +1,48 @@
+//! Polled 16550 UART on COM1 [https://wiki.osdev.org/Serial_Ports], output
+//! only and without interrupts
+
+use crate::cpu::{in8, out8};
+
+const COM1: u16 = 0x3F8;
+
+/// Offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// While set [`DATA`] and [`INTERRUPT_ENABLE`] are the baud rate divisor
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+/// 8 data bits, no parity, one stop bit
+const LINE_CONTROL_8N1: u8 = 0x03;
+/// Enable and clear both FIFOs
+const FIFO_ENABLE_CLEAR: u8 = 0x07;
+/// Data terminal ready and request to send
+const MODEM_CONTROL_DTR_RTS: u8 = 0x03;
+/// The transmit holding register can take another byte
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+/// Divisor of the 115200 baud base clock, the fastest a 16550 goes
+const BAUD_DIVISOR: u16 = 1;
+
+pub fn init() {
+    out8(COM1 + INTERRUPT_ENABLE, 0);
+
+    out8(COM1 + LINE_CONTROL, LINE_CONTROL_DLAB);
+    out8(COM1 + DATA, BAUD_DIVISOR as u8);
+    out8(COM1 + INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+    out8(COM1 + LINE_CONTROL, LINE_CONTROL_8N1);
+
+    out8(COM1 + FIFO_CONTROL, FIFO_ENABLE_CLEAR);
+    out8(COM1 + MODEM_CONTROL, MODEM_CONTROL_DTR_RTS);
+}
+
+/// Write raw bytes, spinning until the UART has room for each one
+pub fn write(bytes: &[u8]) {
+    for &byte in bytes {
+        while in8(COM1 + LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {}
+        out8(COM1 + DATA, byte);
+    }
+}
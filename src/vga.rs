@@ -12,6 +12,8 @@ const WIDTH: isize = 80;
 static OFFSET: AtomicIsize = AtomicIsize::new(0);
 
 const BACKSPACE: u8 = 0x08;
+/// Returns to the start of the line and blanks it so the line can be redrawn
+const CARRIAGE_RETURN: u8 = b'\r';
 
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
@@ -37,6 +39,7 @@ enum Colour {
 
 pub struct Vga;
 
+#[cfg(not(test))]
 impl Write for Vga {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         unsafe {
@@ -44,6 +47,15 @@ impl Write for Vga {
             for byte in s.bytes() {
                 match byte {
                     b'\n' => offset += WIDTH - (offset % WIDTH),
+                    CARRIAGE_RETURN => {
+                        offset -= offset % WIDTH;
+                        for column in 0..WIDTH {
+                            write_volatile(
+                                TEXT_BUF.offset(offset + column),
+                                (Colour::Black as u16) << 8 | b' ' as u16,
+                            );
+                        }
+                    }
                     BACKSPACE => {
                         offset -= 1;
                         write_volatile(
@@ -66,6 +78,16 @@ impl Write for Vga {
     }
 }
 
+/// There is no screen on the host, output goes where the test harness
+/// captures it
+#[cfg(test)]
+impl Write for Vga {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        std::print!("{}", s);
+        Ok(())
+    }
+}
+
 pub fn draw() {
     draw_pixel(Coord::new(0, DRAW_HEIGHT), Colour::Red);
 